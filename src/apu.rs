@@ -0,0 +1,453 @@
+mod envelope;
+mod length;
+mod noise;
+mod square;
+mod wave;
+
+use self::noise::NoiseChannel;
+use self::square::SquareChannel;
+use self::wave::WaveChannel;
+use crate::model::Model;
+
+// the mixed output is sampled once per M-cycle
+pub const NATIVE_SAMPLE_RATE: u32 = 1_048_576;
+
+// high-pass "capacitor" the hardware puts on the output to remove the DC offset, per M-cycle
+const CHARGE_FACTOR: f32 = 0.999_832;
+
+// bits that read back as 1 for NR10-NR52, write-only and unused bits can't be read
+const READ_MASKS: [u8; 23] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Channel {
+    Square1 = 0,
+    Square2 = 1,
+    Wave = 2,
+    Noise = 3,
+}
+
+pub const CHANNELS: [Channel; 4] = [Channel::Square1, Channel::Square2, Channel::Wave, Channel::Noise];
+
+// a register write as seen by the APU, cycle counts T-cycles since the log was started
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RegisterWrite {
+    pub cycle: u64,
+    pub address: u16,
+    pub value: u8,
+}
+
+pub struct APU {
+    model: Model,
+    // NR52 bit 7, everything but wave RAM (and the length counters on DMG) is cleared while off
+    powered: bool,
+    // last written values of NR10-NR52, for reading back
+    registers: [u8; 23],
+    square1: SquareChannel,
+    square2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    // next step of the 512 Hz frame sequencer
+    frame_step: u8,
+    sample_cycles: u8,
+    capacitor_left: f32,
+    capacitor_right: f32,
+    // stereo samples at NATIVE_SAMPLE_RATE, in the -1.0 to 1.0 range
+    samples: Vec<(f32, f32)>,
+    // debugging aids, they only change what is mixed and never the emulated hardware state
+    muted: [bool; 4],
+    soloed: [bool; 4],
+    // when set, each channel's share of the mix is also collected on its own
+    capture_stems: bool,
+    stem_capacitors: [(f32, f32); 4],
+    stems: [Vec<(f32, f32)>; 4],
+    // writes to FF10-FF3F while logging is on, for exporting register dumps
+    register_log: Option<Vec<RegisterWrite>>,
+    log_cycle: u64,
+}
+
+impl APU {
+    pub fn new() -> APU {
+        APU::with_model(Model::default())
+    }
+
+    pub fn with_model(model: Model) -> APU {
+        APU {
+            model,
+            powered: false,
+            registers: [0; 23],
+            square1: SquareChannel::new(true),
+            square2: SquareChannel::new(false),
+            wave: WaveChannel::new(model.is_cgb()),
+            noise: NoiseChannel::new(),
+            frame_step: 0,
+            sample_cycles: 0,
+            capacitor_left: 0.0,
+            capacitor_right: 0.0,
+            samples: Vec::new(),
+            muted: [false; 4],
+            soloed: [false; 4],
+            capture_stems: false,
+            stem_capacitors: [(0.0, 0.0); 4],
+            stems: [Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+            register_log: None,
+            log_cycle: 0,
+        }
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    // while any channel is soloed, only soloed channels are heard
+    pub fn set_solo(&mut self, channel: Channel, solo: bool) {
+        self.soloed[channel as usize] = solo;
+    }
+
+    pub fn is_soloed(&self, channel: Channel) -> bool {
+        self.soloed[channel as usize]
+    }
+
+    // stems are captured whether or not the channel is muted, at NATIVE_SAMPLE_RATE
+    pub fn set_stem_capture(&mut self, enabled: bool) {
+        self.capture_stems = enabled;
+        if !enabled {
+            self.stems = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+        }
+    }
+
+    // per channel samples captured since the last call, indexed by Channel
+    pub fn take_stems(&mut self) -> [Vec<(f32, f32)>; 4] {
+        std::mem::take(&mut self.stems)
+    }
+
+    // start logging register writes, the log opens with writes that restore the current settings
+    // (without retriggering channels) so it can be replayed on its own
+    pub fn start_register_log(&mut self) {
+        self.log_cycle = 0;
+        let mut log = vec![RegisterWrite { cycle: 0, address: 0xFF26, value: if self.powered { 0x80 } else { 0x00 } }];
+        // straight from the channel, the CPU view of wave RAM is blocked or shifted while channel 3 plays
+        for (index, &value) in self.wave.ram.iter().enumerate() {
+            log.push(RegisterWrite { cycle: 0, address: 0xFF30 + index as u16, value });
+        }
+        for (index, &value) in self.registers.iter().enumerate().take(0x16) {
+            let address = 0xFF10 + index as u16;
+            let value = match address {
+                0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => value & 0x7F,
+                _ => value,
+            };
+            log.push(RegisterWrite { cycle: 0, address, value });
+        }
+        self.register_log = Some(log);
+    }
+
+    pub fn stop_register_log(&mut self) {
+        self.register_log = None;
+    }
+
+    pub fn is_logging_registers(&self) -> bool {
+        self.register_log.is_some()
+    }
+
+    // writes logged since the last call
+    pub fn take_register_writes(&mut self) -> Vec<RegisterWrite> {
+        self.register_log.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // T-cycles since the register log was started
+    pub fn register_log_cycle(&self) -> u64 {
+        self.log_cycle
+    }
+
+    fn audible(&self, channel: usize) -> bool {
+        let any_soloed = self.soloed.iter().any(|&solo| solo);
+        !self.muted[channel] && (!any_soloed || self.soloed[channel])
+    }
+
+    // samples produced since the last call
+    pub fn take_samples(&mut self) -> Vec<(f32, f32)> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF26 => {
+                let mut value = READ_MASKS[0x16];
+                if self.powered {
+                    value |= 0x80;
+                }
+                let channels = [self.square1.enabled, self.square2.enabled, self.wave.enabled, self.noise.enabled];
+                for (bit, enabled) in channels.iter().enumerate() {
+                    if *enabled {
+                        value |= 1 << bit;
+                    }
+                }
+                value
+            }
+            0xFF10..=0xFF25 => {
+                let index = (address - 0xFF10) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+            0xFF30..=0xFF3F => self.wave.read_ram((address - 0xFF30) as usize),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        if let (Some(log), 0xFF10..=0xFF26 | 0xFF30..=0xFF3F) = (self.register_log.as_mut(), address) {
+            log.push(RegisterWrite { cycle: self.log_cycle, address, value });
+        }
+        match address {
+            0xFF26 => self.write_power(value & 0x80 != 0),
+            0xFF10..=0xFF25 => {
+                let value = if self.powered {
+                    value
+                } else if self.model.is_cgb() {
+                    return;
+                } else {
+                    // while powered off only the length counters can be written (DMG)
+                    match address {
+                        0xFF11 | 0xFF16 | 0xFF20 => value & 0x3F,
+                        0xFF1B => value,
+                        _ => return,
+                    }
+                };
+                self.registers[(address - 0xFF10) as usize] = value;
+                let next_step_clocks_length = self.frame_step.is_multiple_of(2);
+                match address {
+                    0xFF10..=0xFF14 => self.square1.write(address - 0xFF10, value, next_step_clocks_length),
+                    0xFF15..=0xFF19 => self.square2.write(address - 0xFF15, value, next_step_clocks_length),
+                    0xFF1A..=0xFF1E => self.wave.write(address - 0xFF1A, value, next_step_clocks_length),
+                    0xFF1F..=0xFF23 => self.noise.write(address - 0xFF1F, value, next_step_clocks_length),
+                    // NR50 and NR51 are only used when mixing
+                    _ => {}
+                }
+            }
+            0xFF30..=0xFF3F => self.wave.write_ram((address - 0xFF30) as usize, value),
+            _ => {}
+        }
+    }
+
+    fn write_power(&mut self, powered: bool) {
+        if self.powered && !powered {
+            let keep_lengths = !self.model.is_cgb();
+            self.square1.power_off(keep_lengths);
+            self.square2.power_off(keep_lengths);
+            self.wave.power_off(keep_lengths);
+            self.noise.power_off(keep_lengths);
+            self.registers = [0; 23];
+        } else if !self.powered && powered {
+            self.frame_step = 0;
+        }
+        self.powered = powered;
+    }
+
+    // PCM12/PCM34 on CGB, the current digital output of two channels each
+    pub fn read_pcm(&self, address: u16) -> u8 {
+        match address {
+            0xFF76 => self.square2.output() << 4 | self.square1.output(),
+            0xFF77 => self.noise.output() << 4 | self.wave.output(),
+            _ => 0xFF,
+        }
+    }
+
+    // clocked on the falling edge of DIV bit 4, i.e. at 512 Hz
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.powered {
+            return;
+        }
+        match self.frame_step {
+            0 | 4 => self.clock_lengths(),
+            2 | 6 => {
+                self.clock_lengths();
+                self.square1.clock_sweep();
+            }
+            7 => {
+                self.square1.clock_envelope();
+                self.square2.clock_envelope();
+                self.noise.clock_envelope();
+            }
+            _ => {}
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    fn clock_lengths(&mut self) {
+        self.square1.clock_length();
+        self.square2.clock_length();
+        self.wave.clock_length();
+        self.noise.clock_length();
+    }
+
+    // advance the channels by a number of T-cycles
+    pub fn step(&mut self, cycles: u32) {
+        if self.register_log.is_some() {
+            self.log_cycle += cycles as u64;
+        }
+        for _ in 0..cycles {
+            if self.powered {
+                self.square1.tick();
+                self.square2.tick();
+                self.wave.tick();
+                self.noise.tick();
+            }
+            self.sample_cycles += 1;
+            if self.sample_cycles == 4 {
+                self.sample_cycles = 0;
+                let sample = self.mix();
+                self.samples.push(sample);
+            }
+        }
+    }
+
+    // each channel's DAC turns its 0-15 output into -1.0 to 1.0, NR51 routes the channels to
+    // the left/right terminals and NR50 scales each terminal by 1/8 to 8/8
+    fn mix(&mut self) -> (f32, f32) {
+        let outputs = [
+            dac(self.square1.dac_enabled, self.square1.output()),
+            dac(self.square2.dac_enabled, self.square2.output()),
+            dac(self.wave.dac_enabled, self.wave.output()),
+            dac(self.noise.dac_enabled, self.noise.output()),
+        ];
+        let panning = self.registers[0x15];
+        let volume = self.registers[0x14];
+        let left_volume = (((volume >> 4) & 0x07) + 1) as f32 / 32.0;
+        let right_volume = ((volume & 0x07) + 1) as f32 / 32.0;
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (channel, output) in outputs.iter().enumerate() {
+            let channel_left = if panning & (0x10 << channel) != 0 { output * left_volume } else { 0.0 };
+            let channel_right = if panning & (0x01 << channel) != 0 { output * right_volume } else { 0.0 };
+            if self.audible(channel) {
+                left += channel_left;
+                right += channel_right;
+            }
+            if self.capture_stems {
+                let (capacitor_left, capacitor_right) = &mut self.stem_capacitors[channel];
+                let stem = (high_pass(capacitor_left, channel_left), high_pass(capacitor_right, channel_right));
+                self.stems[channel].push(stem);
+            }
+        }
+
+        (
+            high_pass(&mut self.capacitor_left, left),
+            high_pass(&mut self.capacitor_right, right),
+        )
+    }
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        APU::new()
+    }
+}
+
+fn dac(enabled: bool, output: u8) -> f32 {
+    if enabled {
+        output as f32 / 7.5 - 1.0
+    } else {
+        0.0
+    }
+}
+
+fn high_pass(capacitor: &mut f32, input: f32) -> f32 {
+    let output = input - *capacitor;
+    *capacitor = input - output * CHARGE_FACTOR;
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // both square channels playing different tones on both sides
+    fn playing() -> APU {
+        let mut apu = APU::new();
+        for (address, value) in [
+            (0xFF26, 0x80),
+            (0xFF24, 0x77),
+            (0xFF25, 0xFF),
+            (0xFF11, 0x80),
+            (0xFF12, 0xF0),
+            (0xFF13, 0x00),
+            (0xFF14, 0x87),
+            (0xFF16, 0x40),
+            (0xFF17, 0xA0),
+            (0xFF18, 0x00),
+            (0xFF19, 0x85),
+        ] {
+            apu.write_register(address, value);
+        }
+        apu.set_stem_capture(true);
+        apu
+    }
+
+    type Samples = Vec<(f32, f32)>;
+
+    // mixed samples and stems for 10 ms
+    fn run(apu: &mut APU) -> (Samples, [Samples; 4]) {
+        apu.step(NATIVE_SAMPLE_RATE * 4 / 100);
+        (apu.take_samples(), apu.take_stems())
+    }
+
+    fn assert_mix(mix: &[(f32, f32)], stems: &[&Samples]) {
+        for (index, &(left, right)) in mix.iter().enumerate() {
+            let expected: f32 = stems.iter().map(|stem| stem[index].0).sum();
+            assert!((left - expected).abs() < 1e-4, "sample {}: {} vs {}", index, left, expected);
+            let expected: f32 = stems.iter().map(|stem| stem[index].1).sum();
+            assert!((right - expected).abs() < 1e-4, "sample {}: {} vs {}", index, right, expected);
+        }
+    }
+
+    #[test]
+    fn the_mix_is_the_sum_of_the_stems() {
+        let mut apu = playing();
+        let (mix, stems) = run(&mut apu);
+        assert_eq!(mix.len(), stems[0].len());
+        assert!(mix.iter().any(|&(left, _)| left.abs() > 0.1));
+        assert_mix(&mix, &[&stems[0], &stems[1]]);
+        assert!(stems[2].iter().chain(stems[3].iter()).all(|&sample| sample == (0.0, 0.0)));
+    }
+
+    #[test]
+    fn a_muted_channel_is_left_out_of_the_mix_but_keeps_its_stem() {
+        let mut apu = playing();
+        apu.set_muted(Channel::Square1, true);
+        let (mix, stems) = run(&mut apu);
+        assert!(stems[0].iter().any(|&(left, _)| left.abs() > 0.1));
+        assert_mix(&mix, &[&stems[1]]);
+    }
+
+    #[test]
+    fn soloing_leaves_only_the_soloed_channels() {
+        let mut apu = playing();
+        apu.set_solo(Channel::Square1, true);
+        let (mix, stems) = run(&mut apu);
+        assert_mix(&mix, &[&stems[0]]);
+        // mute wins over solo
+        let mut apu = playing();
+        apu.set_solo(Channel::Square1, true);
+        apu.set_muted(Channel::Square1, true);
+        let (mix, _) = run(&mut apu);
+        assert!(mix.iter().all(|&(left, right)| left.abs() < 1e-4 && right.abs() < 1e-4));
+    }
+
+    #[test]
+    fn stems_are_only_kept_while_capturing() {
+        let mut apu = playing();
+        apu.set_stem_capture(false);
+        let (mix, stems) = run(&mut apu);
+        assert!(!mix.is_empty());
+        assert!(stems.iter().all(|stem| stem.is_empty()));
+    }
+}
@@ -0,0 +1,113 @@
+// volume envelope of NRx2, clocked at 64 Hz by the frame sequencer
+pub(super) struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+    pub(super) volume: u8,
+}
+
+impl Envelope {
+    pub(super) fn new() -> Envelope {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            timer: 0,
+            volume: 0,
+        }
+    }
+
+    pub(super) fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+
+    // the upper 5 bits of NRx2 double as the channel's DAC power
+    pub(super) fn dac_enabled(value: u8) -> bool {
+        value & 0xF8 != 0
+    }
+
+    pub(super) fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    pub(super) fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        // NRx2 written to a channel that was never triggered finds the timer at 0, the volume steps on this clock
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period;
+        if self.increase && self.volume < 15 {
+            self.volume += 1;
+        } else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triggered(nrx2: u8) -> Envelope {
+        let mut envelope = Envelope::new();
+        envelope.write(nrx2);
+        envelope.trigger();
+        envelope
+    }
+
+    #[test]
+    fn the_volume_steps_once_per_period() {
+        let mut envelope = triggered(0xF3);
+        assert_eq!(envelope.volume, 15);
+        for _ in 0..2 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.volume, 15);
+        envelope.clock();
+        assert_eq!(envelope.volume, 14);
+        for _ in 0..3 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.volume, 13);
+    }
+
+    #[test]
+    fn the_volume_stops_at_0_and_15() {
+        let mut envelope = triggered(0x11);
+        for _ in 0..4 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.volume, 0);
+        let mut envelope = triggered(0xE9);
+        for _ in 0..4 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.volume, 15);
+    }
+
+    #[test]
+    fn period_0_holds_the_volume() {
+        let mut envelope = triggered(0x80);
+        for _ in 0..16 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.volume, 8);
+    }
+
+    #[test]
+    fn an_untriggered_envelope_steps_on_the_first_clock() {
+        let mut envelope = Envelope::new();
+        envelope.volume = 5;
+        envelope.write(0x0A);
+        envelope.clock();
+        assert_eq!(envelope.volume, 6);
+    }
+}
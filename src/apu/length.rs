@@ -0,0 +1,102 @@
+// counts down at 256 Hz while enabled and switches the channel off when it reaches 0
+pub(super) struct LengthCounter {
+    max: u16,
+    counter: u16,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    pub(super) fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    // NRx1 holds the length as max minus the number of remaining clocks
+    pub(super) fn load(&mut self, value: u8) {
+        self.counter = self.max - (value as u16 & (self.max - 1));
+    }
+
+    // returns true when the channel has to be disabled
+    pub(super) fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    // the length enable and trigger bits of NRx4, returns true when the channel has to be disabled.
+    // when the next frame sequencer step doesn't clock lengths, enabling the counter clocks it once
+    // right away, and a trigger reloading an empty counter loses one clock as well
+    pub(super) fn write_control(&mut self, enable: bool, trigger: bool, next_step_clocks_length: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enable;
+        let mut disable = false;
+        if !next_step_clocks_length && !was_enabled && enable && self.counter > 0 {
+            self.counter -= 1;
+            disable = self.counter == 0 && !trigger;
+        }
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if enable && !next_step_clocks_length {
+                self.counter -= 1;
+            }
+        }
+        disable
+    }
+
+    // powering the APU off clears the enable bit but on DMG keeps the count
+    pub(super) fn power_off(&mut self) {
+        self.enabled = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_counter_disables_the_channel_when_it_runs_out() {
+        let mut length = LengthCounter::new(64);
+        length.load(60);
+        assert!(!length.write_control(true, false, true));
+        for _ in 0..3 {
+            assert!(!length.clock());
+        }
+        assert!(length.clock());
+        // an empty counter stays put
+        assert!(!length.clock());
+    }
+
+    #[test]
+    fn a_disabled_counter_does_not_count() {
+        let mut length = LengthCounter::new(256);
+        length.load(0xFF);
+        for _ in 0..4 {
+            assert!(!length.clock());
+        }
+        assert!(!length.write_control(true, false, true));
+        assert!(length.clock());
+    }
+
+    #[test]
+    fn enabling_between_length_steps_clocks_once_more() {
+        let mut length = LengthCounter::new(64);
+        length.load(63);
+        assert!(length.write_control(true, false, false));
+    }
+
+    #[test]
+    fn a_trigger_reloads_an_empty_counter() {
+        let mut length = LengthCounter::new(64);
+        assert!(!length.write_control(true, true, true));
+        assert_eq!(length.counter, 64);
+        // between length steps the reload loses one clock
+        let mut length = LengthCounter::new(64);
+        assert!(!length.write_control(true, true, false));
+        assert_eq!(length.counter, 63);
+    }
+}
@@ -0,0 +1,180 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// channel 4, white noise from a linear feedback shift register
+pub(super) struct NoiseChannel {
+    pub(super) enabled: bool,
+    pub(super) dac_enabled: bool,
+    clock_shift: u8,
+    // 7-bit mode gives a short, buzzy period instead of the 15-bit one
+    short_mode: bool,
+    divisor_code: u8,
+    timer: u32,
+    lfsr: u16,
+    pub(super) length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    pub(super) fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            dac_enabled: false,
+            clock_shift: 0,
+            short_mode: false,
+            divisor_code: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    // register 0-4 is NR40 (unused) to NR44
+    pub(super) fn write(&mut self, register: u16, value: u8, next_step_clocks_length: bool) {
+        match register {
+            1 => self.length.load(value & 0x3F),
+            2 => {
+                self.envelope.write(value);
+                self.dac_enabled = Envelope::dac_enabled(value);
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = value >> 4;
+                self.short_mode = value & 0x08 != 0;
+                self.divisor_code = value & 0x07;
+            }
+            4 => {
+                let trigger = value & 0x80 != 0;
+                if self.length.write_control(value & 0x40 != 0, trigger, next_step_clocks_length) {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.enabled = self.dac_enabled;
+                    self.timer = self.period();
+                    self.lfsr = 0x7FFF;
+                    self.envelope.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    pub(super) fn tick(&mut self) {
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period();
+        // shifts 14 and 15 stop the LFSR from being clocked at all
+        if self.clock_shift >= 14 {
+            return;
+        }
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.short_mode {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    // digital output 0-15, bit 0 of the LFSR inverted gates the volume
+    pub(super) fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    // CGB clears the length counter as well
+    pub(super) fn power_off(&mut self, keep_length: bool) {
+        let length = std::mem::replace(&mut self.length, LengthCounter::new(64));
+        *self = NoiseChannel::new();
+        if keep_length {
+            self.length = length;
+            self.length.power_off();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triggered(nr43: u8) -> NoiseChannel {
+        let mut channel = NoiseChannel::new();
+        channel.write(2, 0xF0, false);
+        channel.write(3, nr43, false);
+        channel.write(4, 0x80, false);
+        channel
+    }
+
+    // steps the LFSR until it is back where the trigger left it
+    fn lfsr_period(channel: &mut NoiseChannel, mask: u16) -> u32 {
+        let start = channel.lfsr & mask;
+        for shifts in 1..=0x8000 {
+            for _ in 0..channel.period() {
+                channel.tick();
+            }
+            if channel.lfsr & mask == start {
+                return shifts;
+            }
+        }
+        panic!("the LFSR never repeated");
+    }
+
+    #[test]
+    fn the_lfsr_repeats_after_32767_shifts() {
+        let mut channel = triggered(0x00);
+        assert_eq!(lfsr_period(&mut channel, 0x7FFF), 0x7FFF);
+    }
+
+    #[test]
+    fn short_mode_repeats_after_127_shifts() {
+        let mut channel = triggered(0x08);
+        assert_eq!(lfsr_period(&mut channel, 0x7F), 0x7F);
+    }
+
+    #[test]
+    fn the_output_follows_inverted_bit_0() {
+        let mut channel = triggered(0x00);
+        assert_eq!(channel.output(), 0);
+        // 0x7FFF shifts to 0x3FFF, feedback of two set bits is 0
+        for _ in 0..8 {
+            channel.tick();
+        }
+        assert_eq!(channel.lfsr, 0x3FFF);
+        for _ in 0..8 * 14 {
+            channel.tick();
+        }
+        assert_eq!(channel.lfsr & 1, 0);
+        assert_eq!(channel.output(), 15);
+    }
+
+    #[test]
+    fn clock_shifts_14_and_15_stop_the_lfsr() {
+        let mut channel = triggered(0xE0);
+        for _ in 0..channel.period() * 4 {
+            channel.tick();
+        }
+        assert_eq!(channel.lfsr, 0x7FFF);
+    }
+}
@@ -0,0 +1,205 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+// frequency sweep of channel 1 (NR10), clocked at 128 Hz by the frame sequencer
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    shadow_frequency: u16,
+    enabled: bool,
+    // a calculation in negate mode since the last trigger, clearing negate afterwards kills the channel
+    negate_used: bool,
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            shadow_frequency: 0,
+            enabled: false,
+            negate_used: false,
+        }
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    // next frequency, anything above 2047 disables the channel
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.negate_used = true;
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+}
+
+// channels 1 and 2, only channel 1 has the sweep unit
+pub(super) struct SquareChannel {
+    pub(super) enabled: bool,
+    pub(super) dac_enabled: bool,
+    duty: u8,
+    duty_position: u8,
+    frequency: u16,
+    timer: u16,
+    pub(super) length: LengthCounter,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl SquareChannel {
+    pub(super) fn new(with_sweep: bool) -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+            dac_enabled: false,
+            duty: 0,
+            duty_position: 0,
+            frequency: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            sweep: if with_sweep { Some(Sweep::new()) } else { None },
+        }
+    }
+
+    // register 0-4 is NRx0-NRx4
+    pub(super) fn write(&mut self, register: u16, value: u8, next_step_clocks_length: bool) {
+        match register {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.period = (value >> 4) & 0x07;
+                    let negate = value & 0x08 != 0;
+                    if sweep.negate && !negate && sweep.negate_used {
+                        self.enabled = false;
+                    }
+                    sweep.negate = negate;
+                    sweep.shift = value & 0x07;
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value & 0x3F);
+            }
+            2 => {
+                self.envelope.write(value);
+                self.dac_enabled = Envelope::dac_enabled(value);
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                let trigger = value & 0x80 != 0;
+                if self.length.write_control(value & 0x40 != 0, trigger, next_step_clocks_length) {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.timer = self.period();
+        self.envelope.trigger();
+        let frequency = self.frequency;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow_frequency = frequency;
+            sweep.reload_timer();
+            sweep.negate_used = false;
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            // with a shift the overflow check runs immediately
+            if sweep.shift != 0 && sweep.calculate() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 4
+    }
+
+    pub(super) fn tick(&mut self) {
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period();
+        self.duty_position = (self.duty_position + 1) % 8;
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub(super) fn clock_sweep(&mut self) {
+        let sweep = match self.sweep.as_mut() {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        // until channel 1 is first triggered the sweep timer is 0, it reloads from NR10 here rather than wrapping
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        let frequency = sweep.calculate();
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow_frequency = frequency;
+            self.frequency = frequency;
+            // the new frequency is checked for overflow again straight away, but not written back
+            if sweep.calculate() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // digital output 0-15
+    pub(super) fn output(&self) -> u8 {
+        if self.enabled {
+            DUTY_PATTERNS[self.duty as usize][self.duty_position as usize] * self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    // CGB clears the length counter as well
+    pub(super) fn power_off(&mut self, keep_length: bool) {
+        let length = std::mem::replace(&mut self.length, LengthCounter::new(64));
+        *self = SquareChannel::new(self.sweep.is_some());
+        if keep_length {
+            self.length = length;
+            self.length.power_off();
+        }
+    }
+}
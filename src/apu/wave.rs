@@ -0,0 +1,155 @@
+use super::length::LengthCounter;
+
+// channel 3, plays back 32 4-bit samples from wave RAM
+pub(super) struct WaveChannel {
+    pub(super) enabled: bool,
+    pub(super) dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: u16,
+    position: u8,
+    sample_buffer: u8,
+    pub(super) length: LengthCounter,
+    pub(super) ram: [u8; 16],
+    // CGB lets the CPU at wave RAM at any time while the channel plays and doesn't corrupt it on retrigger
+    cgb: bool,
+    // wave RAM was read in the last cycle, the only moment DMG lets the CPU at it while playing
+    just_fetched: bool,
+}
+
+impl WaveChannel {
+    pub(super) fn new(cgb: bool) -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample_buffer: 0,
+            length: LengthCounter::new(256),
+            ram: [0; 16],
+            cgb,
+            just_fetched: false,
+        }
+    }
+
+    // register 0-4 is NR30-NR34
+    pub(super) fn write(&mut self, register: u16, value: u8, next_step_clocks_length: bool) {
+        match register {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value),
+            2 => self.volume_code = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                let trigger = value & 0x80 != 0;
+                if trigger {
+                    self.corrupt_ram_on_trigger();
+                }
+                if self.length.write_control(value & 0x40 != 0, trigger, next_step_clocks_length) {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.enabled = self.dac_enabled;
+                    self.position = 0;
+                    // the first sample is fetched a few cycles later than a regular period
+                    self.timer = self.period() + 6;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // while the channel plays, the CPU only sees the byte the channel is currently reading, and on DMG
+    // only right as it is read
+    pub(super) fn read_ram(&self, index: usize) -> u8 {
+        if !self.enabled {
+            self.ram[index]
+        } else if self.cgb || self.just_fetched {
+            self.ram[self.position as usize / 2]
+        } else {
+            0xFF
+        }
+    }
+
+    pub(super) fn write_ram(&mut self, index: usize, value: u8) {
+        if !self.enabled {
+            self.ram[index] = value;
+        } else if self.cgb || self.just_fetched {
+            self.ram[self.position as usize / 2] = value;
+        }
+    }
+
+    // retriggering on DMG just as the channel reads a byte overwrites the start of wave RAM with the
+    // byte being read, or with its aligned group of four from the second group on
+    fn corrupt_ram_on_trigger(&mut self) {
+        if self.cgb || !self.enabled || self.timer != 1 {
+            return;
+        }
+        let index = ((self.position as usize + 1) % 32) / 2;
+        if index < 4 {
+            self.ram[0] = self.ram[index];
+        } else {
+            let start = index & !0x03;
+            self.ram.copy_within(start..start + 4, 0);
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+
+    pub(super) fn tick(&mut self) {
+        self.just_fetched = false;
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period();
+        self.position = (self.position + 1) % 32;
+        let byte = self.ram[self.position as usize / 2];
+        self.just_fetched = true;
+        // the upper nibble is played first
+        self.sample_buffer = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    // digital output 0-15
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.volume_code {
+            0 => 0,
+            1 => self.sample_buffer,
+            2 => self.sample_buffer >> 1,
+            _ => self.sample_buffer >> 2,
+        }
+    }
+
+    // wave RAM survives, CGB clears the length counter as well
+    pub(super) fn power_off(&mut self, keep_length: bool) {
+        let length = std::mem::replace(&mut self.length, LengthCounter::new(256));
+        let ram = self.ram;
+        *self = WaveChannel::new(self.cgb);
+        if keep_length {
+            self.length = length;
+            self.length.power_off();
+        }
+        self.ram = ram;
+    }
+}
@@ -0,0 +1,300 @@
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+// memory bank controller, picked from the cartridge type byte at 0x147
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MBC {
+    None,
+    MBC1,
+    MBC2,
+    MBC3,
+    MBC5,
+}
+
+pub struct Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    pub mbc: MBC,
+    ram_enabled: bool,
+    // low and high bank register as written, how they combine depends on the MBC
+    rom_bank: u16,
+    ram_bank: u8,
+    // MBC1 mode select, 1 lets the upper bank bits apply to 0x0000-0x3FFF and RAM too
+    banking_mode: u8,
+    // MBC3 real time clock registers 0x08-0x0C, kept as plain storage
+    rtc: [u8; 5],
+}
+
+impl Cartridge {
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Cartridge, String> {
+        if rom.len() < 0x150 {
+            return Err(format!("ROM is too small to hold a header ({} bytes)", rom.len()));
+        }
+        let mbc = match rom[0x147] {
+            0x00 | 0x08 | 0x09 => MBC::None,
+            0x01..=0x03 => MBC::MBC1,
+            0x05 | 0x06 => MBC::MBC2,
+            0x0F..=0x13 => MBC::MBC3,
+            0x19..=0x1E => MBC::MBC5,
+            other => return Err(format!("unsupported cartridge type 0x{:02x}", other)),
+        };
+        let ram_size = match mbc {
+            // MBC2 has 512 half-bytes built in
+            MBC::MBC2 => 0x200,
+            _ => match rom[0x149] {
+                0x02 => 0x2000,
+                0x03 => 0x8000,
+                0x04 => 0x20000,
+                0x05 => 0x10000,
+                _ => 0,
+            },
+        };
+        Ok(Cartridge::with_mbc(rom, mbc, ram_size))
+    }
+
+    // a cartridge around an already laid out ROM image, without looking at its header
+    pub fn with_mbc(mut rom: Vec<u8>, mbc: MBC, ram_size: usize) -> Cartridge {
+        // pad to whole banks so bank arithmetic never runs past the end
+        let banks = rom.len().div_ceil(ROM_BANK_SIZE).max(2);
+        rom.resize(banks * ROM_BANK_SIZE, 0xFF);
+        Cartridge {
+            rom,
+            ram: vec![0; ram_size],
+            mbc,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            banking_mode: 0,
+            rtc: [0; 5],
+        }
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    // the game title from the header, CGB games use the last bytes of the field for other data
+    pub fn title(&self) -> String {
+        self.rom[0x134..0x144]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .filter(|byte| byte.is_ascii_graphic() || **byte == b' ')
+            .map(|&byte| byte as char)
+            .collect()
+    }
+
+    // sum of the 16 title bytes, what the CGB boot ROM identifies DMG games by
+    pub fn title_checksum(&self) -> u8 {
+        self.rom[0x134..0x144].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+    }
+
+    // published by Nintendo according to the old licensee code, or the new one when the old one is 0x33
+    pub fn is_nintendo_licensed(&self) -> bool {
+        if self.rom[0x14B] == 0x33 { &self.rom[0x144..0x146] == b"01" } else { self.rom[0x14B] == 0x01 }
+    }
+
+    // the CGB flag at 0x143, bit 7 is set by games that use CGB features
+    pub fn supports_cgb(&self) -> bool {
+        self.rom[0x143] & 0x80 != 0
+    }
+
+    // SGB functions are flagged at 0x146, the SGB only looks at it with the new licensee code in use
+    pub fn supports_sgb(&self) -> bool {
+        self.rom[0x146] == 0x03 && self.rom[0x14B] == 0x33
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        self.rom.len() / ROM_BANK_SIZE
+    }
+
+    // bank mapped at 0x0000-0x3FFF and 0x4000-0x7FFF
+    fn rom_banks(&self) -> (usize, usize) {
+        let (low, high) = match self.mbc {
+            MBC::None => (0, 1),
+            MBC::MBC1 => {
+                let upper = (self.ram_bank as usize & 0x03) << 5;
+                let low = if self.banking_mode == 1 { upper } else { 0 };
+                (low, upper | self.rom_bank as usize)
+            }
+            MBC::MBC2 | MBC::MBC3 | MBC::MBC5 => (0, self.rom_bank as usize),
+        };
+        let count = self.rom_bank_count();
+        (low % count, high % count)
+    }
+
+    pub fn read_rom(&self, address: u16) -> u8 {
+        let (low, high) = self.rom_banks();
+        let bank = if address < 0x4000 { low } else { high };
+        self.rom[bank * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1))]
+    }
+
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        match self.mbc {
+            MBC::None => {}
+            MBC::MBC1 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+                0x2000..=0x3FFF => self.rom_bank = (value as u16 & 0x1F).max(1),
+                0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+                _ => self.banking_mode = value & 0x01,
+            },
+            MBC::MBC2 => {
+                // bit 8 of the address picks between RAM enable and ROM bank
+                if address < 0x4000 {
+                    if address & 0x100 == 0 {
+                        self.ram_enabled = value & 0x0F == 0x0A;
+                    } else {
+                        self.rom_bank = (value as u16 & 0x0F).max(1);
+                    }
+                }
+            }
+            MBC::MBC3 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+                0x2000..=0x3FFF => self.rom_bank = (value as u16 & 0x7F).max(1),
+                0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+                // the RTC latch isn't emulated, the registers don't tick
+                _ => {}
+            },
+            MBC::MBC5 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+                0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+                0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((value as u16 & 0x01) << 8),
+                0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+                _ => {}
+            },
+        }
+    }
+
+    // offset into the RAM for an address in 0xA000-0xBFFF, None when nothing is mapped there
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled && self.mbc != MBC::None {
+            return None;
+        }
+        if self.ram.is_empty() {
+            return None;
+        }
+        let offset = address as usize & (RAM_BANK_SIZE - 1);
+        let bank = match self.mbc {
+            MBC::None | MBC::MBC2 => 0,
+            MBC::MBC1 => {
+                if self.banking_mode == 1 { self.ram_bank as usize } else { 0 }
+            }
+            MBC::MBC3 | MBC::MBC5 => self.ram_bank as usize,
+        };
+        Some((bank * RAM_BANK_SIZE + offset) % self.ram.len())
+    }
+
+    pub fn read_ram(&self, address: u16) -> u8 {
+        if self.mbc == MBC::MBC3 && (0x08..=0x0C).contains(&self.ram_bank) {
+            return if self.ram_enabled { self.rtc[self.ram_bank as usize - 0x08] } else { 0xFF };
+        }
+        match self.ram_offset(address) {
+            // MBC2 RAM is only 4 bits wide
+            Some(offset) if self.mbc == MBC::MBC2 => self.ram[offset] | 0xF0,
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        if self.mbc == MBC::MBC3 && (0x08..=0x0C).contains(&self.ram_bank) {
+            if self.ram_enabled {
+                self.rtc[self.ram_bank as usize - 0x08] = value;
+            }
+            return;
+        }
+        if let Some(offset) = self.ram_offset(address) {
+            self.ram[offset] = if self.mbc == MBC::MBC2 { value & 0x0F } else { value };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every bank starts with its own number, low byte first
+    fn cartridge(mbc: MBC, banks: usize) -> Cartridge {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+            rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+        }
+        Cartridge::with_mbc(rom, mbc, 0x8000)
+    }
+
+    fn bank_at(cartridge: &Cartridge, address: u16) -> usize {
+        cartridge.read_rom(address) as usize | (cartridge.read_rom(address + 1) as usize) << 8
+    }
+
+    #[test]
+    fn mbc1_maps_bank_0_writes_to_bank_1() {
+        let mut cartridge = cartridge(MBC::MBC1, 128);
+        assert_eq!(bank_at(&cartridge, 0x4000), 1);
+        cartridge.write_rom(0x2000, 0x05);
+        assert_eq!(bank_at(&cartridge, 0x4000), 5);
+        cartridge.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at(&cartridge, 0x4000), 1);
+        // only the low 5 bits are kept, so 0x20 is bank 0 as well
+        cartridge.write_rom(0x2000, 0x20);
+        assert_eq!(bank_at(&cartridge, 0x4000), 1);
+    }
+
+    #[test]
+    fn mbc1_upper_bits_come_from_the_ram_bank_register() {
+        let mut cartridge = cartridge(MBC::MBC1, 128);
+        cartridge.write_rom(0x4000, 0x02);
+        cartridge.write_rom(0x2000, 0x00);
+        // banks 0x20, 0x40 and 0x60 can't be selected, 0x41 is mapped instead
+        assert_eq!(bank_at(&cartridge, 0x4000), 0x41);
+        assert_eq!(bank_at(&cartridge, 0x0000), 0);
+        // mode 1 applies the upper bits to 0x0000-0x3FFF too
+        cartridge.write_rom(0x6000, 0x01);
+        assert_eq!(bank_at(&cartridge, 0x0000), 0x40);
+    }
+
+    #[test]
+    fn mbc1_bank_numbers_wrap_to_the_rom_size() {
+        let mut cartridge = cartridge(MBC::MBC1, 8);
+        cartridge.write_rom(0x2000, 0x0B);
+        assert_eq!(bank_at(&cartridge, 0x4000), 3);
+    }
+
+    #[test]
+    fn mbc3_uses_7_bits_and_maps_bank_0_to_1() {
+        let mut cartridge = cartridge(MBC::MBC3, 128);
+        cartridge.write_rom(0x2000, 0x7F);
+        assert_eq!(bank_at(&cartridge, 0x4000), 0x7F);
+        cartridge.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at(&cartridge, 0x4000), 1);
+        cartridge.write_rom(0x2000, 0x80);
+        assert_eq!(bank_at(&cartridge, 0x4000), 1);
+    }
+
+    #[test]
+    fn mbc5_has_9_bank_bits_and_can_map_bank_0() {
+        let mut cartridge = cartridge(MBC::MBC5, 512);
+        cartridge.write_rom(0x2000, 0x23);
+        cartridge.write_rom(0x3000, 0x01);
+        assert_eq!(bank_at(&cartridge, 0x4000), 0x123);
+        cartridge.write_rom(0x3000, 0x00);
+        assert_eq!(bank_at(&cartridge, 0x4000), 0x23);
+        cartridge.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at(&cartridge, 0x4000), 0);
+    }
+
+    #[test]
+    fn ram_banks_are_selected_once_ram_is_enabled() {
+        let mut cartridge = cartridge(MBC::MBC5, 2);
+        cartridge.write_ram(0xA000, 0x12);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+        cartridge.write_rom(0x0000, 0x0A);
+        for bank in 0..4 {
+            cartridge.write_rom(0x4000, bank);
+            cartridge.write_ram(0xA000, 0x10 + bank);
+        }
+        cartridge.write_rom(0x4000, 2);
+        assert_eq!(cartridge.read_ram(0xA000), 0x12);
+        cartridge.write_rom(0x0000, 0x00);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+    }
+}
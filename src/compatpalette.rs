@@ -0,0 +1,281 @@
+use crate::cartridge::Cartridge;
+use crate::gamepad::Button;
+
+// the CGB boot ROM's 4-color palettes, RGB555
+const PALETTES: [[u16; 4]; 30] = [
+    [0x7FFF, 0x32BF, 0x00D0, 0x0000],
+    [0x639F, 0x4279, 0x15B0, 0x04CB],
+    [0x7FFF, 0x6E31, 0x454A, 0x0000],
+    [0x7FFF, 0x1BEF, 0x0200, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+    [0x7FFF, 0x5294, 0x294A, 0x0000],
+    [0x7FFF, 0x03FF, 0x012F, 0x0000],
+    [0x7FFF, 0x03EF, 0x01D6, 0x0000],
+    [0x7FFF, 0x42B5, 0x3DC8, 0x0000],
+    [0x7E74, 0x03FF, 0x0180, 0x0000],
+    [0x67FF, 0x77AC, 0x1A13, 0x2D6B],
+    [0x7ED6, 0x4BFF, 0x2175, 0x0000],
+    [0x53FF, 0x4A5F, 0x7E52, 0x0000],
+    [0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0],
+    [0x03ED, 0x7FFF, 0x255F, 0x0000],
+    [0x036A, 0x021F, 0x03FF, 0x7FFF],
+    [0x7FFF, 0x01DF, 0x0112, 0x0000],
+    [0x231F, 0x035F, 0x00F2, 0x0009],
+    [0x7FFF, 0x03EA, 0x011F, 0x0000],
+    [0x299F, 0x001A, 0x000C, 0x0000],
+    [0x7FFF, 0x027F, 0x001F, 0x0000],
+    [0x7FFF, 0x03E0, 0x0206, 0x0120],
+    [0x7FFF, 0x7EEB, 0x001F, 0x7C00],
+    [0x7FFF, 0x3FFF, 0x7E00, 0x001F],
+    [0x7FFF, 0x03FF, 0x001F, 0x0000],
+    [0x03FF, 0x001F, 0x000C, 0x0000],
+    [0x7FFF, 0x033F, 0x0193, 0x0000],
+    [0x0000, 0x4200, 0x037F, 0x7FFF],
+    [0x7FFF, 0x7E8C, 0x7C00, 0x0000],
+    [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+];
+
+// OBJ0, OBJ1 and BG of every combination as offsets into the colors of PALETTES
+const fn combination(obj0: usize, obj1: usize, bg: usize) -> [usize; 3] {
+    [obj0 * 4, obj1 * 4, bg * 4]
+}
+
+const COMBINATIONS: [[usize; 3]; 51] = [
+    combination(4, 4, 29),
+    combination(18, 18, 18),
+    combination(20, 20, 20),
+    combination(24, 24, 24),
+    combination(9, 9, 9),
+    combination(0, 0, 0),
+    combination(27, 27, 27),
+    combination(5, 5, 5),
+    combination(12, 12, 12),
+    combination(26, 26, 26),
+    combination(16, 8, 8),
+    combination(4, 28, 28),
+    combination(4, 2, 2),
+    combination(3, 4, 4),
+    combination(4, 29, 29),
+    combination(28, 4, 28),
+    combination(2, 17, 2),
+    combination(16, 16, 8),
+    combination(4, 4, 7),
+    combination(4, 4, 18),
+    combination(4, 4, 20),
+    combination(19, 19, 9),
+    // a few combinations start partway into a palette, the boot ROM doesn't care about the boundaries
+    [15, 15, 44],
+    combination(17, 17, 2),
+    combination(4, 4, 2),
+    combination(4, 4, 3),
+    combination(28, 28, 0),
+    combination(3, 3, 0),
+    combination(0, 0, 1),
+    combination(18, 22, 18),
+    combination(20, 22, 20),
+    combination(24, 22, 24),
+    combination(16, 22, 8),
+    combination(17, 4, 13),
+    [111, 0, 56],
+    [111, 16, 60],
+    combination(19, 22, 9),
+    combination(16, 28, 10),
+    combination(4, 23, 28),
+    combination(17, 22, 2),
+    combination(4, 0, 2),
+    combination(4, 28, 3),
+    combination(28, 3, 0),
+    combination(3, 28, 4),
+    combination(21, 28, 4),
+    combination(3, 28, 0),
+    combination(25, 3, 28),
+    combination(0, 28, 8),
+    combination(4, 3, 28),
+    combination(28, 3, 6),
+    combination(4, 28, 29),
+];
+
+// sums of the 16 title bytes of the Nintendo games the boot ROM knows
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+    0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+
+// checksums from here on are shared by several games, the fourth title letter tells them apart
+const FIRST_SHARED_CHECKSUM: usize = 65;
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// combination picked for each checksum
+const CHECKSUM_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3,
+    2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6, 5, 33, 25, 42, 42, 40, 2,
+    16, 25, 42, 42, 5, 0, 39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17, 46, 6, 27, 0, 47, 41,
+    41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+// combinations picked by holding a direction, optionally with A or B, while the boot logo shows
+const RIGHT: [u8; 3] = [1, 0, 6];
+const LEFT: [u8; 3] = [48, 40, 7];
+const UP: [u8; 3] = [5, 43, 28];
+const DOWN: [u8; 3] = [8, 3, 49];
+
+// colors a CGB shows DMG games in, one palette for the background and window and one for each OBP
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CompatibilityPalette {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+impl CompatibilityPalette {
+    // the grays of DMG-style output, white to black
+    pub const GRAYSCALE: CompatibilityPalette = CompatibilityPalette {
+        bg: [0x7FFF, 0x56B5, 0x294A, 0x0000],
+        obj0: [0x7FFF, 0x56B5, 0x294A, 0x0000],
+        obj1: [0x7FFF, 0x56B5, 0x294A, 0x0000],
+    };
+
+    // the palette the CGB boot ROM picks for a cartridge. Only games licensed by Nintendo are looked up
+    // by their title checksum, everything else gets the default (the same as right + A).
+    pub fn for_cartridge(cartridge: &Cartridge) -> CompatibilityPalette {
+        if !cartridge.is_nintendo_licensed() {
+            return CompatibilityPalette::combination(0);
+        }
+        let rom = cartridge.rom();
+        let checksum = cartridge.title_checksum();
+        let index = TITLE_CHECKSUMS.iter().enumerate().position(|(index, &entry)| {
+            let shared = index >= FIRST_SHARED_CHECKSUM;
+            entry == checksum && (!shared || FOURTH_LETTERS[index - FIRST_SHARED_CHECKSUM] == rom[0x137])
+        });
+        CompatibilityPalette::combination(index.map_or(0, |index| CHECKSUM_COMBINATIONS[index]))
+    }
+
+    // the palette chosen by the buttons held during the boot logo, none unless a direction is held.
+    // A wins over B when both are held.
+    pub fn for_buttons(buttons: &[Button]) -> Option<CompatibilityPalette> {
+        let held = |button: Button| buttons.contains(&button);
+        let combinations = if held(Button::Right) {
+            RIGHT
+        } else if held(Button::Left) {
+            LEFT
+        } else if held(Button::Up) {
+            UP
+        } else if held(Button::Down) {
+            DOWN
+        } else {
+            return None;
+        };
+        let face = if held(Button::A) { 1 } else if held(Button::B) { 2 } else { 0 };
+        Some(CompatibilityPalette::combination(combinations[face]))
+    }
+
+    fn combination(index: u8) -> CompatibilityPalette {
+        let colors = PALETTES.as_flattened();
+        let palette = |offset: usize| -> [u16; 4] { colors[offset..offset + 4].try_into().unwrap() };
+        let [obj0, obj1, bg] = COMBINATIONS[index as usize];
+        CompatibilityPalette {
+            bg: palette(bg),
+            obj0: palette(obj0),
+            obj1: palette(obj1),
+        }
+    }
+}
+
+impl Default for CompatibilityPalette {
+    fn default() -> Self {
+        CompatibilityPalette::GRAYSCALE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::MBC;
+
+    const RED: [u16; 4] = [0x7FFF, 0x421F, 0x1CF2, 0x0000];
+    const GREEN: [u16; 4] = [0x7FFF, 0x1BEF, 0x0200, 0x0000];
+    const BLUE: [u16; 4] = [0x7FFF, 0x7E8C, 0x7C00, 0x0000];
+    const YELLOW: [u16; 4] = [0x7FFF, 0x03FF, 0x001F, 0x0000];
+    const GRAY: [u16; 4] = [0x7FFF, 0x5294, 0x294A, 0x0000];
+    // combination 0, for games the boot ROM doesn't know
+    const DEFAULT: CompatibilityPalette = CompatibilityPalette {
+        bg: [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+        obj0: RED,
+        obj1: RED,
+    };
+
+    fn cartridge(title: &str, licensee: u8) -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title.as_bytes());
+        rom[0x14B] = licensee;
+        Cartridge::with_mbc(rom, MBC::None, 0)
+    }
+
+    fn palette(title: &str) -> CompatibilityPalette {
+        CompatibilityPalette::for_cartridge(&cartridge(title, 0x01))
+    }
+
+    #[test]
+    fn known_titles_get_their_palette() {
+        assert_eq!(palette("POKEMON RED"), CompatibilityPalette { bg: RED, obj0: GREEN, obj1: RED });
+        assert_eq!(palette("POKEMON BLUE"), CompatibilityPalette { bg: BLUE, obj0: RED, obj1: BLUE });
+        assert_eq!(palette("TETRIS"), CompatibilityPalette { bg: YELLOW, obj0: YELLOW, obj1: YELLOW });
+    }
+
+    #[test]
+    fn the_fourth_letter_tells_shared_checksums_apart() {
+        // both sum to 0x46
+        let metroid = palette("METROID2");
+        assert_eq!(metroid.bg, [0x7FFF, 0x7E8C, 0x7C00, 0x0000]);
+        assert_eq!(metroid.obj0, [0x03FF, 0x001F, 0x000C, 0x0000]);
+        assert_eq!(metroid.obj1, GREEN);
+        let mario = palette("SUPER MARIOLAND");
+        assert_eq!(mario.bg, [0x7ED6, 0x4BFF, 0x2175, 0x0000]);
+        // this one starts partway into a palette
+        assert_eq!(mario.obj0, [0x0000, 0x7FFF, 0x421F, 0x1CF2]);
+        // the same sum with a fourth letter no entry has
+        assert_eq!(palette("METXOID,"), DEFAULT);
+    }
+
+    #[test]
+    fn unknown_and_unlicensed_games_get_the_default() {
+        assert_eq!(palette("PINBALL"), DEFAULT);
+        assert_eq!(CompatibilityPalette::for_cartridge(&cartridge("POKEMON RED", 0x08)), DEFAULT);
+        // the new licensee code counts when the old one says to look there
+        let mut rom = cartridge("POKEMON RED", 0x33).rom().to_vec();
+        rom[0x144..0x146].copy_from_slice(b"01");
+        let cartridge = Cartridge::with_mbc(rom, MBC::None, 0);
+        assert_eq!(CompatibilityPalette::for_cartridge(&cartridge).bg, RED);
+    }
+
+    #[test]
+    fn every_button_combo_picks_its_own_palette() {
+        let mut palettes = Vec::new();
+        for direction in [Button::Up, Button::Down, Button::Left, Button::Right] {
+            for face in [None, Some(Button::A), Some(Button::B)] {
+                let buttons: Vec<Button> = std::iter::once(direction).chain(face).collect();
+                let palette = CompatibilityPalette::for_buttons(&buttons).unwrap();
+                assert!(!palettes.contains(&palette), "{:?} repeats a palette", buttons);
+                palettes.push(palette);
+            }
+        }
+        assert_eq!(palettes.len(), 12);
+        assert_eq!(CompatibilityPalette::for_buttons(&[Button::Right, Button::A]), Some(DEFAULT));
+        let gray = CompatibilityPalette { bg: GRAY, obj0: GRAY, obj1: GRAY };
+        assert_eq!(CompatibilityPalette::for_buttons(&[Button::Left, Button::B]), Some(gray));
+    }
+
+    #[test]
+    fn button_combos_need_a_direction_and_a_beats_b() {
+        assert_eq!(CompatibilityPalette::for_buttons(&[]), None);
+        assert_eq!(CompatibilityPalette::for_buttons(&[Button::A]), None);
+        assert_eq!(
+            CompatibilityPalette::for_buttons(&[Button::Up, Button::B, Button::A]),
+            CompatibilityPalette::for_buttons(&[Button::Up, Button::A])
+        );
+    }
+}
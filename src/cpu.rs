@@ -1,539 +1,1160 @@
-struct CPU {
-    registers: Registers,
-    pc: u16,
-    bus: MemoryBus,
+use crate::instructions::{
+    ArithmeticTarget, ByteRegister, IncDecTarget, Indirect, Instruction, JumpTest, LoadByteSource, LoadType,
+    StackTarget, WordRegister,
+};
+use crate::memorybus::MemoryBus;
+use crate::model::Model;
+use crate::registers::Registers;
+
+const INTERRUPT_ENABLE: u16 = 0xFFFF;
+const INTERRUPT_FLAG: u16 = 0xFF0F;
+const SPEED_SWITCH_CYCLES: u32 = 8200;
+
+// what the CPU needs from the memory map, the full Game Boy bus or a smaller one like the GBS player's
+pub trait Bus {
+    fn read_byte(&self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    // let the rest of the hardware run for a number of T-cycles
+    fn step(&mut self, cycles: u32);
+    // STOP with a CGB speed switch armed, returns whether the speed changed
+    fn switch_speed(&mut self) -> bool {
+        false
+    }
+    // T-cycles the CPU has to wait for VRAM DMA, see MemoryBus::take_vram_dma_stall
+    fn take_vram_dma_stall(&mut self, _halted: bool) -> u32 {
+        0
+    }
+}
+
+impl Bus for MemoryBus {
+    fn read_byte(&self, address: u16) -> u8 {
+        MemoryBus::read_byte(self, address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        MemoryBus::write(self, address, value)
+    }
+
+    fn step(&mut self, cycles: u32) {
+        MemoryBus::step(self, cycles)
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        MemoryBus::switch_speed(self)
+    }
+
+    fn take_vram_dma_stall(&mut self, halted: bool) -> u32 {
+        MemoryBus::take_vram_dma_stall(self, halted)
+    }
+}
+
+pub struct CPU<B: Bus = MemoryBus> {
+    pub registers: Registers,
+    pub pc: u16,
+    pub sp: u16,
+    pub bus: B,
+    pub is_halted: bool,
+    // an opcode that doesn't exist hangs the CPU for good, until the next power cycle
+    pub is_locked: bool,
+    // interrupt master enable
+    ime: bool,
+    // EI only takes effect after the instruction following it
+    ime_pending: bool,
+    // HALT with IME off and an interrupt already pending fails to increment PC for the next opcode
+    halt_bug: bool,
+}
+
+// the registers the boot ROM of each model leaves behind. Games tell CGB hardware apart by A = 0x11 and
+// MGB/SGB2 by A = 0xFF.
+fn boot_registers(bus: &MemoryBus) -> Registers {
+    let cartridge = &bus.cartridge;
+    // H and C are left set by the header checksum check unless the checksum happens to be 0
+    let header_flags = if cartridge.rom()[0x14D] == 0 { 0x80 } else { 0xB0 };
+    let (af, bc, de, hl) = match bus.model() {
+        Model::DMG0 => (0x0100, 0xFF13, 0x00C1, 0x8403),
+        Model::DMG => (0x0100 | header_flags, 0x0013, 0x00D8, 0x014D),
+        Model::MGB => (0xFF00 | header_flags, 0x0013, 0x00D8, 0x014D),
+        Model::SGB => (0x0100, 0x0014, 0x0000, 0xC060),
+        Model::SGB2 => (0xFF00, 0x0014, 0x0000, 0xC060),
+        Model::CGB | Model::AGB if bus.is_cgb_mode() => (0x1180, 0x0000, 0xFF56, 0x000D),
+        // in DMG mode B keeps the title checksum the palette was looked up by
+        Model::CGB | Model::AGB => {
+            let checksum = if cartridge.is_nintendo_licensed() { cartridge.title_checksum() } else { 0 };
+            (0x1180, (checksum as u16) << 8, 0x0008, 0x007C)
+        }
+    };
+    let mut registers = Registers::new();
+    registers.set_af(af);
+    registers.set_bc(bc);
+    registers.set_de(de);
+    registers.set_hl(hl);
+    // the AGB boot ROM finishes with INC B, setting bit 0 of B and the flags to match
+    if bus.model() == Model::AGB {
+        let b = registers.b;
+        registers.b = b.wrapping_add(1);
+        let zero = if registers.b == 0 { 0x80 } else { 0 };
+        let half_carry = if b & 0x0F == 0x0F { 0x20 } else { 0 };
+        registers.f = (zero | half_carry).into();
+    }
+    registers
 }
 
 impl CPU {
-    fn step(&mut self) {
+    pub fn new(bus: MemoryBus) -> CPU {
+        // with a boot ROM everything starts out cleared and the boot ROM runs from 0
+        let booting = bus.is_boot_rom_mapped();
+        let registers = if booting { Registers::new() } else { boot_registers(&bus) };
+        let mut cpu = CPU::with_bus(bus);
+        if !booting {
+            cpu.registers = registers;
+            cpu.pc = 0x0100;
+            cpu.sp = 0xFFFE;
+        }
+        cpu
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    // a CPU with every register cleared, running from 0
+    pub fn with_bus(bus: B) -> CPU<B> {
+        CPU {
+            registers: Registers::new(),
+            pc: 0x0000,
+            sp: 0x0000,
+            bus,
+            is_halted: false,
+            is_locked: false,
+            ime: false,
+            ime_pending: false,
+            halt_bug: false,
+        }
+    }
+
+    // run one instruction (or interrupt dispatch, or one idle HALT cycle) and let the rest of the
+    // hardware catch up, returns the number of T-cycles taken
+    pub fn step(&mut self) -> u32 {
+        // locked up, nothing is fetched and interrupts aren't taken, but the rest of the hardware keeps going
+        if self.is_locked {
+            self.bus.step(4);
+            return 4;
+        }
+        let mut cycles = self.handle_interrupts();
+        if cycles == 0 {
+            cycles = if self.is_halted { 4 } else { self.execute_next() };
+        }
+        self.bus.step(cycles);
+        // the CPU waits while VRAM DMA copies
+        let stall = self.bus.take_vram_dma_stall(self.is_halted);
+        if stall > 0 {
+            self.bus.step(stall);
+            cycles += stall;
+        }
+        cycles
+    }
+
+    fn handle_interrupts(&mut self) -> u32 {
+        let pending = self.bus.read_byte(INTERRUPT_ENABLE) & self.bus.read_byte(INTERRUPT_FLAG) & 0x1F;
+        if pending == 0 {
+            return 0;
+        }
+        // a pending interrupt ends HALT even when IME is off
+        self.is_halted = false;
+        if !self.ime {
+            return 0;
+        }
+        self.ime = false;
+        let interrupt = pending.trailing_zeros() as u16;
+        let flags = self.bus.read_byte(INTERRUPT_FLAG);
+        self.bus.write(INTERRUPT_FLAG, flags & !(1 << interrupt));
+        self.push(self.pc);
+        self.pc = 0x40 + interrupt * 8;
+        20
+    }
+
+    fn execute_next(&mut self) -> u32 {
+        let enable_interrupts = self.ime_pending;
+
         let mut instruction_byte = self.bus.read_byte(self.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
+        }
         let prefixed = instruction_byte == 0xCB;
         if prefixed {
-            instruction_byte = self.bus.read_byte(self.pc + 1);
+            instruction_byte = self.bus.read_byte(self.pc.wrapping_add(1));
         }
 
-        let next_pc = if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed) {
-            self.execute(instruction);
+        let (next_pc, cycles) = if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed) {
+            self.execute(instruction)
         } else {
-            let description = format!("0x{}{:x}", if prefixed { "cb" } else { "" }, instruction_byte);
-            panic!("Unkown instruction found for: {}", description)
+            self.is_locked = true;
+            (self.pc, 4)
         };
-
         self.pc = next_pc;
+
+        // DI right after EI cancels the pending enable
+        if enable_interrupts && self.ime_pending {
+            self.ime_pending = false;
+            self.ime = true;
+        }
+        cycles
+    }
+
+    fn read_next_byte(&self) -> u8 {
+        self.bus.read_byte(self.pc.wrapping_add(1))
+    }
+
+    fn read_next_word(&self) -> u16 {
+        // Gameboy is little endian so read pc + 2 as most significant bit
+        // and pc + 1 as least significant bit
+        let least_significant_byte = self.bus.read_byte(self.pc.wrapping_add(1)) as u16;
+        let most_significant_byte = self.bus.read_byte(self.pc.wrapping_add(2)) as u16;
+        (most_significant_byte << 8) | least_significant_byte
+    }
+
+    fn read_register(&self, register: ByteRegister) -> u8 {
+        match register {
+            ByteRegister::A => self.registers.a,
+            ByteRegister::B => self.registers.b,
+            ByteRegister::C => self.registers.c,
+            ByteRegister::D => self.registers.d,
+            ByteRegister::E => self.registers.e,
+            ByteRegister::H => self.registers.h,
+            ByteRegister::L => self.registers.l,
+            ByteRegister::HLI => self.bus.read_byte(self.registers.get_hl()),
+        }
+    }
+
+    fn write_register(&mut self, register: ByteRegister, value: u8) {
+        match register {
+            ByteRegister::A => self.registers.a = value,
+            ByteRegister::B => self.registers.b = value,
+            ByteRegister::C => self.registers.c = value,
+            ByteRegister::D => self.registers.d = value,
+            ByteRegister::E => self.registers.e = value,
+            ByteRegister::H => self.registers.h = value,
+            ByteRegister::L => self.registers.l = value,
+            ByteRegister::HLI => self.bus.write(self.registers.get_hl(), value),
+        }
+    }
+
+    fn read_word_register(&self, register: WordRegister) -> u16 {
+        match register {
+            WordRegister::BC => self.registers.get_bc(),
+            WordRegister::DE => self.registers.get_de(),
+            WordRegister::HL => self.registers.get_hl(),
+            WordRegister::SP => self.sp,
+        }
+    }
+
+    fn write_word_register(&mut self, register: WordRegister, value: u16) {
+        match register {
+            WordRegister::BC => self.registers.set_bc(value),
+            WordRegister::DE => self.registers.set_de(value),
+            WordRegister::HL => self.registers.set_hl(value),
+            WordRegister::SP => self.sp = value,
+        }
+    }
+
+    // value of an ALU operand and the extra cycles it costs to fetch it
+    fn arithmetic_operand(&self, target: ArithmeticTarget) -> (u8, u32) {
+        match target {
+            ArithmeticTarget::Register(ByteRegister::HLI) => (self.read_register(ByteRegister::HLI), 4),
+            ArithmeticTarget::Register(register) => (self.read_register(register), 0),
+            ArithmeticTarget::D8 => (self.read_next_byte(), 4),
+        }
+    }
+
+    fn arithmetic_length(target: ArithmeticTarget) -> u16 {
+        match target {
+            ArithmeticTarget::D8 => 2,
+            ArithmeticTarget::Register(_) => 1,
+        }
     }
 
-    fn jump(&self, should_jump: bool) -> u16 {
+    fn test_condition(&self, test: JumpTest) -> bool {
+        match test {
+            JumpTest::NotZero => !self.registers.f.zero,
+            JumpTest::NotCarry => !self.registers.f.carry,
+            JumpTest::Zero => self.registers.f.zero,
+            JumpTest::Carry => self.registers.f.carry,
+            JumpTest::Always => true,
+        }
+    }
+
+    fn push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(1);
+        self.bus.write(self.sp, (value >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.bus.write(self.sp, value as u8);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let least_significant_byte = self.bus.read_byte(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        let most_significant_byte = self.bus.read_byte(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        (most_significant_byte << 8) | least_significant_byte
+    }
+
+    fn jump(&self, should_jump: bool) -> (u16, u32) {
         if should_jump {
-          // Gameboy is little endian so read pc + 2 as most significant bit
-          // and pc + 1 as least significant bit
-          let least_significant_byte = self.bus.read_byte(self.pc + 1) as u16;
-          let most_significant_byte = self.bus.read_byte(self.pc + 2) as u16;
-          (most_significant_byte << 8) | least_significant_byte
+          (self.read_next_word(), 16)
         } else {
           // If we don't jump we need to still move the program
           // counter forward by 3 since the jump instruction is
           // 3 bytes wide (1 byte for tag and 2 bytes for jump address)
-          self.pc.wrapping_add(3)
+          (self.pc.wrapping_add(3), 12)
         }
-      }
+    }
 
-    fn execute(&mut self, instruction: Instruction){
-        match instruction{
-            Instruction::JP(test) => {
-                let jump_condition = match test {
-                    JumpTest::NotZero => !self.registers.f.zero,
-                    JumpTest::NotCarry => !self.registers.f.carry,
-                    JumpTest::Zero => self.registers.f.zero,
-                    JumpTest::Carry => self.registers.f.carry,
-                    JumpTest::Always => true,
-                };
-                self.jump(jump_condition)
-            }
-            Instruction::ADD(target) => {
-                match target {
-                    ArithmeticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                        self.pc.wrapping_add(1)
-                    }
-                    _=> { /* more targets */ }
+    fn jump_relative(&self, should_jump: bool) -> (u16, u32) {
+        let next_pc = self.pc.wrapping_add(2);
+        if should_jump {
+            let offset = self.read_next_byte() as i8;
+            (next_pc.wrapping_add(offset as u16), 12)
+        } else {
+            (next_pc, 8)
+        }
+    }
+
+    fn call(&mut self, should_jump: bool) -> (u16, u32) {
+        let next_pc = self.pc.wrapping_add(3);
+        if should_jump {
+            let address = self.read_next_word();
+            self.push(next_pc);
+            (address, 24)
+        } else {
+            (next_pc, 12)
+        }
+    }
+
+    fn return_(&mut self, should_jump: bool) -> u16 {
+        if should_jump {
+            self.pop()
+        } else {
+            self.pc.wrapping_add(1)
+        }
+    }
+
+    // returns the address of the next instruction and the T-cycles taken
+    fn execute(&mut self, instruction: Instruction) -> (u16, u32) {
+        match instruction {
+            Instruction::NOP => (self.pc.wrapping_add(1), 4),
+            Instruction::HALT => {
+                let pending = self.bus.read_byte(INTERRUPT_ENABLE) & self.bus.read_byte(INTERRUPT_FLAG) & 0x1F;
+                if !self.ime && pending != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.is_halted = true;
                 }
+                (self.pc.wrapping_add(1), 4)
+            }
+            // only the CGB speed switch is emulated, it takes 2050 M-cycles
+            Instruction::STOP => {
+                let cycles = if self.bus.switch_speed() { SPEED_SWITCH_CYCLES } else { 4 };
+                (self.pc.wrapping_add(2), cycles)
+            }
+            Instruction::DI => {
+                self.ime = false;
+                self.ime_pending = false;
+                (self.pc.wrapping_add(1), 4)
             }
-            Instruction::ADDHL(target) => {
-                //just like add, the the target is added to the HL register
-                let value = self.registers.c;
-                let new_value = self.add(value);
-                self.registershl = new_value;
-                self.flags.zero = result == 0;
-                self.flags.subtract = false;
-                self.flags.half_carry = (self.registers.a & 0xF) + (value & 0xF) > 0xF;
+            Instruction::EI => {
+                self.ime_pending = true;
+                (self.pc.wrapping_add(1), 4)
+            }
+
+            Instruction::ADD(target) => {
+                let (value, cycles) = self.arithmetic_operand(target);
+                self.registers.a = self.add(value);
+                (self.pc.wrapping_add(Self::arithmetic_length(target)), 4 + cycles)
             }
             Instruction::ADC(target) => {
-                //just like add, the the target is added to the A register, value of carry also added
-                let value = self.registers.c;
-                let new_value = self.add(value);
-                self.registers.a = new_value + self.flags.carry;
+                let (value, cycles) = self.arithmetic_operand(target);
+                self.registers.a = self.adc(value);
+                (self.pc.wrapping_add(Self::arithmetic_length(target)), 4 + cycles)
             }
             Instruction::SUB(target) => {
-                //just like add, the the target is subtracted from the A register
-                let value = self.registers.c;
-                let new_value = self.sub(value);
-                self.registers.a = new_value;
+                let (value, cycles) = self.arithmetic_operand(target);
+                self.registers.a = self.sub(value);
+                (self.pc.wrapping_add(Self::arithmetic_length(target)), 4 + cycles)
             }
             Instruction::SBC(target) => {
-                //just like add, the the target is subtracted from the A register, value of carry subtracted
-                let value = self.registers.c;
-                let new_value = self.sub(value);
-                self.registers.a = new_value - self.flags.carry;
-            }
-            Instruction::AND(target){
-                //do bitwise AND with the target and the A register
-                let value = self.registers.c;
-                self.registers.a = self.registers.a & value;
-                self.flags.zero = result == 0;
-                self.flags.subtract = false;
-                self.flags.half_carry = true; 
-                self.flags.carry = false;
-            }
-            Instruction::OR(target){
-                //do bitwise OR with the target and the A register
-                let value = self.registers.c;
-                self.registers.a = self.registers.a | value;
-                self.flags.zero = result == 0;
-                self.flag.subtract = false;
-                self.flags.half_carry = false;
-                self.flags.carry = false;
-            }
-            Instruction::XOR(target){
-                //do bitwise XOR with the target and the A register
-                let value = self.registers.c;
-                self.registers.a = self.registers.a ^ value;
-                self.flags.zero = result == 0;
-                self.flag.subtract = false; 
-                self.flags.half_carry = false;
-                self.flags.carry = false;
-            }
-            Instruction::CP(target){
-                //just like sub, the the target is subtracted from the A register, but the result is not stored
-                //back into A
-                let value = self.registers.c;
-                let new_value = self.sub(value);
-            }
-            Instruction::INC(target){
-                //increment the target in a specific register by 1
+                let (value, cycles) = self.arithmetic_operand(target);
+                self.registers.a = self.sbc(value);
+                (self.pc.wrapping_add(Self::arithmetic_length(target)), 4 + cycles)
+            }
+            Instruction::AND(target) => {
+                let (value, cycles) = self.arithmetic_operand(target);
+                self.registers.a = self.and(value);
+                (self.pc.wrapping_add(Self::arithmetic_length(target)), 4 + cycles)
+            }
+            Instruction::XOR(target) => {
+                let (value, cycles) = self.arithmetic_operand(target);
+                self.registers.a = self.xor(value);
+                (self.pc.wrapping_add(Self::arithmetic_length(target)), 4 + cycles)
+            }
+            Instruction::OR(target) => {
+                let (value, cycles) = self.arithmetic_operand(target);
+                self.registers.a = self.or(value);
+                (self.pc.wrapping_add(Self::arithmetic_length(target)), 4 + cycles)
+            }
+            Instruction::CP(target) => {
+                //just like sub, but the result is not stored back into A
+                let (value, cycles) = self.arithmetic_operand(target);
+                self.cp(value);
+                (self.pc.wrapping_add(Self::arithmetic_length(target)), 4 + cycles)
+            }
+            Instruction::ADDHL(register) => {
+                let value = self.read_word_register(register);
+                let new_value = self.addhl(value);
+                self.registers.set_hl(new_value);
+                (self.pc.wrapping_add(1), 8)
+            }
+            Instruction::ADDSP => {
+                self.sp = self.add_sp_offset();
+                (self.pc.wrapping_add(2), 16)
+            }
+            Instruction::INC(target) => {
                 match target {
-                    ArithmeticTarget::C => {
-                        self.registers.c = self.registers.c.wrapping_add(1);
+                    IncDecTarget::Byte(register) => {
+                        let value = self.read_register(register);
+                        let new_value = self.inc(value);
+                        self.write_register(register, new_value);
+                        (self.pc.wrapping_add(1), if register == ByteRegister::HLI { 12 } else { 4 })
+                    }
+                    IncDecTarget::Word(register) => {
+                        let value = self.read_word_register(register).wrapping_add(1);
+                        self.write_word_register(register, value);
+                        (self.pc.wrapping_add(1), 8)
                     }
-                    _=> { /* more targets */ }
                 }
             }
-            Instruction::DEC(target){
-                //decrement the target in a specific register by 1
+            Instruction::DEC(target) => {
                 match target {
-                    ArithmeticTarget::C => {
-                        self.registers.c = self.registers.c.wrapping_sub(1);
+                    IncDecTarget::Byte(register) => {
+                        let value = self.read_register(register);
+                        let new_value = self.dec(value);
+                        self.write_register(register, new_value);
+                        (self.pc.wrapping_add(1), if register == ByteRegister::HLI { 12 } else { 4 })
+                    }
+                    IncDecTarget::Word(register) => {
+                        let value = self.read_word_register(register).wrapping_sub(1);
+                        self.write_word_register(register, value);
+                        (self.pc.wrapping_add(1), 8)
                     }
-                    _=> { /* more targets */ }
                 }
             }
+            Instruction::DAA => {
+                self.daa();
+                (self.pc.wrapping_add(1), 4)
+            }
+            Instruction::CPL => {
+                self.cpl();
+                (self.pc.wrapping_add(1), 4)
+            }
             Instruction::CCF => {
-                //complement the carry flag
-                self.flags.carry = !self.flags.carry;
+                self.ccf();
+                (self.pc.wrapping_add(1), 4)
             }
             Instruction::SCF => {
-                //set the carry flag
-                self.flags.carry = true;
+                self.scf();
+                (self.pc.wrapping_add(1), 4)
             }
-            Instruction::RRA => {
-                //rotate the A register right through the carry flag
-                let carry = self.flags.carry;
-                self.flags.carry = self.registers.a & 1 != 0;
-                self.registers.a = (self.registers.a >> 1) | (carry << 7);
+            Instruction::RLCA => {
+                self.rlca();
+                (self.pc.wrapping_add(1), 4)
+            }
+            Instruction::RRCA => {
+                self.rrca();
+                (self.pc.wrapping_add(1), 4)
             }
             Instruction::RLA => {
-                //rotate the A register left through the carry flag
-                let carry = self.flags.carry;
-                self.flags.carry = self.registers.a & 0x80 != 0;
-                self.registers.a = (self.registers.a << 1) | carry;
+                self.rla();
+                (self.pc.wrapping_add(1), 4)
             }
-            Instruction::RRCA => {
-                //rotate the A register right (not through the carry flag)
-                self.flags.carry = self.registers.a & 1 != 0;
-                self.registers.a = (self.registers.a >> 1) | (self.registers.a << 7);
+            Instruction::RRA => {
+                self.rra();
+                (self.pc.wrapping_add(1), 4)
             }
-            Instruction::RLCA => {
-                //rotate the A register left (not through the carry flag)
-                self.flags.carry = self.registers.a & 0x80 != 0;
-                self.registers.a = (self.registers.a << 1) | (self.registers.a >> 7);
+
+            Instruction::JP(test) => {
+                let jump_condition = self.test_condition(test);
+                self.jump(jump_condition)
+            }
+            Instruction::JPHL => (self.registers.get_hl(), 4),
+            Instruction::JR(test) => {
+                let jump_condition = self.test_condition(test);
+                self.jump_relative(jump_condition)
+            }
+            Instruction::CALL(test) => {
+                let jump_condition = self.test_condition(test);
+                self.call(jump_condition)
+            }
+            Instruction::RET(test) => {
+                let jump_condition = self.test_condition(test);
+                let next_pc = self.return_(jump_condition);
+                let cycles = match (test, jump_condition) {
+                    (JumpTest::Always, _) => 16,
+                    (_, true) => 20,
+                    (_, false) => 8,
+                };
+                (next_pc, cycles)
+            }
+            Instruction::RETI => {
+                self.ime = true;
+                (self.pop(), 16)
+            }
+            Instruction::RST(address) => {
+                self.push(self.pc.wrapping_add(1));
+                (address as u16, 16)
+            }
+
+            Instruction::LD(load_type) => self.execute_load(load_type),
+            Instruction::PUSH(target) => {
+                let value = match target {
+                    StackTarget::AF => self.registers.get_af(),
+                    StackTarget::BC => self.registers.get_bc(),
+                    StackTarget::DE => self.registers.get_de(),
+                    StackTarget::HL => self.registers.get_hl(),
+                };
+                self.push(value);
+                (self.pc.wrapping_add(1), 16)
+            }
+            Instruction::POP(target) => {
+                let value = self.pop();
+                match target {
+                    StackTarget::AF => self.registers.set_af(value),
+                    StackTarget::BC => self.registers.set_bc(value),
+                    StackTarget::DE => self.registers.set_de(value),
+                    StackTarget::HL => self.registers.set_hl(value),
+                };
+                (self.pc.wrapping_add(1), 12)
+            }
+
+            Instruction::RLC(register) => self.execute_prefixed(register, CPU::rlc),
+            Instruction::RRC(register) => self.execute_prefixed(register, CPU::rrc),
+            Instruction::RL(register) => self.execute_prefixed(register, CPU::rl),
+            Instruction::RR(register) => self.execute_prefixed(register, CPU::rr),
+            Instruction::SLA(register) => self.execute_prefixed(register, CPU::sla),
+            Instruction::SRA(register) => self.execute_prefixed(register, CPU::sra),
+            Instruction::SWAP(register) => self.execute_prefixed(register, CPU::swap),
+            Instruction::SRL(register) => self.execute_prefixed(register, CPU::srl),
+            Instruction::BIT(bit, register) => {
+                let value = self.read_register(register);
+                self.bit(value, bit);
+                (self.pc.wrapping_add(2), if register == ByteRegister::HLI { 12 } else { 8 })
+            }
+            Instruction::RES(bit, register) => {
+                let value = self.read_register(register);
+                self.write_register(register, reset(value, bit));
+                (self.pc.wrapping_add(2), if register == ByteRegister::HLI { 16 } else { 8 })
+            }
+            Instruction::SET(bit, register) => {
+                let value = self.read_register(register);
+                self.write_register(register, set(value, bit));
+                (self.pc.wrapping_add(2), if register == ByteRegister::HLI { 16 } else { 8 })
+            }
+        }
+    }
+
+    fn execute_prefixed(&mut self, register: ByteRegister, operation: fn(&mut CPU<B>, u8) -> u8) -> (u16, u32) {
+        let value = self.read_register(register);
+        let new_value = operation(self, value);
+        self.write_register(register, new_value);
+        (self.pc.wrapping_add(2), if register == ByteRegister::HLI { 16 } else { 8 })
+    }
+
+    fn indirect_address(&mut self, indirect: Indirect) -> u16 {
+        match indirect {
+            Indirect::BCIndirect => self.registers.get_bc(),
+            Indirect::DEIndirect => self.registers.get_de(),
+            Indirect::HLIndirectPlus => {
+                let hl = self.registers.get_hl();
+                self.registers.set_hl(hl.wrapping_add(1));
+                hl
+            }
+            Indirect::HLIndirectMinus => {
+                let hl = self.registers.get_hl();
+                self.registers.set_hl(hl.wrapping_sub(1));
+                hl
+            }
+            Indirect::WordIndirect => self.read_next_word(),
+            Indirect::LastByteIndirect => 0xFF00 + self.registers.c as u16,
+        }
+    }
+
+    fn indirect_timing(indirect: Indirect) -> (u16, u32) {
+        match indirect {
+            Indirect::WordIndirect => (3, 16),
+            _ => (1, 8),
+        }
+    }
+
+    fn execute_load(&mut self, load_type: LoadType) -> (u16, u32) {
+        match load_type {
+            LoadType::Byte(target, source) => {
+                let (value, length, cycles) = match source {
+                    LoadByteSource::Register(register) => {
+                        (self.read_register(register), 1, if register == ByteRegister::HLI { 8 } else { 4 })
+                    }
+                    LoadByteSource::D8 => (self.read_next_byte(), 2, 8),
+                };
+                self.write_register(target, value);
+                let cycles = if target == ByteRegister::HLI { cycles + 4 } else { cycles };
+                (self.pc.wrapping_add(length), cycles)
+            }
+            LoadType::Word(register) => {
+                let value = self.read_next_word();
+                self.write_word_register(register, value);
+                (self.pc.wrapping_add(3), 12)
+            }
+            LoadType::AFromIndirect(indirect) => {
+                let address = self.indirect_address(indirect);
+                self.registers.a = self.bus.read_byte(address);
+                let (length, cycles) = Self::indirect_timing(indirect);
+                (self.pc.wrapping_add(length), cycles)
+            }
+            LoadType::IndirectFromA(indirect) => {
+                let address = self.indirect_address(indirect);
+                self.bus.write(address, self.registers.a);
+                let (length, cycles) = Self::indirect_timing(indirect);
+                (self.pc.wrapping_add(length), cycles)
+            }
+            LoadType::AFromByteAddress => {
+                let address = 0xFF00 + self.read_next_byte() as u16;
+                self.registers.a = self.bus.read_byte(address);
+                (self.pc.wrapping_add(2), 12)
+            }
+            LoadType::ByteAddressFromA => {
+                let address = 0xFF00 + self.read_next_byte() as u16;
+                self.bus.write(address, self.registers.a);
+                (self.pc.wrapping_add(2), 12)
+            }
+            LoadType::SPFromHL => {
+                self.sp = self.registers.get_hl();
+                (self.pc.wrapping_add(1), 8)
+            }
+            LoadType::HLFromSPN => {
+                let value = self.add_sp_offset();
+                self.registers.set_hl(value);
+                (self.pc.wrapping_add(2), 12)
+            }
+            LoadType::IndirectFromSP => {
+                let address = self.read_next_word();
+                self.bus.write(address, self.sp as u8);
+                self.bus.write(address.wrapping_add(1), (self.sp >> 8) as u8);
+                (self.pc.wrapping_add(3), 20)
             }
-            Instruction::CPL => {
-                //complement the A register
-                self.registers.a = !self.registers.a;
-            }
-            Instruction::BIT => {
-                //test the bit in the A register
-                let value = self.registers.c;
-                self.flags.zero = (self.registers.a & (1 << value)) == 0;
-                self.flags.subtract = false;
-                self.flags.half_carry = true; //TODO check
-            }
-            Instruction::RESET => {
-                //reset the bit in the A register
-                let value = self.registers.c;
-                self.registers.a &= !(1 << value);
-            }
-            Instruction::SET => {
-                //set the bit in the A register
-                let value = self.registers.c;
-                self.registers.a |= 1 << value;
-            }
-            Instruction::RESET => {
-                //reset the bit in the register to 0
-                let value = self.registers.c;
-                //TODO implement
-            }
-            Instruction::SET => {
-                //set the bit in the register to 1
-                let value = self.registers.c;
-                //TODO implement
-            }
-            Instruction::SRL => {
-                //shift the register right
-                let value = self.registers.c;
-                self.flags.carry = value & 1 != 0;
-                self.registers.c = value >> 1;
-                self.flags.zero = self.registers.c == 0;
-                self.flags.subtract = false;
-                self.flags.half_carry = false;
-            }
-            Instruction::RR => {
-                //rotate the register right by 1 through the carry flag
-                let value = self.registers.c;
-                let carry = self.flags.carry;
-                self.flags.carry = value & 1 != 0;
-                self.registers.c = (value >> 1) | (carry << 7);
-                self.flags.zero = self.registers.c == 0;
-                self.flags.subtract = false;
-                self.flags.half_carry = false;
-            }
-            Instruction::RL => {
-                //rotate the register left by 1 through the carry flag
-                let value = self.registers.c;
-                let carry = self.flags.carry;
-                self.flags.carry = value & 0x80 != 0;
-                self.registers.c = (value << 1) | carry;
-                self.flags.zero = self.registers.c == 0;
-                self.flags.subtract = false;
-                self.flags.half_carry = false;
-            }
-            Instruction::RRC => {
-                //rotate the register right (not through the carry flag)
-                let value = self.registers.c;
-                self.flags.carry = value & 1 != 0;
-                self.registers.c = (value >> 1) | (value << 7);
-                self.flags.zero = self.registers.c == 0;
-                self.flags.subtract = false;
-                self.flags.half_carry = false;
-            }
-            Instruction::RLC => {
-                //rotate the register left (not through the carry flag)
-                let value = self.registers.c;
-                self.flags.carry = value & 0x80 != 0;
-                self.registers.c = (value << 1) | (value >> 7);
-                self.flags.zero = self.registers.c == 0;
-                self.flags.subtract = false;
-                self.flags.half_carry = false;
-            }
-            Instruction::SRA => {
-                //aritmetic shift the register right by 1
-                let value = self.registers.c;
-                self.flags.carry = value & 1 != 0;
-                self.registers.c = (value >> 1) | (value & 0x80);
-                self.flags.zero = self.registers.c == 0;
-                self.flags.subtract = false;
-                self.flags.half_carry = false;
-            }
-            Instruction::SLA => {
-                //aritmetic shift the register left by 1
-                let value = self.registers.c;
-                self.flags.carry = value & 0x80 != 0;
-                self.registers.c = value << 1;
-                self.flags.zero = self.registers.c == 0;
-                self.flags.subtract = false;
-                self.flags.half_carry = false;
-            }
-            Instruction::SWAP => {
-                //swap the upper and lower nibbles of the register
-                let value = self.registers.c;
-                self.registers.c = (value << 4) | (value >> 4);
-                self.flags.zero = self.registers.c == 0;
-                self.flags.subtract = false;
-                self.flags.half_carry = false;
-                self.flags.carry = false;
-            }
-            _=> { /* more instructions */ } self.pc
         }
     }
 
+    // ADD (add) - add the value of a specific register to the A register
     fn add(&mut self, value: u8) -> u8 {
         let (new_value, did_overflow) = self.registers.a.overflowing_add(value);
-        self.registers.flags.zero = new_value == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.carry = did_overflow;
+        self.registers.f.zero = new_value == 0;
+        self.registers.f.subtract = false;
+        self.registers.f.carry = did_overflow;
         // set if adding the lower nibbles and register A is greater than 0xF
         // if it is, then the addition caused a carry from the lower nibble to the upper nibble
-        self.registers.flags.half_carry = (self.registers.a & 0xF) + (value & 0xF) > 0xF;
-        
+        self.registers.f.half_carry = (self.registers.a & 0xF) + (value & 0xF) > 0xF;
+
         new_value
     }
 
     // ADDHL (add to HL) - just like ADD except that the target is added to the HL register
-    fn addhl(&mut self, value: u8) -> u8 {
-        let (new_value, did_overflow) = self.registers.hl.overflowing_add(value);
-        self.registers.flags.zero = new_value == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.carry = did_overflow;
-        // set if adding the lower nibbles and register A is greater than 0xF
-        // if it is, then the addition caused a carry from the lower nibble to the upper nibble
-        self.registers.flags.half_carry = (self.registers.hl & 0xF) + (value & 0xF) > 0xF;
-        
+    fn addhl(&mut self, value: u16) -> u16 {
+        let hl = self.registers.get_hl();
+        let (new_value, did_overflow) = hl.overflowing_add(value);
+        self.registers.f.subtract = false;
+        self.registers.f.carry = did_overflow;
+        // for 16-bit additions the half carry is the carry out of bit 11
+        self.registers.f.half_carry = (hl & 0xFFF) + (value & 0xFFF) > 0xFFF;
+
         new_value
     }
 
+    // ADD SP, e8 / LD HL, SP + e8 - the flags come from the unsigned addition of the low byte
+    fn add_sp_offset(&mut self) -> u16 {
+        let offset = self.read_next_byte();
+        let sp = self.sp;
+        self.registers.f.zero = false;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = (sp & 0xF) + (offset as u16 & 0xF) > 0xF;
+        self.registers.f.carry = (sp & 0xFF) + offset as u16 > 0xFF;
+
+        sp.wrapping_add(offset as i8 as u16)
+    }
+
     // ADC (add with carry) - just like ADD except that the value of the carry flag is also added to the number
     fn adc(&mut self, value: u8) -> u8 {
-        let (new_value, did_overflow) = self.registers.a.overflowing_add(value + self.registers.flags.carry as u8);
-        self.registers.flags.zero = new_value == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.carry = did_overflow;
-        // set if adding the lower nibbles and register A is greater than 0xF
-        // if it is, then the addition caused a carry from the lower nibble to the upper nibble
-        self.registers.flags.half_carry = (self.registers.a & 0xF) + (value & 0xF) > 0xF;
-        
+        let carry = self.registers.f.carry as u8;
+        let new_value = self.registers.a.wrapping_add(value).wrapping_add(carry);
+        self.registers.f.zero = new_value == 0;
+        self.registers.f.subtract = false;
+        self.registers.f.carry = self.registers.a as u16 + value as u16 + carry as u16 > 0xFF;
+        self.registers.f.half_carry = (self.registers.a & 0xF) + (value & 0xF) + carry > 0xF;
+
         new_value
     }
 
     // SUB (subtract) - subtract the value stored in a specific register with the value in the A register
     fn sub(&mut self, value: u8) -> u8 {
         let (new_value, did_overflow) = self.registers.a.overflowing_sub(value);
-        self.registers.flags.zero = new_value == 0;
-        self.registers.flags.subtract = true;
-        self.registers.flags.carry = did_overflow;
-        // set if adding the lower nibbles and register A is greater than 0xF
-        // if it is, then the addition caused a carry from the lower nibble to the upper nibble
-        self.registers.flags.half_carry = (self.registers.a & 0xF) < (value & 0xF);
-        
+        self.registers.f.zero = new_value == 0;
+        self.registers.f.subtract = true;
+        self.registers.f.carry = did_overflow;
+        // set if the lower nibble of A is smaller than the lower nibble of the value,
+        // the subtraction then borrowed from the upper nibble
+        self.registers.f.half_carry = (self.registers.a & 0xF) < (value & 0xF);
+
         new_value
     }
 
-    // SBC (subtract with carry) - just like ADD except that the value of the carry flag is also subtracted from the number
+    // SBC (subtract with carry) - just like SUB except that the value of the carry flag is also subtracted from the number
     fn sbc(&mut self, value: u8) -> u8 {
-        let (new_value, did_overflow) = self.registers.a.overflowing_sub(value - self.registers.flags.carry as u8);
-        self.registers.flags.zero = new_value == 0;
-        self.registers.flags.subtract = true;
-        self.registers.flags.carry = did_overflow;
-        // set if adding the lower nibbles and register A is greater than 0xF
-        // if it is, then the addition caused a carry from the lower nibble to the upper nibble
-        self.registers.flags.half_carry = (self.registers.a & 0xF) < (value & 0xF);
-        
+        let carry = self.registers.f.carry as u8;
+        let new_value = self.registers.a.wrapping_sub(value).wrapping_sub(carry);
+        self.registers.f.zero = new_value == 0;
+        self.registers.f.subtract = true;
+        self.registers.f.carry = (self.registers.a as u16) < value as u16 + carry as u16;
+        self.registers.f.half_carry = (self.registers.a & 0xF) < (value & 0xF) + carry;
+
         new_value
     }
 
     // AND (logical and) - do a bitwise and on the value in a specific register and the value in the A register
     fn and(&mut self, value: u8) -> u8 {
         let new_value = self.registers.a & value;
-        self.registers.flags.zero = new_value == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.half_carry = true; 
-        self.registers.flags.carry = false;
-        
+        self.registers.f.zero = new_value == 0;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = true;
+        self.registers.f.carry = false;
+
         new_value
     }
 
     // OR (logical or) - do a bitwise or on the value in a specific register and the value in the A register
     fn or(&mut self, value: u8) -> u8 {
         let new_value = self.registers.a | value;
-        self.registers.flags.zero = new_value == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.half_carry = false;
-        self.registers.flags.carry = false;
-        
+        self.registers.f.zero = new_value == 0;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = false;
+        self.registers.f.carry = false;
+
         new_value
     }
 
     // XOR (logical xor) - do a bitwise xor on the value in a specific register and the value in the A register
     fn xor(&mut self, value: u8) -> u8 {
         let new_value = self.registers.a ^ value;
-        self.registers.flags.zero = new_value == 0;
-        self.registers.flags.subtract = false; 
-        self.registers.flags.half_carry = false;
-        self.registers.flags.carry = false;
-        
+        self.registers.f.zero = new_value == 0;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = false;
+        self.registers.f.carry = false;
+
         new_value
     }
 
     // CP (compare) - just like SUB except the result of the subtraction is not stored back into A
-    fn cp(&mut self, value: u8) -> u8 {
-        let new_value = self.registers.a.overflowing_sub(value).0;
-        self.registers.flags.zero = new_value == 0;
-        self.registers.flags.subtract = true;
-        self.registers.flags.carry = new_value > self.registers.a;
-        self.registers.flags.half_carry = (self.registers.a & 0xF) < (value & 0xF);
-        
-        new_value
+    fn cp(&mut self, value: u8) {
+        self.sub(value);
     }
 
     // INC (increment) - increment the value in a specific register by 1
     fn inc(&mut self, value: u8) -> u8 {
         let new_value = value.wrapping_add(1);
-        self.registers.flags.zero = new_value == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.half_carry = (value & 0xF) + 1 > 0xF;
-        
+        self.registers.f.zero = new_value == 0;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = (value & 0xF) + 1 > 0xF;
+
         new_value
     }
 
     // DEC (decrement) - decrement the value in a specific register by 1
     fn dec(&mut self, value: u8) -> u8 {
         let new_value = value.wrapping_sub(1);
-        self.registers.flags.zero = new_value == 0;
-        self.registers.flags.subtract = true;
-        self.registers.flags.half_carry = (value & 0xF) < 1;
-        
+        self.registers.f.zero = new_value == 0;
+        self.registers.f.subtract = true;
+        self.registers.f.half_carry = (value & 0xF) < 1;
+
         new_value
     }
 
+    // DAA (decimal adjust) - turn the result of a BCD addition or subtraction back into BCD
+    fn daa(&mut self) {
+        let mut a = self.registers.a;
+        let mut carry = self.registers.f.carry;
+        if self.registers.f.subtract {
+            if self.registers.f.half_carry {
+                a = a.wrapping_sub(0x06);
+            }
+            if carry {
+                a = a.wrapping_sub(0x60);
+            }
+        } else {
+            if carry || a > 0x99 {
+                a = a.wrapping_add(0x60);
+                carry = true;
+            }
+            if self.registers.f.half_carry || (a & 0x0F) > 0x09 {
+                a = a.wrapping_add(0x06);
+            }
+        }
+        self.registers.a = a;
+        self.registers.f.zero = a == 0;
+        self.registers.f.half_carry = false;
+        self.registers.f.carry = carry;
+    }
+
     // CCF (complement carry flag) - toggle the value of the carry flag
     fn ccf(&mut self) {
-        self.registers.flags.carry = !self.registers.flags.carry;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = false;
+        self.registers.f.carry = !self.registers.f.carry;
     }
 
     // SCF (set carry flag) - set the carry flag to true
     fn scf(&mut self) {
-        self.registers.flags.carry = true;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = false;
+        self.registers.f.carry = true;
     }
 
     // RRA (rotate right A register) - bit rotate A register right through the carry flag
     fn rra(&mut self) {
-        let carry = self.registers.flags.carry;
-        self.registers.flags.carry = self.registers.a & 1 != 0;
-        self.registers.a = (self.registers.a >> 1) | (carry << 7);
+        let value = self.registers.a;
+        self.registers.a = self.rr(value);
+        self.registers.f.zero = false;
     }
 
     // RLA (rotate left A register) - bit rotate A register left through the carry flag
     fn rla(&mut self) {
-        let carry = self.registers.flags.carry;
-        self.registers.flags.carry = self.registers.a & 0x80 != 0;
-        self.registers.a = (self.registers.a << 1) | carry;
+        let value = self.registers.a;
+        self.registers.a = self.rl(value);
+        self.registers.f.zero = false;
     }
 
     // RRCA (rotate right A register) - bit rotate A register right (not through the carry flag)
     fn rrca(&mut self) {
-        self.registers.flags.carry = self.registers.a & 1 != 0;
-        self.registers.a = (self.registers.a >> 1) | (self.registers.a << 7);
+        let value = self.registers.a;
+        self.registers.a = self.rrc(value);
+        self.registers.f.zero = false;
     }
 
-    // RRLA (rotate left A register) - bit rotate A register left (not through the carry flag)
-    fn rrla(&mut self) {
-        self.registers.flags.carry = self.registers.a & 0x80 != 0;
-        self.registers.a = (self.registers.a << 1) | (self.registers.a >> 7);
+    // RLCA (rotate left A register) - bit rotate A register left (not through the carry flag)
+    fn rlca(&mut self) {
+        let value = self.registers.a;
+        self.registers.a = self.rlc(value);
+        self.registers.f.zero = false;
     }
 
     // CPL (complement) - toggle every bit of the A register
     fn cpl(&mut self) {
         self.registers.a = !self.registers.a;
+        self.registers.f.subtract = true;
+        self.registers.f.half_carry = true;
     }
 
     // BIT (bit test) - test to see if a specific bit of a specific register is set
-    fn bit(&mut self, value: u8) {
-        self.registers.flags.zero = (self.registers.a & (1 << value)) == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.half_carry = true; //TODO check
+    fn bit(&mut self, value: u8, bit: u8) {
+        self.registers.f.zero = (value & (1 << bit)) == 0;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = true;
     }
 
-    // RESET (bit reset) - set a specific bit of a specific register to 0
-    fn reset(&mut self, value: u8) {
-        self.registers.a &= !(1 << value);
-    }
-
-    // SET (bit set) - set a specific bit of a specific register to 1
-    fn set(&mut self, value: u8) {
-        self.registers.a |= 1 << value;
+    fn set_shift_flags(&mut self, new_value: u8, carry: bool) {
+        self.registers.f.zero = new_value == 0;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = false;
+        self.registers.f.carry = carry;
     }
 
     // SRL (shift right logical) - bit shift a specific register right by 1
-    fn srl(&mut self, value: u8) {
-        self.registers.flags.carry = value & 1 != 0;
-        self.registers.a = value >> 1;
-        self.registers.flags.zero = self.registers.a == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.half_carry = false;
+    fn srl(&mut self, value: u8) -> u8 {
+        let new_value = value >> 1;
+        self.set_shift_flags(new_value, value & 1 != 0);
+        new_value
     }
 
     // RR (rotate right) - bit rotate a specific register right by 1 through the carry flag
-    fn rr(&mut self, value: u8) {
-        let carry = self.registers.flags.carry;
-        self.registers.flags.carry = value & 1 != 0;
-        self.registers.a = (value >> 1) | (carry << 7);
-        self.registers.flags.zero = self.registers.a == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.half_carry = false;
+    fn rr(&mut self, value: u8) -> u8 {
+        let new_value = (value >> 1) | ((self.registers.f.carry as u8) << 7);
+        self.set_shift_flags(new_value, value & 1 != 0);
+        new_value
     }
 
     // RL (rotate left) - bit rotate a specific register left by 1 through the carry flag
-    fn rl(&mut self, value: u8) {
-        let carry = self.registers.flags.carry;
-        self.registers.flags.carry = value & 0x80 != 0;
-        self.registers.a = (value << 1) | carry;
-        self.registers.flags.zero = self.registers.a == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.half_carry = false;
-    }
-    
+    fn rl(&mut self, value: u8) -> u8 {
+        let new_value = (value << 1) | self.registers.f.carry as u8;
+        self.set_shift_flags(new_value, value & 0x80 != 0);
+        new_value
+    }
+
     // RRC (rotate right) - bit rotate a specific register right by 1 (not through the carry flag)
-    fn rrc(&mut self, value: u8) {
-        self.registers.flags.carry = value & 1 != 0;
-        self.registers.a = (value >> 1) | (value << 7);
-        self.registers.flags.zero = self.registers.a == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.half_carry = false;
+    fn rrc(&mut self, value: u8) -> u8 {
+        let new_value = value.rotate_right(1);
+        self.set_shift_flags(new_value, value & 1 != 0);
+        new_value
     }
 
     // RLC (rotate left) - bit rotate a specific register left by 1 (not through the carry flag)
-    fn rlc(&mut self, value: u8) {
-        self.registers.flags.carry = value & 0x80 != 0;
-        self.registers.a = (value << 1) | (value >> 7);
-        self.registers.flags.zero = self.registers.a == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.half_carry = false;
-    }
-    
+    fn rlc(&mut self, value: u8) -> u8 {
+        let new_value = value.rotate_left(1);
+        self.set_shift_flags(new_value, value & 0x80 != 0);
+        new_value
+    }
+
     // SRA (shift right arithmetic) - arithmetic shift a specific register right by 1
-    fn sra(&mut self, value: u8) {
-        self.registers.flags.carry = value & 1 != 0;
-        self.registers.a = (value >> 1) | (value & 0x80);
-        self.registers.flags.zero = self.registers.a == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.half_carry = false;
+    fn sra(&mut self, value: u8) -> u8 {
+        let new_value = (value >> 1) | (value & 0x80);
+        self.set_shift_flags(new_value, value & 1 != 0);
+        new_value
     }
 
     // SLA (shift left arithmetic) - arithmetic shift a specific register left by 1
-    fn sla(&mut self, value: u8) {
-        self.registers.flags.carry = value & 0x80 != 0;
-        self.registers.a = value << 1;
-        self.registers.flags.zero = self.registers.a == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.half_carry = false;
-    }
-    
+    fn sla(&mut self, value: u8) -> u8 {
+        let new_value = value << 1;
+        self.set_shift_flags(new_value, value & 0x80 != 0);
+        new_value
+    }
+
     // SWAP (swap nibbles) - switch upper and lower nibble of a specific register
-    fn swap(&mut self, value: u8) {
-        self.registers.a = (value << 4) | (value >> 4);
-        self.registers.flags.zero = self.registers.a == 0;
-        self.registers.flags.subtract = false;
-        self.registers.flags.half_carry = false;
-        self.registers.flags.carry = false;
-    }
-}
\ No newline at end of file
+    fn swap(&mut self, value: u8) -> u8 {
+        let new_value = value.rotate_left(4);
+        self.set_shift_flags(new_value, false);
+        new_value
+    }
+}
+
+// RES (bit reset) - set a specific bit of a specific register to 0
+fn reset(value: u8, bit: u8) -> u8 {
+    value & !(1 << bit)
+}
+
+// SET (bit set) - set a specific bit of a specific register to 1
+fn set(value: u8, bit: u8) -> u8 {
+    value | (1 << bit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{Cartridge, MBC};
+    use crate::ppu::{Renderer, PPU};
+
+    // 64 KiB of plain RAM with nothing else on it
+    struct TestBus {
+        memory: Vec<u8>,
+    }
+
+    impl Bus for TestBus {
+        fn read_byte(&self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.memory[address as usize] = value;
+        }
+
+        fn step(&mut self, _cycles: u32) {}
+    }
+
+    // a CPU about to run the program at 0x0100, the stack at 0xFFFE
+    fn running(program: &[u8]) -> CPU<TestBus> {
+        let mut memory = vec![0; 0x10000];
+        memory[0x0100..0x0100 + program.len()].copy_from_slice(program);
+        let mut cpu = CPU::with_bus(TestBus { memory });
+        cpu.pc = 0x0100;
+        cpu.sp = 0xFFFE;
+        cpu
+    }
+
+    fn flags(cpu: &CPU<TestBus>) -> u8 {
+        u8::from(cpu.registers.f)
+    }
+
+    // program, A, B and F before, A and F after, T-cycles
+    type AluCase = (&'static [u8], u8, u8, u8, u8, u8, u32);
+
+    const ALU_CASES: [AluCase; 9] = [
+        // add a,b
+        (&[0x80], 0x3A, 0xC6, 0x00, 0x00, 0xB0, 4),
+        // sub d8
+        (&[0xD6, 0x3E], 0x3E, 0x00, 0x00, 0x00, 0xC0, 8),
+        // adc a,b with carry in
+        (&[0x88], 0xE1, 0x0F, 0x10, 0xF1, 0x20, 4),
+        // inc a keeps the carry
+        (&[0x3C], 0x0F, 0x00, 0x10, 0x10, 0x30, 4),
+        // dec a
+        (&[0x3D], 0x01, 0x00, 0x00, 0x00, 0xC0, 4),
+        // cp d8 leaves A alone
+        (&[0xFE, 0x40], 0x3C, 0x00, 0x00, 0x3C, 0x50, 8),
+        // and b always sets H
+        (&[0xA0], 0x5A, 0x0F, 0x10, 0x0A, 0x20, 4),
+        // xor a
+        (&[0xAF], 0x5A, 0x00, 0x70, 0x00, 0x80, 4),
+        // rlca clears Z even for a zero result
+        (&[0x07], 0x85, 0x00, 0x80, 0x0B, 0x10, 4),
+    ];
+
+    #[test]
+    fn alu_opcodes_set_the_flags() {
+        for (program, a, b, f, expected_a, expected_f, expected_cycles) in ALU_CASES {
+            let mut cpu = running(program);
+            cpu.registers.a = a;
+            cpu.registers.b = b;
+            cpu.registers.f = f.into();
+            let cycles = cpu.step();
+            assert_eq!(cpu.registers.a, expected_a, "A after {:02x?}", program);
+            assert_eq!(flags(&cpu), expected_f, "F after {:02x?}", program);
+            assert_eq!(cycles, expected_cycles, "cycles of {:02x?}", program);
+        }
+    }
+
+    #[test]
+    fn daa_adjusts_a_bcd_addition() {
+        let mut cpu = running(&[0xC6, 0x38, 0x27]);
+        cpu.registers.a = 0x45;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x83);
+        assert_eq!(flags(&cpu), 0x00);
+    }
+
+    #[test]
+    fn prefixed_opcodes_set_the_flags() {
+        // rl c, then bit 7,h
+        let mut cpu = running(&[0xCB, 0x11, 0xCB, 0x7C]);
+        cpu.registers.c = 0x80;
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.registers.c, 0x00);
+        assert_eq!(flags(&cpu), 0x90);
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(flags(&cpu), 0xB0);
+        assert_eq!(cpu.pc, 0x0104);
+    }
+
+    #[test]
+    fn sixteen_bit_additions_take_h_and_c_from_the_right_bits() {
+        // add hl,bc carries out of bit 11 and 15 and leaves Z alone
+        let mut cpu = running(&[0x09]);
+        cpu.registers.set_hl(0x8A23);
+        cpu.registers.set_bc(0x0605);
+        cpu.registers.f = 0x80.into();
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.registers.get_hl(), 0x9028);
+        assert_eq!(flags(&cpu), 0xA0);
+        // add sp,e8 carries out of bit 3 and 7 of the low byte
+        let mut cpu = running(&[0xE8, 0xFF]);
+        cpu.sp = 0x000F;
+        assert_eq!(cpu.step(), 16);
+        assert_eq!(cpu.sp, 0x000E);
+        assert_eq!(flags(&cpu), 0x30);
+    }
+
+    #[test]
+    fn conditional_branches_take_longer_when_taken() {
+        // jr nz,+2 with Z set, then with Z clear
+        let mut cpu = running(&[0x20, 0x02]);
+        cpu.registers.f = 0x80.into();
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.pc, 0x0102);
+        let mut cpu = running(&[0x20, 0x02]);
+        assert_eq!(cpu.step(), 12);
+        assert_eq!(cpu.pc, 0x0104);
+        // ret nz, both ways
+        let mut cpu = running(&[0xC0]);
+        cpu.registers.f = 0x80.into();
+        assert_eq!(cpu.step(), 8);
+        let mut cpu = running(&[0xC0]);
+        assert_eq!(cpu.step(), 20);
+    }
+
+    #[test]
+    fn memory_and_stack_opcodes_take_their_cycles() {
+        // call, push bc, pop de, ret, ld (hl),d8, ld a,(a16), ld (a16),sp, jp
+        let mut cpu = running(&[0xCD, 0x00, 0x02, 0x36, 0x12, 0xFA, 0x00, 0xC0, 0x08, 0x10, 0xC0, 0xC3, 0x00, 0x01]);
+        cpu.bus.memory[0x0200..0x0203].copy_from_slice(&[0xC5, 0xD1, 0xC9]);
+        cpu.registers.set_bc(0x1234);
+        cpu.registers.set_hl(0xC000);
+        let expected = [(24, 0x0200), (16, 0x0201), (12, 0x0202), (16, 0x0103), (12, 0x0105), (16, 0x0108)];
+        for (cycles, pc) in expected {
+            assert_eq!(cpu.step(), cycles, "cycles before 0x{:04x}", pc);
+            assert_eq!(cpu.pc, pc);
+        }
+        assert_eq!(cpu.registers.get_de(), 0x1234);
+        assert_eq!(cpu.registers.a, 0x12);
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(&cpu.bus.memory[0xC010..0xC012], &[0xFE, 0xFF]);
+        assert_eq!(cpu.step(), 16);
+        assert_eq!(cpu.pc, 0x0100);
+    }
+
+    #[test]
+    fn an_interrupt_dispatch_takes_20_cycles() {
+        // ei, nop
+        let mut cpu = running(&[0xFB, 0x00]);
+        cpu.bus.memory[INTERRUPT_ENABLE as usize] = 0x04;
+        cpu.bus.memory[INTERRUPT_FLAG as usize] = 0x04;
+        cpu.step();
+        // the enable waits for the instruction after EI
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0102);
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(cpu.bus.memory[INTERRUPT_FLAG as usize], 0x00);
+        assert_eq!(&cpu.bus.memory[0xFFFC..0xFFFE], &[0x02, 0x01]);
+    }
+
+    // AF, BC, DE and HL as the model's boot ROM leaves them for a cartridge with this header
+    fn boot_register_pairs(model: Model, header: &[(usize, u8)]) -> [u16; 4] {
+        let mut rom = vec![0; 0x8000];
+        for &(address, value) in header {
+            rom[address] = value;
+        }
+        let cartridge = Cartridge::with_mbc(rom, MBC::None, 0);
+        let cgb_mode = model.is_cgb() && cartridge.supports_cgb();
+        let mut bus = MemoryBus::new(cartridge, PPU::with_model(model, Renderer::Scanline));
+        bus.set_cgb_mode(cgb_mode);
+        let registers = boot_registers(&bus);
+        [registers.get_af(), registers.get_bc(), registers.get_de(), registers.get_hl()]
+    }
+
+    #[test]
+    fn every_model_boots_with_its_own_registers() {
+        // a CGB game with a non-zero header checksum
+        let header = [(0x143, 0x80), (0x14D, 0x66)];
+        let expected = [
+            (Model::DMG0, [0x0100, 0xFF13, 0x00C1, 0x8403]),
+            (Model::DMG, [0x01B0, 0x0013, 0x00D8, 0x014D]),
+            (Model::MGB, [0xFFB0, 0x0013, 0x00D8, 0x014D]),
+            (Model::SGB, [0x0100, 0x0014, 0x0000, 0xC060]),
+            (Model::SGB2, [0xFF00, 0x0014, 0x0000, 0xC060]),
+            (Model::CGB, [0x1180, 0x0000, 0xFF56, 0x000D]),
+            // INC B clears Z and N, and so the carry flag too
+            (Model::AGB, [0x1100, 0x0100, 0xFF56, 0x000D]),
+        ];
+        for (model, registers) in expected {
+            assert_eq!(boot_register_pairs(model, &header), registers, "{}", model.name());
+        }
+    }
+
+    #[test]
+    fn a_zero_header_checksum_leaves_half_carry_and_carry_clear() {
+        assert_eq!(boot_register_pairs(Model::DMG, &[(0x14D, 0x00)])[0], 0x0180);
+        assert_eq!(boot_register_pairs(Model::MGB, &[(0x14D, 0x00)])[0], 0xFF80);
+    }
+
+    #[test]
+    fn a_dmg_game_on_a_cgb_keeps_the_title_checksum_in_b() {
+        // "AB" sums to 0x83, and the old licensee code 0x01 is Nintendo
+        let licensed = [(0x134, b'A'), (0x135, b'B'), (0x14B, 0x01)];
+        assert_eq!(boot_register_pairs(Model::CGB, &licensed), [0x1180, 0x8300, 0x0008, 0x007C]);
+        assert_eq!(boot_register_pairs(Model::AGB, &licensed), [0x1100, 0x8400, 0x0008, 0x007C]);
+        // other publishers never got a palette, B is 0
+        let unlicensed = [(0x134, b'A'), (0x135, b'B'), (0x14B, 0x08)];
+        assert_eq!(boot_register_pairs(Model::CGB, &unlicensed), [0x1180, 0x0000, 0x0008, 0x007C]);
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use crate::instructions::{
+    ArithmeticTarget, ByteRegister, Immediate, IncDecTarget, Indirect, Instruction, LoadByteSource, LoadType,
+    StackTarget,
+};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+// the cartridge header after the entry point, data rather than code
+const HEADER_START: usize = 0x0104;
+const HEADER_END: usize = 0x0150;
+// writes to 0x2000-0x3FFF select the ROM bank on every common MBC
+const BANK_SELECT: std::ops::RangeInclusive<u16> = 0x2000..=0x3FFF;
+const DATA_BYTES_PER_LINE: usize = 16;
+
+// one instruction and the bytes it was decoded from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedInstruction {
+    pub address: u16,
+    // the opcode with its prefix and immediate
+    pub bytes: Vec<u8>,
+    // none for the opcodes that don't exist
+    pub instruction: Option<Instruction>,
+}
+
+// decode the instruction at an address, with the bytes read through the given function
+pub fn decode<F: Fn(u16) -> u8>(read: F, address: u16) -> DecodedInstruction {
+    let opcode = read(address);
+    let instruction = if opcode == 0xCB {
+        Instruction::from_byte(read(address.wrapping_add(1)), true)
+    } else {
+        Instruction::from_byte(opcode, false)
+    };
+    let length = instruction.map_or(1, |instruction| instruction.length());
+    DecodedInstruction {
+        address,
+        bytes: (0..length).map(|offset| read(address.wrapping_add(offset))).collect(),
+        instruction,
+    }
+}
+
+impl DecodedInstruction {
+    // the byte or little-endian word after the opcode
+    fn immediate_value(&self) -> u16 {
+        match self.bytes.len() {
+            2 => self.bytes[1] as u16,
+            3 => u16::from_le_bytes([self.bytes[1], self.bytes[2]]),
+            _ => 0,
+        }
+    }
+
+    // where a jump, call or RST goes
+    pub fn target(&self) -> Option<u16> {
+        match self.instruction? {
+            Instruction::JP(_) | Instruction::CALL(_) => Some(self.immediate_value()),
+            Instruction::JR(_) => Some(self.address.wrapping_add(2).wrapping_add(self.bytes[1] as i8 as u16)),
+            Instruction::RST(vector) => Some(vector as u16),
+            _ => None,
+        }
+    }
+
+    // RGBDS syntax, with jump and call targets named by the given function where it has a label for them
+    pub fn format_with(&self, label: &dyn Fn(u16) -> Option<String>) -> String {
+        let instruction = match self.instruction {
+            Some(instruction) => instruction,
+            None => return format!("db ${:02X}", self.bytes[0]),
+        };
+        let value = self.immediate_value();
+        instruction.format_with(&|immediate| match immediate {
+            Immediate::Byte => format!("${:02X}", value),
+            Immediate::Word => format!("${:04X}", value),
+            Immediate::Offset => (value as u8 as i8).to_string(),
+            Immediate::Address | Immediate::RelativeAddress => {
+                let target = self.target().unwrap_or(value);
+                label(target).unwrap_or_else(|| format!("${:04X}", target))
+            }
+            Immediate::HighAddress => format!("${:04X}", 0xFF00 | value),
+        })
+    }
+}
+
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format_with(&|_| None))
+    }
+}
+
+// a bank is walked from start to end, treating everything as code except the header
+enum Line {
+    Code(DecodedInstruction),
+    Data(u16, Vec<u8>),
+}
+
+fn walk_bank(rom: &[u8], bank: usize) -> Vec<Line> {
+    let start = bank * ROM_BANK_SIZE;
+    let bytes = &rom[start..rom.len().min(start + ROM_BANK_SIZE)];
+    let base = if bank == 0 { 0 } else { ROM_BANK_SIZE };
+    let read = |address: u16| bytes.get(address as usize - base).copied().unwrap_or(0);
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let address = (base + offset) as u16;
+        if bank == 0 && (HEADER_START..HEADER_END).contains(&offset) {
+            let end = HEADER_END.min(bytes.len()).min(offset + DATA_BYTES_PER_LINE);
+            lines.push(Line::Data(address, bytes[offset..end].to_vec()));
+            offset = end;
+            continue;
+        }
+        // an instruction running into the header or past the end of the bank is left as data
+        let limit = if bank == 0 && offset < HEADER_START { HEADER_START.min(bytes.len()) } else { bytes.len() };
+        let decoded = decode(read, address);
+        if offset + decoded.bytes.len() > limit {
+            lines.push(Line::Data(address, vec![bytes[offset]]));
+            offset += 1;
+        } else {
+            offset += decoded.bytes.len();
+            lines.push(Line::Code(decoded));
+        }
+    }
+    lines
+}
+
+// follows the ROM bank code in bank 0 selects, by loading A with a constant and writing it to the MBC, to
+// tell which bank its jumps to 0x4000-0x7FFF land in
+struct BankTracker {
+    bank: usize,
+    loaded_a: Option<usize>,
+    selected_bank: usize,
+}
+
+impl BankTracker {
+    fn new(bank: usize) -> BankTracker {
+        BankTracker {
+            bank,
+            loaded_a: None,
+            selected_bank: 1,
+        }
+    }
+
+    fn observe(&mut self, decoded: &DecodedInstruction) {
+        match decoded.instruction {
+            Some(Instruction::LD(LoadType::Byte(ByteRegister::A, LoadByteSource::D8))) => {
+                self.loaded_a = Some(decoded.bytes[1] as usize);
+            }
+            Some(Instruction::XOR(ArithmeticTarget::Register(ByteRegister::A))) => self.loaded_a = Some(0),
+            Some(Instruction::LD(LoadType::IndirectFromA(Indirect::WordIndirect))) => {
+                if let (true, Some(value)) = (BANK_SELECT.contains(&decoded.immediate_value()), self.loaded_a) {
+                    // bank 0 can't be mapped there, the MBC turns it into bank 1
+                    self.selected_bank = value.max(1);
+                }
+            }
+            // anything else changing A leaves its value unknown, and so does a call, which may return with A
+            // set to anything
+            Some(instruction) if writes_a(instruction) => self.loaded_a = None,
+            _ => {}
+        }
+    }
+
+    fn target_bank(&self, target: u16) -> Option<usize> {
+        match target {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF if self.bank == 0 => Some(self.selected_bank),
+            0x4000..=0x7FFF => Some(self.bank),
+            // RAM, nothing to label
+            _ => None,
+        }
+    }
+}
+
+// everything that leaves a new value in A, CP only sets the flags
+fn writes_a(instruction: Instruction) -> bool {
+    match instruction {
+        Instruction::ADD(_)
+        | Instruction::ADC(_)
+        | Instruction::SUB(_)
+        | Instruction::SBC(_)
+        | Instruction::AND(_)
+        | Instruction::XOR(_)
+        | Instruction::OR(_)
+        | Instruction::DAA
+        | Instruction::CPL
+        | Instruction::RLCA
+        | Instruction::RRCA
+        | Instruction::RLA
+        | Instruction::RRA
+        | Instruction::CALL(_)
+        | Instruction::RST(_)
+        | Instruction::POP(StackTarget::AF) => true,
+        Instruction::INC(IncDecTarget::Byte(register)) | Instruction::DEC(IncDecTarget::Byte(register)) => {
+            matches!(register, ByteRegister::A)
+        }
+        Instruction::RLC(register)
+        | Instruction::RRC(register)
+        | Instruction::RL(register)
+        | Instruction::RR(register)
+        | Instruction::SLA(register)
+        | Instruction::SRA(register)
+        | Instruction::SWAP(register)
+        | Instruction::SRL(register)
+        | Instruction::RES(_, register)
+        | Instruction::SET(_, register) => matches!(register, ByteRegister::A),
+        Instruction::LD(load) => matches!(
+            load,
+            LoadType::Byte(ByteRegister::A, _) | LoadType::AFromIndirect(_) | LoadType::AFromByteAddress
+        ),
+        _ => false,
+    }
+}
+
+// the ROM in RGBDS syntax, one section per bank and a label at every jump, call and RST target
+pub fn disassemble_rom(rom: &[u8]) -> String {
+    let banks: Vec<Vec<Line>> = (0..rom.len().div_ceil(ROM_BANK_SIZE)).map(|bank| walk_bank(rom, bank)).collect();
+
+    // targets mid-instruction get no label, the listing couldn't place it
+    let mut starts = HashSet::new();
+    let mut targets = BTreeMap::new();
+    for (bank, lines) in banks.iter().enumerate() {
+        let mut tracker = BankTracker::new(bank);
+        for line in lines {
+            let Line::Code(decoded) = line else { continue };
+            starts.insert((bank, decoded.address));
+            tracker.observe(decoded);
+            let Some(target) = decoded.target() else { continue };
+            let Some(target_bank) = tracker.target_bank(target) else { continue };
+            let call = matches!(decoded.instruction, Some(Instruction::CALL(_) | Instruction::RST(_)));
+            let kind = targets.entry((target_bank, target)).or_insert(call);
+            *kind |= call;
+        }
+    }
+    let labels: BTreeMap<(usize, u16), String> = targets
+        .into_iter()
+        .filter(|(key, _)| starts.contains(key))
+        .map(|((bank, address), call)| {
+            let kind = if call { "Call" } else { "Jump" };
+            ((bank, address), format!("{}_{:03X}_{:04X}", kind, bank, address))
+        })
+        .collect();
+
+    let mut listing = String::new();
+    for (bank, lines) in banks.iter().enumerate() {
+        if bank == 0 {
+            listing.push_str("SECTION \"ROM Bank $000\", ROM0[$0000]\n");
+        } else {
+            listing.push_str(&format!("\nSECTION \"ROM Bank ${:03X}\", ROMX[$4000], BANK[${:X}]\n", bank, bank));
+        }
+        let mut tracker = BankTracker::new(bank);
+        for line in lines {
+            // code is commented with its address and bytes, data only with the address
+            let (text, comment) = match line {
+                Line::Code(decoded) => {
+                    if let Some(label) = labels.get(&(bank, decoded.address)) {
+                        listing.push_str(&format!("\n{}:\n", label));
+                    }
+                    tracker.observe(decoded);
+                    let label = |target: u16| labels.get(&(tracker.target_bank(target)?, target)).cloned();
+                    let hex: Vec<String> = decoded.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+                    (decoded.format_with(&label), format!("${:04X}: {}", decoded.address, hex.join(" ")))
+                }
+                Line::Data(address, bytes) => {
+                    let values: Vec<String> = bytes.iter().map(|byte| format!("${:02X}", byte)).collect();
+                    (format!("db {}", values.join(", ")), format!("${:04X}", address))
+                }
+            };
+            listing.push_str(&format!("    {:<32} ; {}\n", text, comment));
+        }
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every unprefixed opcode decoded at 0x0100 with 0x34 0x12 after it, 0xCB is the prefix for SWAP H
+    const UNPREFIXED: [(usize, &str); 256] = [
+        (1, "nop"), (3, "ld bc, $1234"), (1, "ld [bc], a"), (1, "inc bc"),
+        (1, "inc b"), (1, "dec b"), (2, "ld b, $34"), (1, "rlca"),
+        (3, "ld [$1234], sp"), (1, "add hl, bc"), (1, "ld a, [bc]"), (1, "dec bc"),
+        (1, "inc c"), (1, "dec c"), (2, "ld c, $34"), (1, "rrca"),
+        (2, "stop"), (3, "ld de, $1234"), (1, "ld [de], a"), (1, "inc de"),
+        (1, "inc d"), (1, "dec d"), (2, "ld d, $34"), (1, "rla"),
+        (2, "jr $0136"), (1, "add hl, de"), (1, "ld a, [de]"), (1, "dec de"),
+        (1, "inc e"), (1, "dec e"), (2, "ld e, $34"), (1, "rra"),
+        (2, "jr nz, $0136"), (3, "ld hl, $1234"), (1, "ld [hl+], a"), (1, "inc hl"),
+        (1, "inc h"), (1, "dec h"), (2, "ld h, $34"), (1, "daa"),
+        (2, "jr z, $0136"), (1, "add hl, hl"), (1, "ld a, [hl+]"), (1, "dec hl"),
+        (1, "inc l"), (1, "dec l"), (2, "ld l, $34"), (1, "cpl"),
+        (2, "jr nc, $0136"), (3, "ld sp, $1234"), (1, "ld [hl-], a"), (1, "inc sp"),
+        (1, "inc [hl]"), (1, "dec [hl]"), (2, "ld [hl], $34"), (1, "scf"),
+        (2, "jr c, $0136"), (1, "add hl, sp"), (1, "ld a, [hl-]"), (1, "dec sp"),
+        (1, "inc a"), (1, "dec a"), (2, "ld a, $34"), (1, "ccf"),
+        (1, "ld b, b"), (1, "ld b, c"), (1, "ld b, d"), (1, "ld b, e"),
+        (1, "ld b, h"), (1, "ld b, l"), (1, "ld b, [hl]"), (1, "ld b, a"),
+        (1, "ld c, b"), (1, "ld c, c"), (1, "ld c, d"), (1, "ld c, e"),
+        (1, "ld c, h"), (1, "ld c, l"), (1, "ld c, [hl]"), (1, "ld c, a"),
+        (1, "ld d, b"), (1, "ld d, c"), (1, "ld d, d"), (1, "ld d, e"),
+        (1, "ld d, h"), (1, "ld d, l"), (1, "ld d, [hl]"), (1, "ld d, a"),
+        (1, "ld e, b"), (1, "ld e, c"), (1, "ld e, d"), (1, "ld e, e"),
+        (1, "ld e, h"), (1, "ld e, l"), (1, "ld e, [hl]"), (1, "ld e, a"),
+        (1, "ld h, b"), (1, "ld h, c"), (1, "ld h, d"), (1, "ld h, e"),
+        (1, "ld h, h"), (1, "ld h, l"), (1, "ld h, [hl]"), (1, "ld h, a"),
+        (1, "ld l, b"), (1, "ld l, c"), (1, "ld l, d"), (1, "ld l, e"),
+        (1, "ld l, h"), (1, "ld l, l"), (1, "ld l, [hl]"), (1, "ld l, a"),
+        (1, "ld [hl], b"), (1, "ld [hl], c"), (1, "ld [hl], d"), (1, "ld [hl], e"),
+        (1, "ld [hl], h"), (1, "ld [hl], l"), (1, "halt"), (1, "ld [hl], a"),
+        (1, "ld a, b"), (1, "ld a, c"), (1, "ld a, d"), (1, "ld a, e"),
+        (1, "ld a, h"), (1, "ld a, l"), (1, "ld a, [hl]"), (1, "ld a, a"),
+        (1, "add a, b"), (1, "add a, c"), (1, "add a, d"), (1, "add a, e"),
+        (1, "add a, h"), (1, "add a, l"), (1, "add a, [hl]"), (1, "add a, a"),
+        (1, "adc a, b"), (1, "adc a, c"), (1, "adc a, d"), (1, "adc a, e"),
+        (1, "adc a, h"), (1, "adc a, l"), (1, "adc a, [hl]"), (1, "adc a, a"),
+        (1, "sub a, b"), (1, "sub a, c"), (1, "sub a, d"), (1, "sub a, e"),
+        (1, "sub a, h"), (1, "sub a, l"), (1, "sub a, [hl]"), (1, "sub a, a"),
+        (1, "sbc a, b"), (1, "sbc a, c"), (1, "sbc a, d"), (1, "sbc a, e"),
+        (1, "sbc a, h"), (1, "sbc a, l"), (1, "sbc a, [hl]"), (1, "sbc a, a"),
+        (1, "and a, b"), (1, "and a, c"), (1, "and a, d"), (1, "and a, e"),
+        (1, "and a, h"), (1, "and a, l"), (1, "and a, [hl]"), (1, "and a, a"),
+        (1, "xor a, b"), (1, "xor a, c"), (1, "xor a, d"), (1, "xor a, e"),
+        (1, "xor a, h"), (1, "xor a, l"), (1, "xor a, [hl]"), (1, "xor a, a"),
+        (1, "or a, b"), (1, "or a, c"), (1, "or a, d"), (1, "or a, e"),
+        (1, "or a, h"), (1, "or a, l"), (1, "or a, [hl]"), (1, "or a, a"),
+        (1, "cp a, b"), (1, "cp a, c"), (1, "cp a, d"), (1, "cp a, e"),
+        (1, "cp a, h"), (1, "cp a, l"), (1, "cp a, [hl]"), (1, "cp a, a"),
+        (1, "ret nz"), (1, "pop bc"), (3, "jp nz, $1234"), (3, "jp $1234"),
+        (3, "call nz, $1234"), (1, "push bc"), (2, "add a, $34"), (1, "rst $00"),
+        (1, "ret z"), (1, "ret"), (3, "jp z, $1234"), (2, "swap h"),
+        (3, "call z, $1234"), (3, "call $1234"), (2, "adc a, $34"), (1, "rst $08"),
+        (1, "ret nc"), (1, "pop de"), (3, "jp nc, $1234"), (1, "db $D3"),
+        (3, "call nc, $1234"), (1, "push de"), (2, "sub a, $34"), (1, "rst $10"),
+        (1, "ret c"), (1, "reti"), (3, "jp c, $1234"), (1, "db $DB"),
+        (3, "call c, $1234"), (1, "db $DD"), (2, "sbc a, $34"), (1, "rst $18"),
+        (2, "ldh [$FF34], a"), (1, "pop hl"), (1, "ldh [c], a"), (1, "db $E3"),
+        (1, "db $E4"), (1, "push hl"), (2, "and a, $34"), (1, "rst $20"),
+        (2, "add sp, 52"), (1, "jp hl"), (3, "ld [$1234], a"), (1, "db $EB"),
+        (1, "db $EC"), (1, "db $ED"), (2, "xor a, $34"), (1, "rst $28"),
+        (2, "ldh a, [$FF34]"), (1, "pop af"), (1, "ldh a, [c]"), (1, "di"),
+        (1, "db $F4"), (1, "push af"), (2, "or a, $34"), (1, "rst $30"),
+        (2, "ld hl, sp+52"), (1, "ld sp, hl"), (3, "ld a, [$1234]"), (1, "ei"),
+        (1, "db $FC"), (1, "db $FD"), (2, "cp a, $34"), (1, "rst $38"),
+    ];
+
+    fn decode_bytes(bytes: &[u8], address: u16) -> DecodedInstruction {
+        decode(|at| bytes.get(at.wrapping_sub(address) as usize).copied().unwrap_or(0), address)
+    }
+
+    #[test]
+    fn decodes_every_unprefixed_opcode() {
+        for (opcode, &(length, text)) in UNPREFIXED.iter().enumerate() {
+            let decoded = decode_bytes(&[opcode as u8, 0x34, 0x12], 0x0100);
+            assert_eq!(decoded.bytes.len(), length, "opcode ${:02X}", opcode);
+            if let Some(instruction) = decoded.instruction {
+                assert_eq!(instruction.length() as usize, length, "opcode ${:02X}", opcode);
+            }
+            assert_eq!(decoded.to_string(), text, "opcode ${:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn decodes_every_prefixed_opcode() {
+        let operations = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+        let registers = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+        for opcode in 0..=0xFFu8 {
+            let register = registers[opcode as usize & 0x07];
+            let bit = (opcode >> 3) & 0x07;
+            let text = match opcode >> 6 {
+                0 => format!("{} {}", operations[bit as usize], register),
+                1 => format!("bit {}, {}", bit, register),
+                2 => format!("res {}, {}", bit, register),
+                _ => format!("set {}, {}", bit, register),
+            };
+            let decoded = decode_bytes(&[0xCB, opcode], 0x0100);
+            let instruction = decoded.instruction.unwrap();
+            assert!(instruction.is_prefixed());
+            assert_eq!(instruction.length(), 2, "opcode $CB ${:02X}", opcode);
+            assert_eq!(decoded.bytes, [0xCB, opcode]);
+            assert_eq!(decoded.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn computes_jump_and_call_targets() {
+        assert_eq!(decode_bytes(&[0x18, 0xFE], 0x0150).target(), Some(0x0150));
+        assert_eq!(decode_bytes(&[0x20, 0x05], 0x0200).target(), Some(0x0207));
+        assert_eq!(decode_bytes(&[0x38, 0x80], 0x0000).target(), Some(0xFF82));
+        assert_eq!(decode_bytes(&[0xC3, 0x50, 0x01], 0x0100).target(), Some(0x0150));
+        assert_eq!(decode_bytes(&[0xDA, 0x00, 0x40], 0x0100).target(), Some(0x4000));
+        assert_eq!(decode_bytes(&[0xCD, 0x34, 0x12], 0x0100).target(), Some(0x1234));
+        assert_eq!(decode_bytes(&[0xFF], 0x0100).target(), Some(0x0038));
+        assert_eq!(decode_bytes(&[0xE9], 0x0100).target(), None);
+        assert_eq!(decode_bytes(&[0x21, 0x34, 0x12], 0x0100).target(), None);
+
+        let label = |target: u16| (target == 0x0150).then(|| "Start".to_string());
+        assert_eq!(decode_bytes(&[0x18, 0xFE], 0x0150).format_with(&label), "jr Start");
+        assert_eq!(decode_bytes(&[0x18, 0x00], 0x0150).format_with(&label), "jr $0152");
+    }
+
+    #[test]
+    fn labels_targets_in_the_selected_bank() {
+        let mut rom = vec![0x00; 3 * ROM_BANK_SIZE];
+        // jp $0150
+        rom[0x0101..0x0104].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        // ld a, $02; ld [$2000], a; call $4000
+        rom[0x0150..0x0158].copy_from_slice(&[0x3E, 0x02, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40]);
+        // ret in bank 2
+        rom[2 * ROM_BANK_SIZE] = 0xC9;
+
+        let listing = disassemble_rom(&rom);
+        assert!(listing.contains("\nJump_000_0150:\n"));
+        assert!(listing.contains("call Call_002_4000"));
+        assert!(!listing.contains("Call_001_4000"));
+        let bank = listing.find("SECTION \"ROM Bank $002\"").unwrap();
+        let label = listing.find("\nCall_002_4000:\n").unwrap();
+        assert!(label > bank);
+        assert!(listing[label..].lines().nth(2).unwrap().trim_start().starts_with("ret "));
+    }
+
+    // three banks with the code at 0x0150 and a RET at the start of bank 2
+    fn disassemble_code(code: &[u8]) -> String {
+        let mut rom = vec![0x00; 3 * ROM_BANK_SIZE];
+        rom[0x0101..0x0104].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x0150..0x0150 + code.len()].copy_from_slice(code);
+        rom[0x0200] = 0xC9;
+        rom[2 * ROM_BANK_SIZE] = 0xC9;
+        disassemble_rom(&rom)
+    }
+
+    #[test]
+    fn forgets_a_once_something_else_writes_it() {
+        let changes: [&[u8]; 7] = [
+            // inc a
+            &[0x3C],
+            // ld a, [hl]
+            &[0x7E],
+            // ld a, b
+            &[0x78],
+            // pop af
+            &[0xF1],
+            // add a, $01
+            &[0xC6, 0x01],
+            // ldh a, [$FF80]
+            &[0xF0, 0x80],
+            // call $0200, which could return anything in A
+            &[0xCD, 0x00, 0x02],
+        ];
+        for change in changes {
+            // ld a, $02; <change>; ld [$2000], a; call $4000
+            let mut code = vec![0x3E, 0x02];
+            code.extend_from_slice(change);
+            code.extend_from_slice(&[0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40]);
+            let listing = disassemble_code(&code);
+            assert!(!listing.contains("Call_002_"), "{:02X?}", change);
+            assert!(listing.contains("call Call_001_4000"), "{:02X?}", change);
+        }
+    }
+
+    #[test]
+    fn keeps_a_across_instructions_that_leave_it_alone() {
+        // ld a, $02; cp a, $01; ld b, a; ld [hl], a; ld [$2000], a; call $4000
+        let listing = disassemble_code(&[0x3E, 0x02, 0xFE, 0x01, 0x47, 0x77, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40]);
+        assert!(listing.contains("call Call_002_4000"));
+    }
+
+    #[test]
+    fn xor_a_selects_bank_1() {
+        // ld a, $02; ld [$2000], a; xor a, a; ld [$2000], a; call $4000
+        let listing = disassemble_code(&[0x3E, 0x02, 0xEA, 0x00, 0x20, 0xAF, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40]);
+        assert!(listing.contains("call Call_001_4000"));
+        assert!(!listing.contains("Call_002_"));
+    }
+}
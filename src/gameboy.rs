@@ -0,0 +1,248 @@
+use std::io;
+use std::path::Path;
+
+use crate::cartridge::Cartridge;
+use crate::compatpalette::CompatibilityPalette;
+use crate::cpu::CPU;
+use crate::memorybus::MemoryBus;
+use crate::model::Model;
+use crate::ppu::{Renderer, PPU};
+use crate::printer::Printer;
+use crate::serial::SerialCapture;
+use crate::vgm::VgmRecorder;
+use crate::wav::WavRecorder;
+
+// T-cycles in one frame, a frame is also cut off here while the LCD is switched off
+pub const CYCLES_PER_FRAME: u32 = 70224;
+
+pub const DEFAULT_RECORDING_RATE: u32 = 44100;
+
+pub struct GameBoy {
+    pub cpu: CPU,
+    // native rate APU output of the last frame
+    audio: Vec<(f32, f32)>,
+    recorder: Option<WavRecorder>,
+    vgm_recorder: Option<VgmRecorder>,
+}
+
+impl GameBoy {
+    pub fn new(cartridge: Cartridge) -> GameBoy {
+        let model = GameBoy::default_model(&cartridge);
+        GameBoy::with_model(cartridge, model, Renderer::Scanline)
+    }
+
+    // CGB games run on a CGB and everything else on a DMG
+    pub fn default_model(cartridge: &Cartridge) -> Model {
+        if cartridge.supports_cgb() { Model::CGB } else { Model::DMG }
+    }
+
+    pub fn with_model(cartridge: Cartridge, model: Model, renderer: Renderer) -> GameBoy {
+        GameBoy::with_ppu(cartridge, PPU::with_model(model, renderer))
+    }
+
+    // the hardware model is the one the PPU was made for. On CGB hardware games flagged for CGB run in
+    // CGB mode and the others are colorized by the palette the boot ROM would pick.
+    pub fn with_ppu(cartridge: Cartridge, ppu: PPU) -> GameBoy {
+        let model = ppu.model();
+        let cgb_mode = model.is_cgb() && cartridge.supports_cgb();
+        let palette = if model.is_cgb() && !cgb_mode {
+            CompatibilityPalette::for_cartridge(&cartridge)
+        } else {
+            CompatibilityPalette::GRAYSCALE
+        };
+        let mut bus = MemoryBus::new(cartridge, ppu);
+        bus.set_cgb_mode(cgb_mode);
+        bus.ppu.set_dmg_palette(palette);
+        bus.skip_boot();
+        GameBoy::with_bus(bus)
+    }
+
+    // start from the model's boot ROM instead of the state it leaves behind. The CGB boot ROM starts out in
+    // CGB mode and switches to DMG mode for older games itself.
+    pub fn with_boot_rom(
+        cartridge: Cartridge,
+        model: Model,
+        renderer: Renderer,
+        boot_rom: Vec<u8>,
+    ) -> Result<GameBoy, String> {
+        let mut bus = MemoryBus::new(cartridge, PPU::with_model(model, renderer));
+        bus.set_cgb_mode(model.is_cgb());
+        bus.load_boot_rom(boot_rom)?;
+        Ok(GameBoy::with_bus(bus))
+    }
+
+    fn with_bus(bus: MemoryBus) -> GameBoy {
+        GameBoy {
+            cpu: CPU::new(bus),
+            audio: Vec::new(),
+            recorder: None,
+            vgm_recorder: None,
+        }
+    }
+
+    // run until the PPU finishes a frame
+    pub fn run_frame(&mut self) -> io::Result<()> {
+        let mut cycles = 0;
+        while cycles < CYCLES_PER_FRAME {
+            let step = self.step();
+            // frames are counted in PPU dots, which don't speed up with the CPU
+            cycles += if self.cpu.bus.is_double_speed() { step / 2 } else { step };
+            if self.cpu.bus.ppu.take_frame_ready() {
+                break;
+            }
+        }
+        self.finish_frame()
+    }
+
+    // a single instruction or interrupt dispatch, returns the T-cycles taken. finish_frame has to be
+    // called once in a while to collect the audio.
+    pub fn step(&mut self) -> u32 {
+        self.cpu.step()
+    }
+
+    // collect the audio produced since the last frame and pass it on to the recorders
+    pub fn finish_frame(&mut self) -> io::Result<()> {
+        self.audio = self.cpu.bus.apu.take_samples();
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.push(&self.audio)?;
+        }
+        if let Some(recorder) = self.vgm_recorder.as_mut() {
+            let apu = &mut self.cpu.bus.apu;
+            recorder.push(&apu.take_register_writes(), apu.register_log_cycle())?;
+        }
+        Ok(())
+    }
+
+    pub fn frame_buffer(&self) -> &[u8] {
+        self.cpu.bus.ppu.frame_buffer()
+    }
+
+    // the frame in RGB555, in color on CGB and in grays otherwise
+    pub fn color_frame_buffer(&self) -> &[u16] {
+        self.cpu.bus.ppu.color_frame_buffer()
+    }
+
+    // on SGB the colorized frame inside its border, 256x224 RGB555
+    pub fn sgb_frame_buffer(&self) -> Option<&[u16]> {
+        self.cpu.bus.sgb.as_ref().map(|sgb| sgb.frame_buffer())
+    }
+
+    // show a DMG game in color, like a CGB does with the palette its boot ROM picked
+    pub fn set_dmg_palette(&mut self, palette: CompatibilityPalette) {
+        self.cpu.bus.ppu.set_dmg_palette(palette);
+    }
+
+    pub fn audio_samples(&self) -> &[(f32, f32)] {
+        &self.audio
+    }
+
+    // plug a capture into the link port, the returned handle reads back everything sent from now on
+    pub fn capture_serial(&mut self) -> SerialCapture {
+        let capture = SerialCapture::new();
+        self.cpu.bus.serial.connect(Box::new(capture.clone()));
+        capture
+    }
+
+    // plug a Game Boy Printer into the link port, the returned handle collects the printed pages
+    pub fn connect_printer(&mut self) -> Printer {
+        let printer = Printer::new();
+        self.cpu.bus.serial.connect(Box::new(printer.clone()));
+        printer
+    }
+
+    // start writing everything the APU outputs from now on to a WAV file, replacing any running recording
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P, sample_rate: u32) -> io::Result<()> {
+        self.stop_recording()?;
+        self.recorder = Some(WavRecorder::create(path, sample_rate)?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // log APU register writes from now on to a VGM file, replacing any running VGM recording
+    pub fn start_vgm_recording<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.stop_vgm_recording()?;
+        self.vgm_recorder = Some(VgmRecorder::create(path)?);
+        self.cpu.bus.apu.start_register_log();
+        Ok(())
+    }
+
+    pub fn stop_vgm_recording(&mut self) -> io::Result<()> {
+        match self.vgm_recorder.take() {
+            Some(recorder) => {
+                self.cpu.bus.apu.stop_register_log();
+                recorder.finish()
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_vgm_recording(&self) -> bool {
+        self.vgm_recorder.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::MBC;
+
+    // a Game Boy is too big for a test thread's stack in debug builds, the tests run on a thread of their own
+    fn on_large_stack(test: fn()) {
+        std::thread::Builder::new().stack_size(32 << 20).spawn(test).unwrap().join().unwrap();
+    }
+
+    fn cartridge(cgb_flag: u8) -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = cgb_flag;
+        Cartridge::with_mbc(rom, MBC::None, 0)
+    }
+
+    #[test]
+    fn cgb_games_default_to_a_cgb() {
+        assert_eq!(GameBoy::default_model(&cartridge(0x00)), Model::DMG);
+        assert_eq!(GameBoy::default_model(&cartridge(0x80)), Model::CGB);
+        assert_eq!(GameBoy::default_model(&cartridge(0xC0)), Model::CGB);
+    }
+
+    #[test]
+    fn the_model_reaches_the_bus_and_the_ppu() {
+        on_large_stack(|| {
+            for model in Model::ALL {
+                let gameboy = GameBoy::with_model(cartridge(0x80), model, Renderer::Scanline);
+                assert_eq!(gameboy.cpu.bus.model(), model);
+                assert_eq!(gameboy.cpu.bus.ppu.model(), model);
+                // CGB games only get CGB mode on CGB hardware, and SGB hardware brings the SNES side along
+                assert_eq!(gameboy.cpu.bus.is_cgb_mode(), model.is_cgb());
+                assert_eq!(gameboy.cpu.bus.sgb.is_some(), model.is_sgb());
+            }
+        });
+    }
+
+    #[test]
+    fn dmg_games_on_a_cgb_get_a_compatibility_palette() {
+        // a frame of the blank background with every BGP shade set to 1
+        fn background(model: Model) -> u16 {
+            let mut gameboy = GameBoy::with_model(cartridge(0x00), model, Renderer::Scanline);
+            gameboy.cpu.bus.write(0xFF47, 0x55);
+            gameboy.run_frame().unwrap();
+            gameboy.run_frame().unwrap();
+            gameboy.color_frame_buffer()[0]
+        }
+        on_large_stack(|| {
+            let palette = CompatibilityPalette::for_cartridge(&cartridge(0x00));
+            assert_ne!(palette.bg[1], CompatibilityPalette::GRAYSCALE.bg[1]);
+            assert_eq!(background(Model::CGB), palette.bg[1]);
+            assert_eq!(background(Model::DMG), CompatibilityPalette::GRAYSCALE.bg[1]);
+        });
+    }
+}
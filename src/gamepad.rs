@@ -1,3 +1,4 @@
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Button {
     A,
     B,
@@ -0,0 +1,396 @@
+use crate::apu::APU;
+use crate::cpu::{Bus, CPU};
+use crate::gameboy::CYCLES_PER_FRAME;
+use crate::timer::{Timer, TIMER_INTERRUPT};
+
+const HEADER_SIZE: usize = 0x70;
+const ROM_BANK_SIZE: usize = 0x4000;
+// the frame sequencer is clocked by this DIV counter bit falling, the next one up in double speed
+const FRAME_SEQUENCER_BIT: u16 = 0x1000;
+// init and play return here, nothing is ever mapped at this address so it can't be reached otherwise
+const RETURN_ADDRESS: u16 = 0xFEF0;
+
+pub struct GbsHeader {
+    pub track_count: u8,
+    // 1-based, as stored in the file
+    pub first_track: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    fn parse(data: &[u8]) -> Result<GbsHeader, String> {
+        if data.len() < HEADER_SIZE || &data[0..3] != b"GBS" {
+            return Err("not a GBS file".to_string());
+        }
+        if data[3] != 1 {
+            return Err(format!("unsupported GBS version {}", data[3]));
+        }
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let text = |offset: usize| {
+            data[offset..offset + 32]
+                .iter()
+                .take_while(|&&byte| byte != 0)
+                .map(|&byte| byte as char)
+                .collect::<String>()
+        };
+        Ok(GbsHeader {
+            track_count: data[4],
+            first_track: data[5],
+            load_address: word(6),
+            init_address: word(8),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+        })
+    }
+
+    // the play routine runs off the timer when TAC enables it, otherwise at the VBlank rate
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & 0x04 != 0
+    }
+
+    // TAC bit 7 asks for the CGB's double speed mode, the CPU and timer run twice as fast
+    pub fn double_speed(&self) -> bool {
+        self.timer_control & 0x80 != 0
+    }
+}
+
+// the memory map a GBS driver runs in: its image as a bank switched ROM, RAM everywhere else and just the
+// timer and APU among the I/O registers. There is no LCD, joypad or serial port to talk to.
+pub struct GbsBus {
+    image: Vec<u8>,
+    // ROM bank mapped at 0x4000-0x7FFF, picked by writing to 0x2000-0x3FFF
+    bank: usize,
+    memory: [u8; 0x10000],
+    pub apu: APU,
+    pub timer: Timer,
+    // set when TIMA overflows, play is due then no matter what the driver did with IE and IF
+    timer_fired: bool,
+    double_speed: bool,
+}
+
+impl GbsBus {
+    fn new(image: Vec<u8>, double_speed: bool) -> GbsBus {
+        GbsBus {
+            image,
+            bank: 1,
+            memory: [0; 0x10000],
+            apu: APU::new(),
+            timer: Timer::new(),
+            timer_fired: false,
+            double_speed,
+        }
+    }
+
+    fn rom_byte(&self, offset: usize) -> u8 {
+        self.image.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    // the APU frame sequencer runs off DIV, so it has to follow timer writes as well as ticks
+    fn clock_frame_sequencer(&mut self, old_counter: u16) {
+        let bit = if self.double_speed { FRAME_SEQUENCER_BIT << 1 } else { FRAME_SEQUENCER_BIT };
+        if old_counter & bit != 0 && self.timer.counter() & bit == 0 {
+            self.apu.clock_frame_sequencer();
+        }
+    }
+}
+
+impl Bus for GbsBus {
+    fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => self.rom_byte(address as usize),
+            0x4000..=0x7FFF => self.rom_byte(self.bank * ROM_BANK_SIZE + address as usize - ROM_BANK_SIZE),
+            // echo of work RAM
+            0xE000..=0xFDFF => self.memory[address as usize - 0x2000],
+            0xFF04..=0xFF07 => self.timer.read_register(address),
+            0xFF0F => self.memory[address as usize] | 0xE0,
+            0xFF10..=0xFF3F => self.apu.read_register(address),
+            _ => self.memory[address as usize],
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            // like MBC1, bank 0 can't be mapped in the upper half and selects bank 1
+            0x2000..=0x3FFF => self.bank = (value as usize).max(1),
+            0x0000..=0x7FFF => {}
+            0xE000..=0xFDFF => self.memory[address as usize - 0x2000] = value,
+            0xFF04..=0xFF07 => {
+                let counter = self.timer.counter();
+                self.timer.write_register(address, value);
+                self.clock_frame_sequencer(counter);
+            }
+            0xFF10..=0xFF3F => self.apu.write_register(address, value),
+            _ => self.memory[address as usize] = value,
+        }
+    }
+
+    fn step(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            let counter = self.timer.counter();
+            let interrupts = self.timer.tick();
+            self.timer_fired |= interrupts & TIMER_INTERRUPT != 0;
+            self.memory[0xFF0F] |= interrupts;
+            self.clock_frame_sequencer(counter);
+        }
+        self.apu.step(if self.double_speed { cycles / 2 } else { cycles });
+    }
+}
+
+// plays GBS music rips: the driver code runs on the CPU with just the timer and APU around it, and
+// init/play are called like subroutines from the player
+pub struct GbsPlayer {
+    pub header: GbsHeader,
+    image: Vec<u8>,
+    pub cpu: CPU<GbsBus>,
+    track: u8,
+    play_due: bool,
+    vblank_cycles: u32,
+    audio: Vec<(f32, f32)>,
+}
+
+impl GbsPlayer {
+    pub fn from_bytes(data: Vec<u8>) -> Result<GbsPlayer, String> {
+        let header = GbsHeader::parse(&data)?;
+        if header.load_address < 0x0400 || header.load_address >= 0x8000 {
+            return Err(format!("invalid GBS load address 0x{:04x}", header.load_address));
+        }
+
+        let load_address = header.load_address as usize;
+        let mut image = vec![0xFF; load_address];
+        image.extend_from_slice(&data[HEADER_SIZE..]);
+        // RST vectors jump to the same offset from the load address
+        for vector in (0x00..0x40).step_by(8) {
+            let target = header.load_address + vector as u16;
+            image[vector..vector + 3].copy_from_slice(&[0xC3, target as u8, (target >> 8) as u8]);
+        }
+        // interrupts are never enabled by the player, a driver that does it anyway just returns
+        for vector in (0x40..=0x60).step_by(8) {
+            image[vector] = 0xD9;
+        }
+
+        let first_track = header.first_track.saturating_sub(1);
+        let mut player = GbsPlayer {
+            cpu: CPU::with_bus(GbsBus::new(image.clone(), header.double_speed())),
+            header,
+            image,
+            track: first_track,
+            play_due: false,
+            vblank_cycles: 0,
+            audio: Vec::new(),
+        };
+        player.select_track(first_track)?;
+        Ok(player)
+    }
+
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+
+    // restart the hardware and run the init routine for a 0-based track number
+    pub fn select_track(&mut self, track: u8) -> Result<(), String> {
+        if track >= self.header.track_count {
+            return Err(format!("track {} out of range, the file has {}", track + 1, self.header.track_count));
+        }
+        self.track = track;
+        self.cpu = CPU::with_bus(GbsBus::new(self.image.clone(), self.header.double_speed()));
+        self.play_due = false;
+        self.vblank_cycles = 0;
+
+        let bus = &mut self.cpu.bus;
+        bus.write(0xFF26, 0x80);
+        bus.write(0xFF25, 0xFF);
+        bus.write(0xFF24, 0x77);
+        bus.write(0xFF06, self.header.timer_modulo);
+        bus.write(0xFF07, self.header.timer_control);
+
+        self.cpu.registers = Default::default();
+        self.cpu.registers.a = track;
+        self.cpu.sp = self.header.stack_pointer;
+        self.call(self.header.init_address);
+        Ok(())
+    }
+
+    fn call(&mut self, address: u16) {
+        let sp = self.cpu.sp.wrapping_sub(2);
+        self.cpu.bus.write(sp, RETURN_ADDRESS as u8);
+        self.cpu.bus.write(sp.wrapping_add(1), (RETURN_ADDRESS >> 8) as u8);
+        self.cpu.sp = sp;
+        self.cpu.pc = address;
+    }
+
+    fn idle(&self) -> bool {
+        self.cpu.pc == RETURN_ADDRESS
+    }
+
+    // CPU cycles in a frame, twice as many in double speed
+    fn frame_cycles(&self) -> u32 {
+        if self.header.double_speed() { CYCLES_PER_FRAME * 2 } else { CYCLES_PER_FRAME }
+    }
+
+    // run for one frame's worth of cycles, calling play whenever it is due and the driver is idle
+    pub fn run_frame(&mut self) {
+        let mut cycles = 0;
+        while cycles < self.frame_cycles() {
+            let step = if self.idle() {
+                if self.play_due {
+                    self.play_due = false;
+                    self.call(self.header.play_address);
+                    continue;
+                }
+                self.cpu.bus.step(4);
+                4
+            } else {
+                self.cpu.step()
+            };
+            cycles += step;
+            self.update_play_timer(step);
+        }
+        self.audio = self.cpu.bus.apu.take_samples();
+    }
+
+    fn update_play_timer(&mut self, cycles: u32) {
+        if self.header.uses_timer() {
+            // the player answers the timer itself, IF may already have been cleared by a driver running with EI
+            if std::mem::take(&mut self.cpu.bus.timer_fired) {
+                self.play_due = true;
+            }
+        } else {
+            self.vblank_cycles += cycles;
+            if self.vblank_cycles >= self.frame_cycles() {
+                self.vblank_cycles -= self.frame_cycles();
+                self.play_due = true;
+            }
+        }
+    }
+
+    pub fn audio_samples(&self) -> &[(f32, f32)] {
+        &self.audio
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // init stores A at 0xC000 and runs with the timer interrupt enabled, play counts calls at 0xC001
+    const DRIVER: [u8; 13] = [
+        0xEA, 0x00, 0xC0, // ld (0xC000),a
+        0x3E, 0x04, // ld a,0x04
+        0xEA, 0xFF, 0xFF, // ld (0xFFFF),a
+        0xFB, // ei
+        0xC9, // ret
+        0x34, // play: inc (hl)
+        0xC9, // ret
+        0x00,
+    ];
+
+    fn gbs(timer_modulo: u8, timer_control: u8) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[0..4].copy_from_slice(b"GBS\x01");
+        data[4] = 3;
+        data[5] = 2;
+        for (offset, word) in [(6, 0x0400u16), (8, 0x0400), (0x0A, 0x040A), (0x0C, 0xFFFE)] {
+            data[offset..offset + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        data[0x0E] = timer_modulo;
+        data[0x0F] = timer_control;
+        data[0x10..0x15].copy_from_slice(b"Title");
+        data[0x30..0x36].copy_from_slice(b"Author");
+        data[0x50..0x54].copy_from_slice(b"2001");
+        data.extend_from_slice(&DRIVER);
+        data
+    }
+
+    // calls to play over a number of frames, HL is pointed at the counter before every frame
+    fn plays(player: &mut GbsPlayer, frames: u32) -> u32 {
+        let mut total = 0;
+        for _ in 0..frames {
+            let before = player.cpu.bus.read_byte(0xC001);
+            player.cpu.registers.set_hl(0xC001);
+            player.run_frame();
+            total += player.cpu.bus.read_byte(0xC001).wrapping_sub(before) as u32;
+        }
+        total
+    }
+
+    #[test]
+    fn the_header_is_parsed() {
+        let header = GbsHeader::parse(&gbs(0xC0, 0x04)).unwrap();
+        assert_eq!((header.track_count, header.first_track), (3, 2));
+        assert_eq!((header.load_address, header.init_address, header.play_address), (0x0400, 0x0400, 0x040A));
+        assert_eq!(header.stack_pointer, 0xFFFE);
+        assert_eq!((header.timer_modulo, header.timer_control), (0xC0, 0x04));
+        assert_eq!(header.title, "Title");
+        assert_eq!(header.author, "Author");
+        assert_eq!(header.copyright, "2001");
+        assert!(header.uses_timer());
+        assert!(!header.double_speed());
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let mut data = gbs(0, 0);
+        data[0] = b'X';
+        assert!(GbsHeader::parse(&data).is_err());
+        let mut data = gbs(0, 0);
+        data[3] = 2;
+        assert!(GbsHeader::parse(&data).is_err());
+        assert!(GbsHeader::parse(&gbs(0, 0)[..HEADER_SIZE - 1]).is_err());
+        let mut data = gbs(0, 0);
+        data[7] = 0x03;
+        assert!(GbsPlayer::from_bytes(data).is_err());
+    }
+
+    #[test]
+    fn init_gets_the_track_in_a() {
+        let mut player = GbsPlayer::from_bytes(gbs(0, 0)).unwrap();
+        player.run_frame();
+        // the header's first track is 1-based
+        assert_eq!(player.track(), 1);
+        assert_eq!(player.cpu.bus.read_byte(0xC000), 1);
+        player.select_track(2).unwrap();
+        player.run_frame();
+        assert_eq!(player.cpu.bus.read_byte(0xC000), 2);
+        assert!(player.select_track(3).is_err());
+    }
+
+    #[test]
+    fn play_runs_at_the_vblank_rate_without_the_timer() {
+        let mut player = GbsPlayer::from_bytes(gbs(0, 0)).unwrap();
+        // the first call comes a frame after init
+        assert_eq!(plays(&mut player, 60), 59);
+    }
+
+    #[test]
+    fn play_runs_at_the_timer_rate_with_it() {
+        // TIMA counts every 16 cycles and overflows every 256 counts, about 17 calls a frame
+        let mut player = GbsPlayer::from_bytes(gbs(0x00, 0x05)).unwrap();
+        let count = plays(&mut player, 30);
+        let expected = 30 * CYCLES_PER_FRAME / (16 * 256);
+        assert!(count.abs_diff(expected) <= 1, "{} calls, expected {}", count, expected);
+    }
+
+    #[test]
+    fn double_speed_doubles_the_timer_rate_but_not_the_audio() {
+        let mut player = GbsPlayer::from_bytes(gbs(0x00, 0x05)).unwrap();
+        let normal = plays(&mut player, 10);
+        let samples = player.audio_samples().len();
+        let mut player = GbsPlayer::from_bytes(gbs(0x00, 0x85)).unwrap();
+        let double = plays(&mut player, 10);
+        assert!(double.abs_diff(normal * 2) <= 1, "{} calls against {}", double, normal);
+        assert_eq!(player.audio_samples().len(), samples);
+    }
+}
@@ -1,18 +1,157 @@
-enum JumpTest{
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JumpTest{
     NotZero, Zero, NotCarry, Carry, Always,
 }
 
-enum Instruction { 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    NOP,
+    HALT,
+    STOP,
+    DI,
+    EI,
     ADD(ArithmeticTarget),
-    JMP(JumpTest),
+    ADC(ArithmeticTarget),
+    SUB(ArithmeticTarget),
+    SBC(ArithmeticTarget),
+    AND(ArithmeticTarget),
+    XOR(ArithmeticTarget),
+    OR(ArithmeticTarget),
+    CP(ArithmeticTarget),
+    ADDHL(WordRegister),
+    ADDSP,
+    INC(IncDecTarget),
+    DEC(IncDecTarget),
+    DAA,
+    CPL,
+    CCF,
+    SCF,
+    RLCA,
+    RRCA,
+    RLA,
+    RRA,
+    JP(JumpTest),
+    JPHL,
+    JR(JumpTest),
+    CALL(JumpTest),
+    RET(JumpTest),
+    RETI,
+    RST(u8),
+    LD(LoadType),
+    PUSH(StackTarget),
+    POP(StackTarget),
+    // 0xCB prefixed
+    RLC(ByteRegister),
+    RRC(ByteRegister),
+    RL(ByteRegister),
+    RR(ByteRegister),
+    SLA(ByteRegister),
+    SRA(ByteRegister),
+    SWAP(ByteRegister),
+    SRL(ByteRegister),
+    BIT(u8, ByteRegister),
+    RES(u8, ByteRegister),
+    SET(u8, ByteRegister),
+}
+
+// 8-bit operands in the order the opcodes encode them, HLI is the byte HL points to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRegister {
+    B, C, D, E, H, L, HLI, A,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WordRegister {
+    BC, DE, HL, SP,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArithmeticTarget {
+    Register(ByteRegister),
+    D8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IncDecTarget {
+    Byte(ByteRegister),
+    Word(WordRegister),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackTarget {
+    BC, DE, HL, AF,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadByteSource {
+    Register(ByteRegister),
+    D8,
+}
+
+// memory operands of the loads to and from A
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Indirect {
+    BCIndirect,
+    DEIndirect,
+    HLIndirectPlus,
+    HLIndirectMinus,
+    // a 16-bit address following the opcode
+    WordIndirect,
+    // 0xFF00 + C
+    LastByteIndirect,
 }
 
-enum ArithmeticTarget {
-    A, B, C, D, E, H, L,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadType {
+    Byte(ByteRegister, LoadByteSource),
+    // LD rr, d16
+    Word(WordRegister),
+    AFromIndirect(Indirect),
+    IndirectFromA(Indirect),
+    // LDH A, (a8)
+    AFromByteAddress,
+    // LDH (a8), A
+    ByteAddressFromA,
+    SPFromHL,
+    // LD HL, SP + e8
+    HLFromSPN,
+    // LD (a16), SP
+    IndirectFromSP,
+}
+
+const BYTE_REGISTERS: [ByteRegister; 8] = [
+    ByteRegister::B, ByteRegister::C, ByteRegister::D, ByteRegister::E,
+    ByteRegister::H, ByteRegister::L, ByteRegister::HLI, ByteRegister::A,
+];
+
+const WORD_REGISTERS: [WordRegister; 4] = [WordRegister::BC, WordRegister::DE, WordRegister::HL, WordRegister::SP];
+
+const STACK_TARGETS: [StackTarget; 4] = [StackTarget::BC, StackTarget::DE, StackTarget::HL, StackTarget::AF];
+
+const CONDITIONS: [JumpTest; 4] = [JumpTest::NotZero, JumpTest::Zero, JumpTest::NotCarry, JumpTest::Carry];
+
+// register encoded in bits 0-2 of the opcode
+fn low_register(byte: u8) -> ByteRegister {
+    BYTE_REGISTERS[(byte & 0x07) as usize]
+}
+
+// register encoded in bits 3-5 of the opcode
+fn high_register(byte: u8) -> ByteRegister {
+    BYTE_REGISTERS[((byte >> 3) & 0x07) as usize]
+}
+
+fn word_register(byte: u8) -> WordRegister {
+    WORD_REGISTERS[((byte >> 4) & 0x03) as usize]
+}
+
+fn condition(byte: u8) -> JumpTest {
+    CONDITIONS[((byte >> 3) & 0x03) as usize]
 }
 
 impl Instruction{
-    fn from_byte(byte: u8, prefixed: bool) -> Option<Instruction> {
+    pub fn from_byte(byte: u8, prefixed: bool) -> Option<Instruction> {
         if prefixed {
           Instruction::from_byte_prefixed(byte)
         } else {
@@ -21,16 +160,356 @@ impl Instruction{
     }
 
     fn from_byte_prefixed(byte: u8) -> Option<Instruction> {
-        match byte {
-          0x00 => Some(Instruction::RLC(PrefixTarget::B)),
-          _ => /* TODO: Add mapping for rest of instructions */ None
-        }
+        let register = low_register(byte);
+        let bit = (byte >> 3) & 0x07;
+        let instruction = match byte {
+          0x00..=0x07 => Instruction::RLC(register),
+          0x08..=0x0F => Instruction::RRC(register),
+          0x10..=0x17 => Instruction::RL(register),
+          0x18..=0x1F => Instruction::RR(register),
+          0x20..=0x27 => Instruction::SLA(register),
+          0x28..=0x2F => Instruction::SRA(register),
+          0x30..=0x37 => Instruction::SWAP(register),
+          0x38..=0x3F => Instruction::SRL(register),
+          0x40..=0x7F => Instruction::BIT(bit, register),
+          0x80..=0xBF => Instruction::RES(bit, register),
+          _ => Instruction::SET(bit, register),
+        };
+        Some(instruction)
     }
-    
+
     fn from_byte_not_prefixed(byte: u8) -> Option<Instruction> {
-        match byte {
-          0x02 => Some(Instruction::INC(IncDecTarget::BC)),
-          _ => /* TODO: Add mapping for rest of instructions */ None
+        let instruction = match byte {
+          0x00 => Instruction::NOP,
+          0x10 => Instruction::STOP,
+          0x76 => Instruction::HALT,
+          0xF3 => Instruction::DI,
+          0xFB => Instruction::EI,
+
+          0x01 | 0x11 | 0x21 | 0x31 => Instruction::LD(LoadType::Word(word_register(byte))),
+          0x02 => Instruction::LD(LoadType::IndirectFromA(Indirect::BCIndirect)),
+          0x12 => Instruction::LD(LoadType::IndirectFromA(Indirect::DEIndirect)),
+          0x22 => Instruction::LD(LoadType::IndirectFromA(Indirect::HLIndirectPlus)),
+          0x32 => Instruction::LD(LoadType::IndirectFromA(Indirect::HLIndirectMinus)),
+          0x0A => Instruction::LD(LoadType::AFromIndirect(Indirect::BCIndirect)),
+          0x1A => Instruction::LD(LoadType::AFromIndirect(Indirect::DEIndirect)),
+          0x2A => Instruction::LD(LoadType::AFromIndirect(Indirect::HLIndirectPlus)),
+          0x3A => Instruction::LD(LoadType::AFromIndirect(Indirect::HLIndirectMinus)),
+          0x03 | 0x13 | 0x23 | 0x33 => Instruction::INC(IncDecTarget::Word(word_register(byte))),
+          0x0B | 0x1B | 0x2B | 0x3B => Instruction::DEC(IncDecTarget::Word(word_register(byte))),
+          0x09 | 0x19 | 0x29 | 0x39 => Instruction::ADDHL(word_register(byte)),
+          0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => {
+            Instruction::INC(IncDecTarget::Byte(high_register(byte)))
+          }
+          0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => {
+            Instruction::DEC(IncDecTarget::Byte(high_register(byte)))
+          }
+          0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => {
+            Instruction::LD(LoadType::Byte(high_register(byte), LoadByteSource::D8))
+          }
+          0x08 => Instruction::LD(LoadType::IndirectFromSP),
+
+          0x07 => Instruction::RLCA,
+          0x0F => Instruction::RRCA,
+          0x17 => Instruction::RLA,
+          0x1F => Instruction::RRA,
+          0x27 => Instruction::DAA,
+          0x2F => Instruction::CPL,
+          0x37 => Instruction::SCF,
+          0x3F => Instruction::CCF,
+
+          0x18 => Instruction::JR(JumpTest::Always),
+          0x20 | 0x28 | 0x30 | 0x38 => Instruction::JR(condition(byte)),
+
+          0x40..=0x7F => {
+            Instruction::LD(LoadType::Byte(high_register(byte), LoadByteSource::Register(low_register(byte))))
+          }
+
+          0x80..=0x87 => Instruction::ADD(ArithmeticTarget::Register(low_register(byte))),
+          0x88..=0x8F => Instruction::ADC(ArithmeticTarget::Register(low_register(byte))),
+          0x90..=0x97 => Instruction::SUB(ArithmeticTarget::Register(low_register(byte))),
+          0x98..=0x9F => Instruction::SBC(ArithmeticTarget::Register(low_register(byte))),
+          0xA0..=0xA7 => Instruction::AND(ArithmeticTarget::Register(low_register(byte))),
+          0xA8..=0xAF => Instruction::XOR(ArithmeticTarget::Register(low_register(byte))),
+          0xB0..=0xB7 => Instruction::OR(ArithmeticTarget::Register(low_register(byte))),
+          0xB8..=0xBF => Instruction::CP(ArithmeticTarget::Register(low_register(byte))),
+          0xC6 => Instruction::ADD(ArithmeticTarget::D8),
+          0xCE => Instruction::ADC(ArithmeticTarget::D8),
+          0xD6 => Instruction::SUB(ArithmeticTarget::D8),
+          0xDE => Instruction::SBC(ArithmeticTarget::D8),
+          0xE6 => Instruction::AND(ArithmeticTarget::D8),
+          0xEE => Instruction::XOR(ArithmeticTarget::D8),
+          0xF6 => Instruction::OR(ArithmeticTarget::D8),
+          0xFE => Instruction::CP(ArithmeticTarget::D8),
+
+          0xC0 | 0xC8 | 0xD0 | 0xD8 => Instruction::RET(condition(byte)),
+          0xC9 => Instruction::RET(JumpTest::Always),
+          0xD9 => Instruction::RETI,
+          0xC2 | 0xCA | 0xD2 | 0xDA => Instruction::JP(condition(byte)),
+          0xC3 => Instruction::JP(JumpTest::Always),
+          0xE9 => Instruction::JPHL,
+          0xC4 | 0xCC | 0xD4 | 0xDC => Instruction::CALL(condition(byte)),
+          0xCD => Instruction::CALL(JumpTest::Always),
+          0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => Instruction::RST(byte & 0x38),
+          0xC1 | 0xD1 | 0xE1 | 0xF1 => Instruction::POP(STACK_TARGETS[((byte >> 4) & 0x03) as usize]),
+          0xC5 | 0xD5 | 0xE5 | 0xF5 => Instruction::PUSH(STACK_TARGETS[((byte >> 4) & 0x03) as usize]),
+
+          0xE0 => Instruction::LD(LoadType::ByteAddressFromA),
+          0xF0 => Instruction::LD(LoadType::AFromByteAddress),
+          0xE2 => Instruction::LD(LoadType::IndirectFromA(Indirect::LastByteIndirect)),
+          0xF2 => Instruction::LD(LoadType::AFromIndirect(Indirect::LastByteIndirect)),
+          0xEA => Instruction::LD(LoadType::IndirectFromA(Indirect::WordIndirect)),
+          0xFA => Instruction::LD(LoadType::AFromIndirect(Indirect::WordIndirect)),
+          0xE8 => Instruction::ADDSP,
+          0xF8 => Instruction::LD(LoadType::HLFromSPN),
+          0xF9 => Instruction::LD(LoadType::SPFromHL),
+
+          // 0xCB is handled by the caller, the rest (0xD3, 0xDB, ...) don't exist and lock up the CPU
+          _ => return None,
+        };
+        Some(instruction)
+    }
+}
+
+// immediate operands following the opcode, printed as RGBDS placeholders unless the bytes are known
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Immediate {
+    Byte,
+    // a value or an address
+    Word,
+    // signed, added to SP
+    Offset,
+    // where JP and CALL go
+    Address,
+    // where JR goes, the offset after the opcode is relative to the next instruction
+    RelativeAddress,
+    // the byte after LDH, an address in 0xFF00-0xFFFF
+    HighAddress,
+}
+
+impl Immediate {
+    // the operand names the RGBDS documentation uses
+    pub fn placeholder(self) -> &'static str {
+        match self {
+            Immediate::Byte => "n8",
+            Immediate::Word | Immediate::Address | Immediate::HighAddress => "n16",
+            Immediate::Offset | Immediate::RelativeAddress => "e8",
+        }
+    }
+}
+
+impl Instruction {
+    // bytes taken by the opcode, the 0xCB prefix and the immediate. STOP is followed by a byte that is skipped.
+    pub fn length(&self) -> u16 {
+        match self.immediate() {
+            Some(Immediate::Word | Immediate::Address) => 3,
+            Some(_) => 2,
+            None if self.is_prefixed() || *self == Instruction::STOP => 2,
+            None => 1,
+        }
+    }
+
+    pub fn is_prefixed(&self) -> bool {
+        matches!(
+            self,
+            Instruction::RLC(_)
+                | Instruction::RRC(_)
+                | Instruction::RL(_)
+                | Instruction::RR(_)
+                | Instruction::SLA(_)
+                | Instruction::SRA(_)
+                | Instruction::SWAP(_)
+                | Instruction::SRL(_)
+                | Instruction::BIT(..)
+                | Instruction::RES(..)
+                | Instruction::SET(..)
+        )
+    }
+
+    pub fn immediate(&self) -> Option<Immediate> {
+        let immediate = match self {
+            Instruction::ADD(ArithmeticTarget::D8)
+            | Instruction::ADC(ArithmeticTarget::D8)
+            | Instruction::SUB(ArithmeticTarget::D8)
+            | Instruction::SBC(ArithmeticTarget::D8)
+            | Instruction::AND(ArithmeticTarget::D8)
+            | Instruction::XOR(ArithmeticTarget::D8)
+            | Instruction::OR(ArithmeticTarget::D8)
+            | Instruction::CP(ArithmeticTarget::D8)
+            | Instruction::LD(LoadType::Byte(_, LoadByteSource::D8)) => Immediate::Byte,
+            Instruction::LD(LoadType::Word(_))
+            | Instruction::LD(LoadType::AFromIndirect(Indirect::WordIndirect))
+            | Instruction::LD(LoadType::IndirectFromA(Indirect::WordIndirect))
+            | Instruction::LD(LoadType::IndirectFromSP) => Immediate::Word,
+            Instruction::ADDSP | Instruction::LD(LoadType::HLFromSPN) => Immediate::Offset,
+            Instruction::JP(_) | Instruction::CALL(_) => Immediate::Address,
+            Instruction::JR(_) => Immediate::RelativeAddress,
+            Instruction::LD(LoadType::AFromByteAddress) | Instruction::LD(LoadType::ByteAddressFromA) => {
+                Immediate::HighAddress
+            }
+            _ => return None,
+        };
+        Some(immediate)
+    }
+
+    // RGBDS syntax, with the immediate operand written by the given function
+    pub fn format_with(&self, immediate: &dyn Fn(Immediate) -> String) -> String {
+        let arithmetic = |target: ArithmeticTarget| match target {
+            ArithmeticTarget::Register(register) => register.to_string(),
+            ArithmeticTarget::D8 => immediate(Immediate::Byte),
+        };
+        let condition = |test: JumpTest| match test {
+            JumpTest::Always => String::new(),
+            _ => format!("{}, ", test),
+        };
+        match *self {
+            Instruction::NOP => "nop".to_string(),
+            Instruction::HALT => "halt".to_string(),
+            Instruction::STOP => "stop".to_string(),
+            Instruction::DI => "di".to_string(),
+            Instruction::EI => "ei".to_string(),
+            Instruction::ADD(target) => format!("add a, {}", arithmetic(target)),
+            Instruction::ADC(target) => format!("adc a, {}", arithmetic(target)),
+            Instruction::SUB(target) => format!("sub a, {}", arithmetic(target)),
+            Instruction::SBC(target) => format!("sbc a, {}", arithmetic(target)),
+            Instruction::AND(target) => format!("and a, {}", arithmetic(target)),
+            Instruction::XOR(target) => format!("xor a, {}", arithmetic(target)),
+            Instruction::OR(target) => format!("or a, {}", arithmetic(target)),
+            Instruction::CP(target) => format!("cp a, {}", arithmetic(target)),
+            Instruction::ADDHL(register) => format!("add hl, {}", register),
+            Instruction::ADDSP => format!("add sp, {}", immediate(Immediate::Offset)),
+            Instruction::INC(target) => format!("inc {}", target),
+            Instruction::DEC(target) => format!("dec {}", target),
+            Instruction::DAA => "daa".to_string(),
+            Instruction::CPL => "cpl".to_string(),
+            Instruction::CCF => "ccf".to_string(),
+            Instruction::SCF => "scf".to_string(),
+            Instruction::RLCA => "rlca".to_string(),
+            Instruction::RRCA => "rrca".to_string(),
+            Instruction::RLA => "rla".to_string(),
+            Instruction::RRA => "rra".to_string(),
+            Instruction::JP(test) => format!("jp {}{}", condition(test), immediate(Immediate::Address)),
+            Instruction::JPHL => "jp hl".to_string(),
+            Instruction::JR(test) => format!("jr {}{}", condition(test), immediate(Immediate::RelativeAddress)),
+            Instruction::CALL(test) => format!("call {}{}", condition(test), immediate(Immediate::Address)),
+            Instruction::RET(JumpTest::Always) => "ret".to_string(),
+            Instruction::RET(test) => format!("ret {}", test),
+            Instruction::RETI => "reti".to_string(),
+            Instruction::RST(vector) => format!("rst ${:02X}", vector),
+            Instruction::LD(load_type) => load_type.format_with(immediate),
+            Instruction::PUSH(target) => format!("push {}", target),
+            Instruction::POP(target) => format!("pop {}", target),
+            Instruction::RLC(register) => format!("rlc {}", register),
+            Instruction::RRC(register) => format!("rrc {}", register),
+            Instruction::RL(register) => format!("rl {}", register),
+            Instruction::RR(register) => format!("rr {}", register),
+            Instruction::SLA(register) => format!("sla {}", register),
+            Instruction::SRA(register) => format!("sra {}", register),
+            Instruction::SWAP(register) => format!("swap {}", register),
+            Instruction::SRL(register) => format!("srl {}", register),
+            Instruction::BIT(bit, register) => format!("bit {}, {}", bit, register),
+            Instruction::RES(bit, register) => format!("res {}, {}", bit, register),
+            Instruction::SET(bit, register) => format!("set {}, {}", bit, register),
+        }
+    }
+}
+
+impl LoadType {
+    fn format_with(&self, immediate: &dyn Fn(Immediate) -> String) -> String {
+        let indirect = |indirect: Indirect| match indirect {
+            Indirect::BCIndirect => "[bc]".to_string(),
+            Indirect::DEIndirect => "[de]".to_string(),
+            Indirect::HLIndirectPlus => "[hl+]".to_string(),
+            Indirect::HLIndirectMinus => "[hl-]".to_string(),
+            Indirect::WordIndirect => format!("[{}]", immediate(Immediate::Word)),
+            Indirect::LastByteIndirect => "[c]".to_string(),
+        };
+        match *self {
+            LoadType::Byte(register, LoadByteSource::Register(source)) => format!("ld {}, {}", register, source),
+            LoadType::Byte(register, LoadByteSource::D8) => format!("ld {}, {}", register, immediate(Immediate::Byte)),
+            LoadType::Word(register) => format!("ld {}, {}", register, immediate(Immediate::Word)),
+            LoadType::AFromIndirect(Indirect::LastByteIndirect) => "ldh a, [c]".to_string(),
+            LoadType::AFromIndirect(source) => format!("ld a, {}", indirect(source)),
+            LoadType::IndirectFromA(Indirect::LastByteIndirect) => "ldh [c], a".to_string(),
+            LoadType::IndirectFromA(destination) => format!("ld {}, a", indirect(destination)),
+            LoadType::AFromByteAddress => format!("ldh a, [{}]", immediate(Immediate::HighAddress)),
+            LoadType::ByteAddressFromA => format!("ldh [{}], a", immediate(Immediate::HighAddress)),
+            LoadType::SPFromHL => "ld sp, hl".to_string(),
+            LoadType::HLFromSPN => {
+                let offset = immediate(Immediate::Offset);
+                let sign = if offset.starts_with('-') { "" } else { "+" };
+                format!("ld hl, sp{}{}", sign, offset)
+            }
+            LoadType::IndirectFromSP => format!("ld [{}], sp", immediate(Immediate::Word)),
+        }
+    }
+}
+
+// the operands as placeholders, e.g. "ld a, n8"
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format_with(&|immediate| immediate.placeholder().to_string()))
+    }
+}
+
+impl fmt::Display for JumpTest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            JumpTest::NotZero => "nz",
+            JumpTest::Zero => "z",
+            JumpTest::NotCarry => "nc",
+            JumpTest::Carry => "c",
+            JumpTest::Always => "",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for ByteRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ByteRegister::B => "b",
+            ByteRegister::C => "c",
+            ByteRegister::D => "d",
+            ByteRegister::E => "e",
+            ByteRegister::H => "h",
+            ByteRegister::L => "l",
+            ByteRegister::HLI => "[hl]",
+            ByteRegister::A => "a",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for WordRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            WordRegister::BC => "bc",
+            WordRegister::DE => "de",
+            WordRegister::HL => "hl",
+            WordRegister::SP => "sp",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for StackTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            StackTarget::BC => "bc",
+            StackTarget::DE => "de",
+            StackTarget::HL => "hl",
+            StackTarget::AF => "af",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for IncDecTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IncDecTarget::Byte(register) => write!(f, "{}", register),
+            IncDecTarget::Word(register) => write!(f, "{}", register),
         }
     }
-}
\ No newline at end of file
+}
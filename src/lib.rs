@@ -0,0 +1,23 @@
+pub mod apu;
+pub mod cartridge;
+pub mod compatpalette;
+pub mod cpu;
+pub mod disassembler;
+pub mod gameboy;
+pub mod gamepad;
+pub mod gbs;
+pub mod instructions;
+pub mod link;
+pub mod memorybus;
+pub mod model;
+pub mod png;
+pub mod ppu;
+pub mod printer;
+pub mod registers;
+pub mod resampler;
+pub mod serial;
+pub mod sgb;
+pub mod socketlink;
+pub mod timer;
+pub mod vgm;
+pub mod wav;
@@ -0,0 +1,148 @@
+use std::io;
+
+use crate::gameboy::{GameBoy, CYCLES_PER_FRAME};
+
+// two Game Boys connected by a link cable, run in lockstep so neither gets more than one instruction
+// ahead of the other. Whichever side starts an internally clocked transfer drives the other: it shifts in
+// the listening side's SB, and the listening side receives its byte when the clocking side finishes.
+pub struct LinkCable {
+    pub first: GameBoy,
+    pub second: GameBoy,
+    // T-cycles the first Game Boy is ahead of the second
+    balance: i64,
+    // byte on its way to each side, sent by the other one
+    in_flight: [Option<u8>; 2],
+}
+
+impl LinkCable {
+    // anything plugged into either link port is unplugged, the cable takes its place
+    pub fn new(mut first: GameBoy, mut second: GameBoy) -> LinkCable {
+        first.cpu.bus.serial.disconnect();
+        second.cpu.bus.serial.disconnect();
+        LinkCable {
+            first,
+            second,
+            balance: 0,
+            in_flight: [None, None],
+        }
+    }
+
+    pub fn into_inner(self) -> (GameBoy, GameBoy) {
+        (self.first, self.second)
+    }
+
+    // run both Game Boys for one frame's worth of cycles, the LCD timing of either side doesn't matter here
+    pub fn run_frame(&mut self) -> io::Result<()> {
+        let target = CYCLES_PER_FRAME as i64;
+        let mut first_cycles = 0;
+        let mut second_cycles = 0;
+        while first_cycles < target || second_cycles < target {
+            if self.balance <= 0 {
+                let cycles = self.first.step() as i64;
+                first_cycles += cycles;
+                self.balance += cycles;
+            } else {
+                let cycles = self.second.step() as i64;
+                second_cycles += cycles;
+                self.balance -= cycles;
+            }
+            self.exchange();
+        }
+        self.first.finish_frame()?;
+        self.second.finish_frame()
+    }
+
+    fn exchange(&mut self) {
+        self.connect(0);
+        self.connect(1);
+    }
+
+    // pass transfers clocked by one side on to the other
+    fn connect(&mut self, clocking: usize) {
+        let (master, slave) = if clocking == 0 {
+            (&mut self.first.cpu.bus.serial, &mut self.second.cpu.bus.serial)
+        } else {
+            (&mut self.second.cpu.bus.serial, &mut self.first.cpu.bus.serial)
+        };
+        let listening = 1 - clocking;
+
+        if let Some(byte) = master.take_transfer_started() {
+            // with nobody listening the line stays high and the clocking side reads 0xFF
+            if slave.is_listening() {
+                master.set_incoming(slave.read_register(0xFF01));
+                self.in_flight[listening] = Some(byte);
+            } else {
+                self.in_flight[listening] = None;
+            }
+        }
+        if master.take_transfer_finished() {
+            if let Some(byte) = self.in_flight[listening].take() {
+                slave.receive(byte);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{Cartridge, MBC};
+    use crate::model::Model;
+    use crate::ppu::Renderer;
+
+    // puts sb in SB, optionally waits a while, writes sc to SC, waits for the transfer and copies SB to 0xC000
+    fn game_boy(sb: u8, sc: u8, delay: bool) -> GameBoy {
+        let mut code = vec![0x3E, sb, 0xE0, 0x01];
+        if delay {
+            // ld b,0 / dec b / jr nz
+            code.extend_from_slice(&[0x06, 0x00, 0x05, 0x20, 0xFD]);
+        }
+        code.extend_from_slice(&[
+            0x3E, sc, 0xE0, 0x02, // ld a,sc / ldh (0x02),a
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, // wait for bit 7 of SC to clear
+            0xF0, 0x01, 0xEA, 0x00, 0xC0, // ldh a,(0x01) / ld (0xC000),a
+            0x18, 0xFE, // jr -2
+        ]);
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0100 + code.len()].copy_from_slice(&code);
+        let cartridge = Cartridge::with_mbc(rom, MBC::None, 0);
+        let mut game_boy = GameBoy::with_model(cartridge, Model::DMG, Renderer::Scanline);
+        game_boy.cpu.bus.write(0xC000, 0x00);
+        game_boy
+    }
+
+    type Program = (u8, u8, bool);
+
+    // what each side ends up with after two frames. Two Game Boys don't fit on a test thread's stack
+    // in debug builds, so they get a thread of their own.
+    fn received(first: Program, second: Program) -> (u8, u8) {
+        let run = move || {
+            let first = game_boy(first.0, first.1, first.2);
+            let second = game_boy(second.0, second.1, second.2);
+            let mut cable = LinkCable::new(first, second);
+            for _ in 0..2 {
+                cable.run_frame().unwrap();
+            }
+            let (first, second) = cable.into_inner();
+            (first.cpu.bus.read_byte(0xC000), second.cpu.bus.read_byte(0xC000))
+        };
+        std::thread::Builder::new().stack_size(32 << 20).spawn(run).unwrap().join().unwrap()
+    }
+
+    #[test]
+    fn the_clocking_side_swaps_bytes_with_the_listening_side() {
+        assert_eq!(received((0x11, 0x81, true), (0x22, 0x80, false)), (0x22, 0x11));
+        assert_eq!(received((0x11, 0x80, false), (0x22, 0x81, true)), (0x22, 0x11));
+    }
+
+    #[test]
+    fn both_sides_clocking_read_an_idle_line() {
+        assert_eq!(received((0x11, 0x81, false), (0x22, 0x81, false)), (0xFF, 0xFF));
+    }
+
+    #[test]
+    fn listening_after_the_transfer_started_is_too_late() {
+        // the first side clocks into an idle line, the second then waits for a clock that never comes
+        assert_eq!(received((0x11, 0x81, false), (0x22, 0x80, true)), (0xFF, 0x00));
+    }
+}
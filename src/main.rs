@@ -1,3 +1,357 @@
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+
+use emulator::cartridge::Cartridge;
+use emulator::compatpalette::CompatibilityPalette;
+use emulator::disassembler::disassemble_rom;
+use emulator::gameboy::{GameBoy, DEFAULT_RECORDING_RATE};
+use emulator::gamepad::Button;
+use emulator::gbs::GbsPlayer;
+use emulator::model::Model;
+use emulator::ppu::Renderer;
+use emulator::socketlink::SocketLink;
+use emulator::vgm::VgmRecorder;
+use emulator::wav::WavRecorder;
+
+const DEFAULT_FRAMES: u32 = 3600;
+
+const USAGE: &str = "usage: emulator <rom|gbs> [--frames <count>] [--record-wav <file>] [--record-vgm <file>] [--track <number>] [--serial] [--printer <directory>]
+       [--link-listen <address>] [--link-connect <address>] [--palette <auto|combo>]
+       [--model <DMG0|DMG|MGB|SGB|SGB2|CGB|AGB>] [--boot-rom <file>] [--renderer <scanline|fifo>] [--disassemble]
+addresses are host:port for TCP or unix:<path> for a Unix domain socket
+--palette runs DMG games on a CGB model with the palette picked by title or the one a button combo
+like up, left+a or down+b selects during the boot logo
+--renderer fifo draws dot by dot and shows mid-line register writes, scanline draws a line at a time
+--disassemble prints the whole ROM in RGBDS syntax instead of running it";
+
+struct Options {
+    rom: String,
+    frames: u32,
+    record_wav: Option<String>,
+    record_vgm: Option<String>,
+    // 1-based GBS track, the file's first track when not given
+    track: Option<u8>,
+    // print what the ROM sends over serial and stop once it reports Passed or Failed
+    serial: bool,
+    // link cable to another emulator process, the bool is true for the side that waits for the connection
+    link: Option<(String, bool)>,
+    // directory for the pages printed on an emulated Game Boy Printer
+    printer: Option<String>,
+    // buttons held at boot to pick the CGB palette for a DMG game, empty to pick it by title
+    palette: Option<Vec<Button>>,
+    // hardware to run on, picked from the cartridge header when not given
+    model: Option<Model>,
+    // boot ROM image to start from, its size tells DMG and CGB apart when no model is given
+    boot_rom: Option<String>,
+    // the scanline renderer when not given
+    renderer: Option<Renderer>,
+    // print a listing of the ROM and exit
+    disassemble: bool,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut rom = None;
+    let mut frames = DEFAULT_FRAMES;
+    let mut record_wav = None;
+    let mut record_vgm = None;
+    let mut track = None;
+    let mut serial = false;
+    let mut link = None;
+    let mut printer = None;
+    let mut palette = None;
+    let mut model = None;
+    let mut boot_rom = None;
+    let mut renderer = None;
+    let mut disassemble = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => {
+                let value = args.next().ok_or("--frames needs a count")?;
+                frames = value.parse().map_err(|_| format!("invalid frame count: {}", value))?;
+            }
+            "--record-wav" => {
+                record_wav = Some(args.next().ok_or("--record-wav needs a file name")?.clone());
+            }
+            "--record-vgm" => {
+                record_vgm = Some(args.next().ok_or("--record-vgm needs a file name")?.clone());
+            }
+            "--track" => {
+                let value = args.next().ok_or("--track needs a number")?;
+                track = Some(value.parse().ok().filter(|&track| track > 0).ok_or(format!("invalid track: {}", value))?);
+            }
+            "--serial" => serial = true,
+            "--printer" => {
+                printer = Some(args.next().ok_or("--printer needs a directory")?.clone());
+            }
+            "--link-listen" | "--link-connect" => {
+                let address = args.next().ok_or(format!("{} needs an address", arg))?;
+                link = Some((address.clone(), arg == "--link-listen"));
+            }
+            "--palette" => {
+                let value = args.next().ok_or("--palette needs auto or a button combo")?;
+                palette = Some(parse_palette_buttons(value).ok_or(format!("invalid palette: {}", value))?);
+            }
+            "--model" => {
+                let value = args.next().ok_or("--model needs a model name")?;
+                model = Some(Model::from_name(value).ok_or(format!("unknown model: {}", value))?);
+            }
+            "--boot-rom" => {
+                boot_rom = Some(args.next().ok_or("--boot-rom needs a file name")?.clone());
+            }
+            "--renderer" => {
+                let value = args.next().ok_or("--renderer needs scanline or fifo")?;
+                renderer = Some(match value.as_str() {
+                    "scanline" => Renderer::Scanline,
+                    "fifo" => Renderer::PixelFifo,
+                    _ => return Err(format!("unknown renderer: {}", value)),
+                });
+            }
+            "--disassemble" => disassemble = true,
+            _ if arg.starts_with("--") => return Err(format!("unknown option: {}", arg)),
+            _ => rom = Some(arg.clone()),
+        }
+    }
+
+    if [serial, link.is_some(), printer.is_some()].iter().filter(|&&used| used).count() > 1 {
+        return Err("--serial, --printer and a link cable can't share the link port".to_string());
+    }
+    let rom = rom.ok_or("no ROM given")?;
+    if rom.to_ascii_lowercase().ends_with(".gbs") {
+        // a GBS file only runs on the CPU, timer and APU, there is no cartridge or other hardware to set up
+        let link_option = match link {
+            Some((_, true)) => "--link-listen",
+            _ => "--link-connect",
+        };
+        let rom_only = [
+            ("--disassemble", disassemble),
+            ("--serial", serial),
+            ("--printer", printer.is_some()),
+            (link_option, link.is_some()),
+            ("--palette", palette.is_some()),
+            ("--model", model.is_some()),
+            ("--boot-rom", boot_rom.is_some()),
+            ("--renderer", renderer.is_some()),
+        ];
+        if let Some((option, _)) = rom_only.iter().find(|(_, used)| *used) {
+            return Err(format!("{} needs a ROM, not a GBS file", option));
+        }
+    }
+
+    Ok(Options {
+        rom,
+        frames,
+        record_wav,
+        record_vgm,
+        track,
+        serial,
+        link,
+        printer,
+        palette,
+        model,
+        boot_rom,
+        renderer,
+        disassemble,
+    })
+}
+
+// "auto" or a direction with an optional +a or +b, e.g. "left+b"
+fn parse_palette_buttons(value: &str) -> Option<Vec<Button>> {
+    let value = value.to_ascii_lowercase();
+    if value == "auto" {
+        return Some(Vec::new());
+    }
+    let (direction, face) = match value.split_once('+') {
+        Some((direction, face)) => (direction, Some(face)),
+        None => (value.as_str(), None),
+    };
+    let mut buttons = vec![match direction {
+        "up" => Button::Up,
+        "down" => Button::Down,
+        "left" => Button::Left,
+        "right" => Button::Right,
+        _ => return None,
+    }];
+    match face {
+        None => {}
+        Some("a") => buttons.push(Button::A),
+        Some("b") => buttons.push(Button::B),
+        Some(_) => return None,
+    }
+    Some(buttons)
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let rom = fs::read(&options.rom).map_err(|error| format!("can't read {}: {}", options.rom, error))?;
+    if options.disassemble {
+        return io::stdout().write_all(disassemble_rom(&rom).as_bytes()).map_err(|error| error.to_string());
+    }
+    if options.rom.to_ascii_lowercase().ends_with(".gbs") {
+        return run_gbs(rom, options);
+    }
+    let cartridge = Cartridge::from_bytes(rom)?;
+    let boot_rom = match &options.boot_rom {
+        Some(path) => Some(fs::read(path).map_err(|error| format!("can't read {}: {}", path, error))?),
+        None => None,
+    };
+    let model = options.model.unwrap_or_else(|| match &boot_rom {
+        Some(boot_rom) if boot_rom.len() > 0x100 => Model::CGB,
+        Some(_) => Model::DMG,
+        // asking for a compatibility palette asks for a CGB to show it
+        None if options.palette.is_some() => Model::CGB,
+        None => GameBoy::default_model(&cartridge),
+    });
+    // compatibility palettes are a CGB thing for games that don't run in CGB mode, and a boot ROM picks its own
+    if options.palette.is_some() {
+        if boot_rom.is_some() {
+            return Err("--palette can't be used with --boot-rom, the boot ROM picks the palette".to_string());
+        }
+        if !model.is_cgb() {
+            return Err(format!("--palette needs a CGB model, the {} has no color palettes", model.name()));
+        }
+        if cartridge.supports_cgb() {
+            return Err("--palette only applies to DMG games, this one runs in CGB mode".to_string());
+        }
+    }
+    let palette = options.palette.as_ref().map(|buttons| {
+        CompatibilityPalette::for_buttons(buttons).unwrap_or_else(|| CompatibilityPalette::for_cartridge(&cartridge))
+    });
+    let renderer = options.renderer.unwrap_or(Renderer::Scanline);
+    let mut gameboy = match boot_rom {
+        Some(boot_rom) => GameBoy::with_boot_rom(cartridge, model, renderer, boot_rom)?,
+        None => GameBoy::with_model(cartridge, model, renderer),
+    };
+    if let Some(palette) = palette {
+        gameboy.set_dmg_palette(palette);
+    }
+
+    if let Some(path) = &options.record_wav {
+        gameboy
+            .start_recording(path, DEFAULT_RECORDING_RATE)
+            .map_err(|error| format!("can't record to {}: {}", path, error))?;
+    }
+    if let Some(path) = &options.record_vgm {
+        gameboy
+            .start_vgm_recording(path)
+            .map_err(|error| format!("can't record to {}: {}", path, error))?;
+    }
+
+    if let Some((address, listen)) = &options.link {
+        let link = open_link(address, *listen).map_err(|error| format!("can't link over {}: {}", address, error))?;
+        gameboy.cpu.bus.serial.connect(Box::new(link));
+    }
+
+    let capture = if options.serial { Some(gameboy.capture_serial()) } else { None };
+    let printer = options.printer.as_ref().map(|_| gameboy.connect_printer());
+    // the end of the serial output so far, Passed or Failed can be split across two frames
+    let mut recent = Vec::new();
+    let mut failed = false;
+
+    for _ in 0..options.frames {
+        gameboy.run_frame().map_err(|error| error.to_string())?;
+        if let Some(capture) = &capture {
+            let bytes = capture.take_bytes();
+            let mut stdout = io::stdout();
+            stdout.write_all(&bytes).and_then(|_| stdout.flush()).map_err(|error| error.to_string())?;
+            recent.extend_from_slice(&bytes);
+            let text = String::from_utf8_lossy(&recent);
+            if text.contains("Passed") || text.contains("Failed") {
+                failed = text.contains("Failed");
+                break;
+            }
+            recent.drain(..recent.len().saturating_sub("Passed".len() - 1));
+        }
+    }
+
+    if let (Some(printer), Some(directory)) = (&printer, &options.printer) {
+        printer.finish_page();
+        for (index, page) in printer.take_pages().iter().enumerate() {
+            let path = Path::new(directory).join(format!("print-{:03}.png", index + 1));
+            page.write_png(&path).map_err(|error| format!("can't write {}: {}", path.display(), error))?;
+        }
+    }
+
+    gameboy.stop_recording().map_err(|error| error.to_string())?;
+    gameboy.stop_vgm_recording().map_err(|error| error.to_string())?;
+    if failed {
+        return Err("\ntest ROM reported a failure".to_string());
+    }
+    Ok(())
+}
+
+fn open_link(address: &str, listen: bool) -> io::Result<SocketLink> {
+    #[cfg(unix)]
+    if let Some(path) = address.strip_prefix("unix:") {
+        return if listen { SocketLink::listen_unix(path) } else { SocketLink::connect_unix(path) };
+    }
+    if listen {
+        SocketLink::listen_tcp(address)
+    } else {
+        SocketLink::connect_tcp(address)
+    }
+}
+
+fn run_gbs(data: Vec<u8>, options: Options) -> Result<(), String> {
+    let mut player = GbsPlayer::from_bytes(data)?;
+    if let Some(track) = options.track {
+        player.select_track(track - 1)?;
+    }
+    let header = &player.header;
+    println!("{} - {} ({})", header.title, header.author, header.copyright);
+    println!("track {} of {}", player.track() + 1, header.track_count);
+
+    let mut recorder = match &options.record_wav {
+        Some(path) => Some(
+            WavRecorder::create(path, DEFAULT_RECORDING_RATE)
+                .map_err(|error| format!("can't record to {}: {}", path, error))?,
+        ),
+        None => None,
+    };
+    let mut vgm_recorder = match &options.record_vgm {
+        Some(path) => Some(VgmRecorder::create(path).map_err(|error| format!("can't record to {}: {}", path, error))?),
+        None => None,
+    };
+    if vgm_recorder.is_some() {
+        player.cpu.bus.apu.start_register_log();
+    }
+
+    for _ in 0..options.frames {
+        player.run_frame();
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.push(player.audio_samples()).map_err(|error| error.to_string())?;
+        }
+        if let Some(recorder) = vgm_recorder.as_mut() {
+            let apu = &mut player.cpu.bus.apu;
+            recorder
+                .push(&apu.take_register_writes(), apu.register_log_cycle())
+                .map_err(|error| error.to_string())?;
+        }
+    }
+
+    if let Some(recorder) = recorder {
+        recorder.finish().map_err(|error| error.to_string())?;
+    }
+    match vgm_recorder {
+        Some(recorder) => recorder.finish().map_err(|error| error.to_string()),
+        None => Ok(()),
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
-}
\ No newline at end of file
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_options(&args) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}\n{}", error, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(error) = run(options) {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
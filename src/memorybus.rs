@@ -1,13 +1,906 @@
-struct MemoryBus{
-    memory: [u8; 0xFFFF]
+use crate::apu::APU;
+use crate::cartridge::Cartridge;
+use crate::model::Model;
+use crate::ppu::{Mode, PPU, VBLANK_INTERRUPT};
+use crate::serial::SerialPort;
+use crate::sgb::Sgb;
+use crate::timer::Timer;
+
+const OAM_DMA_LENGTH: u16 = 0xA0;
+// M-cycles from the write to 0xFF46 until the first byte is copied
+const OAM_DMA_STARTUP_DELAY: u8 = 2;
+
+// VRAM DMA copies 16 byte blocks, each one keeps the CPU stopped for 32 dots
+const VRAM_DMA_BLOCK_SIZE: u16 = 0x10;
+const VRAM_DMA_BLOCK_DOTS: u32 = 32;
+
+// DMG-family boot ROMs cover 0x0000-0x00FF, the CGB one also 0x0200-0x08FF around the cartridge header
+const BOOT_ROM_SIZE: usize = 0x100;
+const CGB_BOOT_ROM_SIZE: usize = 0x900;
+// KEY0 value the CGB boot ROM writes to run a game in DMG compatibility mode
+const KEY0_DMG_MODE: u8 = 0x04;
+
+const WRAM_BANK_SIZE: usize = 0x1000;
+// CGB has eight work RAM banks, DMG only uses the first two
+const WRAM_BANKS: usize = 8;
+
+#[derive(Clone, Copy)]
+struct OamDma {
+    source: u16,
+    index: u16,
+    delay: u8,
+}
+
+// CGB VRAM DMA from HDMA1-5, either all at once (general purpose) or one block per HBlank
+struct VramDma {
+    source: u16,
+    // offset into VRAM
+    destination: u16,
+    // blocks left to copy, minus one, as HDMA5 reports them
+    remaining: u8,
+    hblank_active: bool,
+    // an HBlank started that hasn't had its block copied yet
+    block_due: bool,
+    // T-cycles the CPU has to wait for copies already made
+    stall: u32,
+}
+
+pub struct MemoryBus{
+    model: Model,
+    memory: [u8; 0x10000],
+    pub cartridge: Cartridge,
+    pub ppu: PPU,
+    pub apu: APU,
+    pub timer: Timer,
+    pub serial: SerialPort,
+    // the CPU is locked out of VRAM in mode 3 and OAM in modes 2/3, can be switched off for debugging
+    ppu_access_restrictions: bool,
+    oam_dma: Option<OamDma>,
+    // a newly requested transfer waits out its startup delay while a running one keeps going
+    oam_dma_pending: Option<OamDma>,
+    // last byte moved by the DMA, this is what the CPU sees when it reads from the bus the DMA is using
+    oam_dma_byte: u8,
+    // T-cycles not yet making up a full M-cycle
+    dma_cycles: u32,
+    // CGB mode, picked from the cartridge header. CGB-only registers read 0xFF otherwise.
+    cgb_mode: bool,
+    wram: [u8; WRAM_BANK_SIZE * WRAM_BANKS],
+    // SVBK as written, bank 0 maps bank 1 like on DMG
+    svbk: u8,
+    // KEY1 bit 0, STOP switches the CPU speed while it is set
+    speed_switch_armed: bool,
+    double_speed: bool,
+    // undocumented CGB registers 0xFF72-0xFF75, plain storage
+    undocumented: [u8; 4],
+    // infrared port RP, only the LED and read enable bits are kept
+    rp: u8,
+    vram_dma: VramDma,
+    // mapped over the cartridge until 0xFF50 is written
+    boot_rom: Option<Vec<u8>>,
+    // KEY0, only writable by the CGB boot ROM
+    key0: u8,
+    // the SNES side of a Super Game Boy, listening on JOYP
+    pub sgb: Option<Sgb>,
 }
 
 impl MemoryBus {
-    fn read_byte(&self, address: u16) -> u8 {
-        self.memory[address as usize]
+    // the bus is built for the model the PPU was made for
+    pub fn new(cartridge: Cartridge, ppu: PPU) -> MemoryBus {
+        let model = ppu.model();
+        let sgb = model.is_sgb().then(|| Sgb::new(cartridge.supports_sgb()));
+        MemoryBus {
+            model,
+            memory: [0; 0x10000],
+            cartridge,
+            ppu,
+            apu: APU::with_model(model),
+            timer: Timer::new(),
+            serial: SerialPort::new(),
+            ppu_access_restrictions: true,
+            oam_dma: None,
+            oam_dma_pending: None,
+            oam_dma_byte: 0xFF,
+            dma_cycles: 0,
+            cgb_mode: false,
+            wram: [0; WRAM_BANK_SIZE * WRAM_BANKS],
+            svbk: 0,
+            speed_switch_armed: false,
+            double_speed: false,
+            undocumented: [0; 4],
+            rp: 0,
+            vram_dma: VramDma {
+                source: 0,
+                destination: 0,
+                remaining: 0x7F,
+                hblank_active: false,
+                block_due: false,
+                stall: 0,
+            },
+            boot_rom: None,
+            key0: 0,
+            sgb,
+        }
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+        self.ppu.set_cgb_mode(cgb_mode);
+        self.serial.set_cgb_mode(cgb_mode);
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    // map a boot ROM over the cartridge, which runs from the power-on state
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<u8>) -> Result<(), String> {
+        let expected = if self.model.is_cgb() { CGB_BOOT_ROM_SIZE } else { BOOT_ROM_SIZE };
+        if boot_rom.len() != expected {
+            return Err(format!("a {} boot ROM is {} bytes, got {}", self.model.name(), expected, boot_rom.len()));
+        }
+        self.boot_rom = Some(boot_rom);
+        Ok(())
+    }
+
+    // put the I/O registers where the model's boot ROM leaves them when it jumps to 0x0100. CGB mode has to
+    // be set first, the boot ROM takes a different path for DMG games.
+    pub fn skip_boot(&mut self) {
+        self.ppu.skip_boot();
+        self.timer.set_counter(self.post_boot_counter());
+        // the VBlank interrupt of the frame the boot ROM finished in is still pending
+        self.memory[0xFF0F] = VBLANK_INTERRUPT;
+        self.memory[0xFF46] = if self.model.is_cgb() { 0x00 } else { 0xFF };
+        if self.model.is_cgb() {
+            // SC reads 0x7F on CGB, in DMG mode the fast clock bit just isn't kept
+            self.serial.write_register(0xFF02, 0x03);
+        }
+        self.write(0xFF26, 0x80);
+        self.write(0xFF11, 0x80);
+        if self.model.is_sgb() {
+            // the SGB boot ROM doesn't play the chime, channel 1 stays off
+            self.write(0xFF12, 0xF3);
+        } else {
+            // channel 1 is left running after the chime, silent with its volume decayed to 0
+            self.write(0xFF12, 0x08);
+            self.write(0xFF13, 0xC1);
+            self.write(0xFF14, 0x87);
+            self.write(0xFF12, 0xF3);
+        }
+        self.write(0xFF24, 0x77);
+        self.write(0xFF25, 0xF3);
+    }
+
+    // the internal counter behind DIV at 0x0100. The CGB boot ROM spends longer on DMG games picking a
+    // palette, and the AGB one runs one more instruction. The SGB's depends on how long the SNES takes to
+    // answer, so there is no single right value and the DMG one is used.
+    fn post_boot_counter(&self) -> u16 {
+        match self.model {
+            Model::DMG0 => 0x1830,
+            Model::DMG | Model::MGB | Model::SGB | Model::SGB2 => 0xABCC,
+            Model::CGB if self.cgb_mode => 0x1EA0,
+            Model::CGB => 0x267C,
+            Model::AGB if self.cgb_mode => 0x1EA4,
+            Model::AGB => 0x2680,
+        }
+    }
+
+    pub fn is_boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    fn boot_rom_byte(&self, address: u16) -> Option<u8> {
+        let boot_rom = self.boot_rom.as_ref()?;
+        let address = address as usize;
+        if address < BOOT_ROM_SIZE || (0x200..boot_rom.len()).contains(&address) {
+            Some(boot_rom[address])
+        } else {
+            None
+        }
+    }
+
+    // writing bit 0 of 0xFF50 hands the low addresses back to the cartridge for good. If the CGB boot ROM
+    // picked DMG mode by then, the compatibility palette it loaded is what the game gets to see.
+    fn unmap_boot_rom(&mut self) {
+        if self.boot_rom.take().is_none() {
+            return;
+        }
+        if self.model.is_cgb() && self.key0 & KEY0_DMG_MODE != 0 {
+            let palette = self.ppu.palette_ram_colors();
+            self.set_cgb_mode(false);
+            self.ppu.set_dmg_palette(palette);
+        }
+    }
+
+    pub fn is_cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // STOP with KEY1 armed switches the CPU speed, returns whether it did
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb_mode || !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        // the divider is reset by STOP
+        self.write(0xFF04, 0);
+        true
     }
 
-    fn write(&mut self, address: u16, value: u8) {
-        self.memory[address as usize] = value;
+    // offset into work RAM for 0xC000-0xDFFF, the upper half is banked by SVBK in CGB mode
+    fn wram_offset(&self, address: u16) -> usize {
+        let offset = address as usize & (WRAM_BANK_SIZE - 1);
+        if address < 0xD000 {
+            return offset;
+        }
+        let bank = if self.cgb_mode { (self.svbk as usize & 0x07).max(1) } else { 1 };
+        bank * WRAM_BANK_SIZE + offset
     }
-}
\ No newline at end of file
+
+    pub fn set_ppu_access_restrictions(&mut self, enabled: bool) {
+        self.ppu_access_restrictions = enabled;
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8 {
+        if self.oam_dma_conflict(address) {
+            // OAM itself reads 0xFF, the shared bus returns whatever the DMA is moving
+            return if (0xFE00..=0xFEFF).contains(&address) { 0xFF } else { self.oam_dma_byte };
+        }
+        match address {
+            0x0000..=0x08FF if self.boot_rom.is_some() => {
+                self.boot_rom_byte(address).unwrap_or_else(|| self.cartridge.read_rom(address))
+            }
+            0x0000..=0x7FFF => self.cartridge.read_rom(address),
+            0x8000..=0x9FFF => {
+                if self.vram_accessible() { self.ppu.read_vram(address) } else { 0xFF }
+            }
+            0xA000..=0xBFFF => self.cartridge.read_ram(address),
+            0xC000..=0xDFFF => self.wram[self.wram_offset(address)],
+            // echo of work RAM
+            0xE000..=0xFDFF => self.wram[self.wram_offset(address - 0x2000)],
+            0xFE00..=0xFE9F => {
+                if self.oam_accessible() { self.ppu.read_oam(address) } else { 0xFF }
+            }
+            0xFEA0..=0xFEFF => 0x00,
+            // no buttons are wired up yet, so every selected line reads as released. With neither selected an
+            // SGB puts the current controller there.
+            0xFF00 => {
+                let lines = self.memory[address as usize] & 0x30;
+                let low = match self.sgb.as_ref() {
+                    Some(sgb) if lines == 0x30 => sgb.joypad_id(),
+                    _ => 0x0F,
+                };
+                0xC0 | lines | low
+            }
+            0xFF01 | 0xFF02 => self.serial.read_register(address),
+            0xFF0F => self.memory[address as usize] | 0xE0,
+            0xFF04..=0xFF07 => self.timer.read_register(address),
+            0xFF10..=0xFF3F => self.apu.read_register(address),
+            0xFF46 => self.memory[address as usize],
+            0xFF50 => 0xFF,
+            0xFF40..=0xFF4B | 0xFF4F => self.ppu.read_register(address),
+            0xFF4C..=0xFF7F if !self.cgb_mode => 0xFF,
+            // the HDMA addresses are write only
+            0xFF51..=0xFF54 => 0xFF,
+            0xFF68..=0xFF6C => self.ppu.read_register(address),
+            0xFF4D => 0x7E | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8,
+            // bit 1 is the receiver, 1 means no light is seen
+            // bit 7 is clear while an HBlank DMA is running, the rest counts the blocks left minus one
+            0xFF55 => (!self.vram_dma.hblank_active as u8) << 7 | self.vram_dma.remaining,
+            0xFF56 => 0x3C | (self.rp & 0xC1) | 0x02,
+            0xFF70 => 0xF8 | self.svbk,
+            0xFF72..=0xFF74 => self.undocumented[address as usize - 0xFF72],
+            0xFF75 => 0x8F | self.undocumented[3],
+            0xFF76 | 0xFF77 => self.apu.read_pcm(address),
+            _ => self.memory[address as usize],
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if self.oam_dma_conflict(address) {
+            return;
+        }
+        match address {
+            0x0000..=0x7FFF => self.cartridge.write_rom(address, value),
+            // writes the PPU doesn't let through are dropped
+            0x8000..=0x9FFF => {
+                if self.vram_accessible() {
+                    self.ppu.write_vram(address, value);
+                }
+            }
+            0xA000..=0xBFFF => self.cartridge.write_ram(address, value),
+            0xC000..=0xDFFF => self.wram[self.wram_offset(address)] = value,
+            0xE000..=0xFDFF => self.wram[self.wram_offset(address - 0x2000)] = value,
+            0xFE00..=0xFE9F => {
+                if self.oam_accessible() {
+                    self.ppu.write_oam(address, value);
+                }
+            }
+            0xFEA0..=0xFEFF => {}
+            0xFF00 => {
+                self.memory[address as usize] = value;
+                if let Some(sgb) = self.sgb.as_mut() {
+                    sgb.write_joypad(value);
+                }
+            }
+            0xFF01 | 0xFF02 => self.serial.write_register(address, value),
+            0xFF04..=0xFF07 => {
+                let counter = self.timer.counter();
+                self.timer.write_register(address, value);
+                self.memory[0xFF0F] |= self.clock_div_edges(counter);
+            }
+            0xFF10..=0xFF3F => self.apu.write_register(address, value),
+            0xFF46 => {
+                self.memory[address as usize] = value;
+                self.start_oam_dma(value);
+            }
+            0xFF50 => {
+                if value & 0x01 != 0 {
+                    self.unmap_boot_rom();
+                }
+            }
+            0xFF40..=0xFF4B | 0xFF4F => self.ppu.write_register(address, value),
+            0xFF4C..=0xFF7F if !self.cgb_mode => {}
+            0xFF4C => {
+                if self.boot_rom.is_some() {
+                    self.key0 = value;
+                }
+            }
+            0xFF68..=0xFF6C => self.ppu.write_register(address, value),
+            0xFF4D => self.speed_switch_armed = value & 0x01 != 0,
+            0xFF51 => self.vram_dma.source = (value as u16) << 8 | (self.vram_dma.source & 0x00FF),
+            0xFF52 => self.vram_dma.source = (self.vram_dma.source & 0xFF00) | (value & 0xF0) as u16,
+            0xFF53 => self.vram_dma.destination = ((value & 0x1F) as u16) << 8 | (self.vram_dma.destination & 0x00FF),
+            0xFF54 => self.vram_dma.destination = (self.vram_dma.destination & 0x1F00) | (value & 0xF0) as u16,
+            0xFF55 => self.start_vram_dma(value),
+            0xFF56 => self.rp = value & 0xC1,
+            0xFF70 => self.svbk = value & 0x07,
+            0xFF72..=0xFF74 => self.undocumented[address as usize - 0xFF72] = value,
+            0xFF75 => self.undocumented[3] = value & 0x70,
+            // PCM12/PCM34 are read only
+            0xFF76 | 0xFF77 => {}
+            _ => self.memory[address as usize] = value,
+        }
+    }
+
+    // advance the hardware on the bus by a number of T-cycles and latch the requested interrupts into IF
+    // the CPU, timer, serial port and OAM DMA run twice as fast in double speed mode, the PPU and APU don't
+    pub fn step(&mut self, cycles: u32) {
+        let dots = if self.double_speed { cycles / 2 } else { cycles };
+        let was_hblank = self.ppu.mode() == Mode::HBlank;
+        let mut interrupts = self.ppu.step(dots);
+        if interrupts & VBLANK_INTERRUPT != 0 {
+            if let Some(sgb) = self.sgb.as_mut() {
+                sgb.finish_frame(self.ppu.frame_buffer());
+            }
+        }
+        if self.vram_dma.hblank_active {
+            // a block is copied at the start of every HBlank, one that the halted CPU sleeps through is skipped
+            let hblank = self.ppu.mode() == Mode::HBlank;
+            self.vram_dma.block_due = hblank && (self.vram_dma.block_due || !was_hblank);
+        }
+        for _ in 0..cycles {
+            let counter = self.timer.counter();
+            interrupts |= self.timer.tick();
+            interrupts |= self.serial.tick();
+            interrupts |= self.clock_div_edges(counter);
+        }
+        self.apu.step(dots);
+        self.memory[0xFF0F] |= interrupts;
+
+        self.dma_cycles += cycles;
+        while self.dma_cycles >= 4 {
+            self.dma_cycles -= 4;
+            self.oam_dma_tick();
+        }
+    }
+
+    // copy the block due for the current HBlank if the CPU is awake to give up the bus, then hand over the
+    // T-cycles the CPU has to wait for VRAM DMA copies. The bus has to be stepped through them.
+    pub fn take_vram_dma_stall(&mut self, halted: bool) -> u32 {
+        if self.vram_dma.block_due && !halted {
+            self.vram_dma.block_due = false;
+            self.copy_vram_dma_block();
+            if self.vram_dma.remaining == 0x7F {
+                self.vram_dma.hblank_active = false;
+            }
+        }
+        std::mem::take(&mut self.vram_dma.stall)
+    }
+
+    // HDMA5 write, bit 7 picks HBlank DMA over general purpose DMA
+    fn start_vram_dma(&mut self, value: u8) {
+        let length = value & 0x7F;
+        if self.vram_dma.hblank_active && value & 0x80 == 0 {
+            // clearing bit 7 stops a running HBlank DMA, HDMA5 keeps the count of blocks left
+            self.vram_dma.hblank_active = false;
+            self.vram_dma.block_due = false;
+            return;
+        }
+        self.vram_dma.remaining = length;
+        if value & 0x80 != 0 {
+            self.vram_dma.hblank_active = true;
+            // starting during HBlank (or with the LCD off) copies the first block right away
+            self.vram_dma.block_due = self.ppu.mode() == Mode::HBlank;
+            return;
+        }
+        for _ in 0..=length {
+            self.copy_vram_dma_block();
+        }
+    }
+
+    fn copy_vram_dma_block(&mut self) {
+        let dma = &self.vram_dma;
+        let (source, destination) = (dma.source, dma.destination);
+        for offset in 0..VRAM_DMA_BLOCK_SIZE {
+            let value = self.vram_dma_source_read(source.wrapping_add(offset));
+            // the copy goes into the selected VRAM bank no matter what the PPU is doing
+            self.ppu.write_vram(0x8000 | ((destination + offset) & 0x1FFF), value);
+        }
+        let dma = &mut self.vram_dma;
+        dma.source = source.wrapping_add(VRAM_DMA_BLOCK_SIZE);
+        dma.destination = (destination + VRAM_DMA_BLOCK_SIZE) & 0x1FF0;
+        dma.remaining = dma.remaining.wrapping_sub(1) & 0x7F;
+        // the copy runs at the same speed in double speed mode, which is twice the CPU cycles
+        dma.stall += if self.double_speed { VRAM_DMA_BLOCK_DOTS * 2 } else { VRAM_DMA_BLOCK_DOTS };
+    }
+
+    // VRAM itself can't be a source, 0xE000 and up reads external RAM
+    fn vram_dma_source_read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x7FFF => self.cartridge.read_rom(address),
+            0x8000..=0x9FFF => 0xFF,
+            0xA000..=0xBFFF => self.cartridge.read_ram(address),
+            0xC000..=0xDFFF => self.wram[self.wram_offset(address)],
+            _ => self.cartridge.read_ram(address - 0x4000),
+        }
+    }
+
+    // the APU frame sequencer runs off the falling edge of DIV bit 4 (bit 5 in double speed) and the
+    // serial clock off lower counter bits, including resets by writing DIV
+    fn clock_div_edges(&mut self, old_counter: u16) -> u8 {
+        let counter = self.timer.counter();
+        let apu_bit = if self.double_speed { 0x2000 } else { 0x1000 };
+        if old_counter & apu_bit != 0 && counter & apu_bit == 0 {
+            self.apu.clock_frame_sequencer();
+        }
+        self.serial.clock(old_counter, counter)
+    }
+
+    fn start_oam_dma(&mut self, value: u8) {
+        // writing again while a transfer runs restarts it, the old one continues until the new one starts
+        self.oam_dma_pending = Some(OamDma {
+            source: (value as u16) << 8,
+            index: 0,
+            delay: OAM_DMA_STARTUP_DELAY,
+        });
+    }
+
+    // one M-cycle of OAM DMA, copies a single byte
+    fn oam_dma_tick(&mut self) {
+        if let Some(pending) = self.oam_dma_pending.as_mut() {
+            pending.delay -= 1;
+            if pending.delay == 0 {
+                self.oam_dma = self.oam_dma_pending.take();
+            }
+        }
+
+        let mut dma = match self.oam_dma {
+            Some(dma) => dma,
+            None => return,
+        };
+        let value = self.oam_dma_source_read(dma.source + dma.index);
+        self.ppu.write_oam(0xFE00 + dma.index, value);
+        self.oam_dma_byte = value;
+        dma.index += 1;
+        self.oam_dma = if dma.index < OAM_DMA_LENGTH { Some(dma) } else { None };
+    }
+
+    // the DMA reads memory directly, sources above 0xDFFF land in the echo of work RAM
+    fn oam_dma_source_read(&self, address: u16) -> u8 {
+        let address = if address >= 0xE000 { address - 0x2000 } else { address };
+        match address {
+            0x0000..=0x7FFF => self.cartridge.read_rom(address),
+            0x8000..=0x9FFF => self.ppu.read_vram(address),
+            0xA000..=0xBFFF => self.cartridge.read_ram(address),
+            _ => self.wram[self.wram_offset(address)],
+        }
+    }
+
+    // while OAM DMA runs the CPU can't use OAM or whichever bus (external or video) the DMA reads from,
+    // only I/O and HRAM stay reliable
+    fn oam_dma_conflict(&self, address: u16) -> bool {
+        let dma = match self.oam_dma {
+            Some(dma) => dma,
+            None => return false,
+        };
+        match address {
+            0xFE00..=0xFEFF => true,
+            0xFF00..=0xFFFF => false,
+            _ => is_video_bus(address) == is_video_bus(dma.source),
+        }
+    }
+
+    fn vram_accessible(&self) -> bool {
+        !self.ppu_access_restrictions || self.ppu.mode() != Mode::Drawing
+    }
+
+    fn oam_accessible(&self) -> bool {
+        !self.ppu_access_restrictions || !matches!(self.ppu.mode(), Mode::OamScan | Mode::Drawing)
+    }
+}
+
+fn is_video_bus(address: u16) -> bool {
+    (0x8000..=0x9FFF).contains(&address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::MBC;
+    use crate::ppu::Renderer;
+
+    fn bus(model: Model) -> MemoryBus {
+        let cartridge = Cartridge::with_mbc(vec![0; 0x8000], MBC::None, 0x2000);
+        MemoryBus::new(cartridge, PPU::with_model(model, Renderer::Scanline))
+    }
+
+    // turn the LCD on and run until the PPU gets to the mode, a line takes 456 dots
+    fn run_to_mode(bus: &mut MemoryBus, mode: Mode) {
+        bus.write(0xFF40, 0x91);
+        for _ in 0..456 * 2 {
+            if bus.ppu.mode() == mode {
+                return;
+            }
+            bus.step(1);
+        }
+        panic!("the PPU never got to {:?}", mode);
+    }
+
+    #[test]
+    fn vram_is_locked_out_while_drawing() {
+        let mut bus = bus(Model::DMG);
+        bus.write(0x8000, 0x12);
+        run_to_mode(&mut bus, Mode::Drawing);
+        assert_eq!(bus.read_byte(0x8000), 0xFF);
+        bus.write(0x8000, 0x34);
+        run_to_mode(&mut bus, Mode::HBlank);
+        assert_eq!(bus.read_byte(0x8000), 0x12);
+    }
+
+    #[test]
+    fn oam_is_locked_out_during_oam_scan_and_drawing() {
+        let mut bus = bus(Model::DMG);
+        bus.write(0xFE00, 0x12);
+        run_to_mode(&mut bus, Mode::OamScan);
+        assert_eq!(bus.read_byte(0xFE00), 0xFF);
+        bus.write(0xFE00, 0x34);
+        run_to_mode(&mut bus, Mode::Drawing);
+        assert_eq!(bus.read_byte(0xFE00), 0xFF);
+        bus.write(0xFE00, 0x56);
+        run_to_mode(&mut bus, Mode::HBlank);
+        assert_eq!(bus.read_byte(0xFE00), 0x12);
+        // VRAM stays open during OAM scan
+        run_to_mode(&mut bus, Mode::OamScan);
+        bus.write(0x8000, 0x78);
+        assert_eq!(bus.read_byte(0x8000), 0x78);
+    }
+
+    #[test]
+    fn lockout_can_be_switched_off() {
+        let mut bus = bus(Model::DMG);
+        bus.set_ppu_access_restrictions(false);
+        run_to_mode(&mut bus, Mode::Drawing);
+        bus.write(0x8000, 0x12);
+        bus.write(0xFE00, 0x34);
+        assert_eq!(bus.read_byte(0x8000), 0x12);
+        assert_eq!(bus.read_byte(0xFE00), 0x34);
+    }
+
+    // fill 0xC000-0xC09F with a pattern and start a DMA from there, the LCD stays off
+    fn start_wram_dma(bus: &mut MemoryBus) {
+        for index in 0..0xA0 {
+            bus.write(0xC000 + index, index as u8 ^ 0x5A);
+        }
+        bus.write(0xFF80, 0x99);
+        bus.write(0xFF46, 0xC0);
+    }
+
+    #[test]
+    fn oam_dma_waits_out_its_startup_delay() {
+        let mut bus = bus(Model::DMG);
+        start_wram_dma(&mut bus);
+        bus.step(4);
+        assert_eq!(bus.read_byte(0xC010), 0x10 ^ 0x5A);
+        bus.step(4);
+        // the first byte has been copied and the CPU now sees it on the external bus
+        assert_eq!(bus.read_byte(0xC010), 0x5A);
+    }
+
+    #[test]
+    fn oam_dma_takes_over_the_bus_it_reads_from() {
+        let mut bus = bus(Model::DMG);
+        bus.write(0x8000, 0x77);
+        start_wram_dma(&mut bus);
+        bus.step(4 * 4);
+        // the first byte is copied in the last M-cycle of the startup delay, so the third one is the latest
+        assert_eq!(bus.read_byte(0xC010), 0x02 ^ 0x5A);
+        assert_eq!(bus.read_byte(0x0100), 0x02 ^ 0x5A);
+        assert_eq!(bus.read_byte(0xFE00), 0xFF);
+        // the video bus, I/O and HRAM are left alone
+        assert_eq!(bus.read_byte(0x8000), 0x77);
+        assert_eq!(bus.read_byte(0xFF80), 0x99);
+        assert_eq!(bus.read_byte(0xFF46), 0xC0);
+        // writes to the busy bus are dropped
+        bus.write(0xC0A0, 0x11);
+        bus.step(4 * OAM_DMA_LENGTH as u32);
+        assert_eq!(bus.read_byte(0xC0A0), 0x00);
+        assert_eq!(bus.read_byte(0xFE00), 0x5A);
+        assert_eq!(bus.read_byte(0xFE9F), 0x9F ^ 0x5A);
+    }
+
+    #[test]
+    fn oam_dma_from_vram_leaves_the_external_bus_free() {
+        let mut bus = bus(Model::DMG);
+        bus.write(0xC000, 0x12);
+        bus.write(0x8000, 0x34);
+        bus.write(0xFF46, 0x80);
+        bus.step(4 * 2);
+        assert_eq!(bus.read_byte(0xC000), 0x12);
+        assert_eq!(bus.read_byte(0x9000), 0x34);
+    }
+
+    fn cgb_bus() -> MemoryBus {
+        let mut bus = bus(Model::CGB);
+        bus.set_cgb_mode(true);
+        bus
+    }
+
+    #[test]
+    fn key1_arms_a_speed_switch_for_stop() {
+        let mut bus = cgb_bus();
+        assert_eq!(bus.read_byte(0xFF4D), 0x7E);
+        assert!(!bus.switch_speed());
+        bus.write(0xFF4D, 0x01);
+        assert_eq!(bus.read_byte(0xFF4D), 0x7F);
+        bus.step(0x100);
+        assert!(bus.switch_speed());
+        assert!(bus.is_double_speed());
+        assert_eq!(bus.read_byte(0xFF4D), 0xFE);
+        // STOP resets DIV
+        assert_eq!(bus.read_byte(0xFF04), 0x00);
+        assert!(!bus.switch_speed());
+        bus.write(0xFF4D, 0x01);
+        assert!(bus.switch_speed());
+        assert_eq!(bus.read_byte(0xFF4D), 0x7E);
+    }
+
+    #[test]
+    fn key1_is_not_there_outside_cgb_mode() {
+        let mut bus = bus(Model::CGB);
+        bus.write(0xFF4D, 0x01);
+        assert_eq!(bus.read_byte(0xFF4D), 0xFF);
+        assert!(!bus.switch_speed());
+    }
+
+    #[test]
+    fn svbk_banks_the_upper_half_of_work_ram() {
+        let mut bus = cgb_bus();
+        for bank in 0..8 {
+            bus.write(0xFF70, bank);
+            bus.write(0xD000, 0x10 + bank);
+            assert_eq!(bus.read_byte(0xFF70), 0xF8 | bank);
+        }
+        bus.write(0xFF70, 0x00);
+        // bank 0 maps bank 1, so the write to bank 1 replaced it
+        assert_eq!(bus.read_byte(0xD000), 0x11);
+        bus.write(0xFF70, 0x07);
+        assert_eq!(bus.read_byte(0xD000), 0x17);
+        assert_eq!(bus.read_byte(0xF000), 0x17);
+        // only three bits are kept
+        bus.write(0xFF70, 0xFA);
+        assert_eq!(bus.read_byte(0xD000), 0x12);
+        // the lower half isn't banked
+        bus.write(0xC000, 0x55);
+        bus.write(0xFF70, 0x03);
+        assert_eq!(bus.read_byte(0xC000), 0x55);
+    }
+
+    #[test]
+    fn svbk_is_ignored_outside_cgb_mode() {
+        let mut bus = bus(Model::CGB);
+        bus.write(0xD000, 0x11);
+        bus.write(0xFF70, 0x02);
+        assert_eq!(bus.read_byte(0xFF70), 0xFF);
+        assert_eq!(bus.read_byte(0xD000), 0x11);
+    }
+
+    #[test]
+    fn vbk_switches_between_the_two_vram_banks() {
+        let mut bus = cgb_bus();
+        bus.write(0x8000, 0x12);
+        bus.write(0xFF4F, 0x01);
+        assert_eq!(bus.read_byte(0xFF4F), 0xFF);
+        assert_eq!(bus.read_byte(0x8000), 0x00);
+        bus.write(0x8000, 0x34);
+        bus.write(0xFF4F, 0xFE);
+        assert_eq!(bus.read_byte(0xFF4F), 0xFE);
+        assert_eq!(bus.read_byte(0x8000), 0x12);
+    }
+
+    #[test]
+    fn vbk_is_ignored_outside_cgb_mode() {
+        let mut bus = bus(Model::CGB);
+        bus.write(0xFF4F, 0x01);
+        assert_eq!(bus.read_byte(0xFF4F), 0xFF);
+        bus.write(0x8000, 0x56);
+        bus.set_cgb_mode(true);
+        assert_eq!(bus.read_byte(0x8000), 0x56);
+    }
+
+    // HDMA1-4 for a copy from 0xC000, filled with a pattern, to the start of VRAM
+    fn setup_vram_dma(bus: &mut MemoryBus) {
+        for offset in 0..0x100 {
+            bus.write(0xC000 + offset, offset as u8);
+        }
+        for (address, value) in [(0xFF51, 0xC0), (0xFF52, 0x00), (0xFF53, 0x00), (0xFF54, 0x00)] {
+            bus.write(address, value);
+        }
+    }
+
+    #[test]
+    fn general_purpose_dma_stalls_32_dots_a_block() {
+        let mut bus = cgb_bus();
+        setup_vram_dma(&mut bus);
+        bus.write(0xFF55, 0x03);
+        assert_eq!(bus.take_vram_dma_stall(false), 4 * 32);
+        assert_eq!(bus.take_vram_dma_stall(false), 0);
+        assert_eq!(bus.read_byte(0xFF55), 0xFF);
+        assert_eq!(bus.read_byte(0x803F), 0x3F);
+        assert_eq!(bus.read_byte(0x8040), 0x00);
+    }
+
+    #[test]
+    fn general_purpose_dma_takes_twice_the_cpu_cycles_in_double_speed() {
+        let mut bus = cgb_bus();
+        bus.write(0xFF4D, 0x01);
+        bus.switch_speed();
+        setup_vram_dma(&mut bus);
+        bus.write(0xFF55, 0x01);
+        assert_eq!(bus.take_vram_dma_stall(false), 2 * 64);
+    }
+
+    #[test]
+    fn hblank_dma_copies_one_block_per_hblank() {
+        let mut bus = cgb_bus();
+        setup_vram_dma(&mut bus);
+        run_to_mode(&mut bus, Mode::OamScan);
+        bus.write(0xFF55, 0x81);
+        assert_eq!(bus.read_byte(0xFF55), 0x01);
+        assert_eq!(bus.take_vram_dma_stall(false), 0);
+        run_to_mode(&mut bus, Mode::HBlank);
+        assert_eq!(bus.take_vram_dma_stall(false), 32);
+        assert_eq!(bus.take_vram_dma_stall(false), 0);
+        assert_eq!(bus.read_byte(0xFF55), 0x00);
+        assert_eq!(bus.read_byte(0x800F), 0x0F);
+        // the CPU sleeping through an HBlank misses its block
+        run_to_mode(&mut bus, Mode::OamScan);
+        run_to_mode(&mut bus, Mode::HBlank);
+        assert_eq!(bus.take_vram_dma_stall(true), 0);
+        run_to_mode(&mut bus, Mode::OamScan);
+        run_to_mode(&mut bus, Mode::HBlank);
+        assert_eq!(bus.take_vram_dma_stall(false), 32);
+        assert_eq!(bus.read_byte(0xFF55), 0xFF);
+        assert_eq!(bus.read_byte(0x801F), 0x1F);
+    }
+
+    #[test]
+    fn clearing_bit_7_stops_an_hblank_dma() {
+        let mut bus = cgb_bus();
+        setup_vram_dma(&mut bus);
+        run_to_mode(&mut bus, Mode::OamScan);
+        bus.write(0xFF55, 0x83);
+        bus.write(0xFF55, 0x00);
+        assert_eq!(bus.read_byte(0xFF55), 0x83);
+        run_to_mode(&mut bus, Mode::HBlank);
+        assert_eq!(bus.take_vram_dma_stall(false), 0);
+    }
+
+    #[test]
+    fn a_boot_rom_has_to_match_the_model() {
+        for model in [Model::DMG, Model::CGB] {
+            let mut bus = bus(model);
+            let (size, other_size) =
+                if model.is_cgb() { (CGB_BOOT_ROM_SIZE, BOOT_ROM_SIZE) } else { (BOOT_ROM_SIZE, CGB_BOOT_ROM_SIZE) };
+            assert!(bus.load_boot_rom(vec![0; other_size]).is_err());
+            assert!(!bus.is_boot_rom_mapped());
+            assert!(bus.load_boot_rom(vec![0; size]).is_ok());
+        }
+    }
+
+    #[test]
+    fn the_boot_rom_is_mapped_until_ff50_is_written() {
+        let mut bus = bus(Model::DMG);
+        bus.load_boot_rom(vec![0x31; BOOT_ROM_SIZE]).unwrap();
+        assert!(bus.is_boot_rom_mapped());
+        assert_eq!(bus.read_byte(0x0000), 0x31);
+        assert_eq!(bus.read_byte(0x00FF), 0x31);
+        assert_eq!(bus.read_byte(0x0100), 0x00);
+        assert_eq!(bus.read_byte(0xFF50), 0xFF);
+        // only bit 0 unmaps it
+        bus.write(0xFF50, 0xFE);
+        assert_eq!(bus.read_byte(0x0000), 0x31);
+        bus.write(0xFF50, 0x01);
+        assert!(!bus.is_boot_rom_mapped());
+        assert_eq!(bus.read_byte(0x0000), 0x00);
+        // and there is no mapping it back
+        bus.write(0xFF50, 0x00);
+        assert_eq!(bus.read_byte(0x0000), 0x00);
+    }
+
+    #[test]
+    fn the_cgb_boot_rom_leaves_the_cartridge_header_visible() {
+        let mut bus = bus(Model::CGB);
+        bus.set_cgb_mode(true);
+        bus.load_boot_rom(vec![0x31; CGB_BOOT_ROM_SIZE]).unwrap();
+        assert_eq!(bus.read_byte(0x00FF), 0x31);
+        assert_eq!(bus.read_byte(0x0100), 0x00);
+        assert_eq!(bus.read_byte(0x01FF), 0x00);
+        assert_eq!(bus.read_byte(0x0200), 0x31);
+        assert_eq!(bus.read_byte(0x08FF), 0x31);
+        assert_eq!(bus.read_byte(0x0900), 0x00);
+        bus.write(0xFF50, 0x01);
+        assert_eq!(bus.read_byte(0x0200), 0x00);
+    }
+
+    #[test]
+    fn key0_picks_dmg_mode_when_the_cgb_boot_rom_unmaps() {
+        let mut bus = bus(Model::CGB);
+        bus.set_cgb_mode(true);
+        bus.load_boot_rom(vec![0; CGB_BOOT_ROM_SIZE]).unwrap();
+        bus.write(0xFF4C, KEY0_DMG_MODE);
+        assert!(bus.is_cgb_mode());
+        bus.write(0xFF50, 0x01);
+        assert!(!bus.is_cgb_mode());
+
+        // without the boot ROM KEY0 is locked
+        let mut bus = cgb_bus();
+        bus.write(0xFF4C, KEY0_DMG_MODE);
+        bus.write(0xFF50, 0x01);
+        assert!(bus.is_cgb_mode());
+    }
+
+    #[test]
+    fn skip_boot_leaves_the_io_registers_where_each_boot_rom_does() {
+        // DIV, IF, DMA, SC and NR52 for a DMG game
+        let expected = [
+            (Model::DMG0, [0x18, 0xE1, 0xFF, 0x7E, 0xF1]),
+            (Model::DMG, [0xAB, 0xE1, 0xFF, 0x7E, 0xF1]),
+            (Model::MGB, [0xAB, 0xE1, 0xFF, 0x7E, 0xF1]),
+            (Model::SGB, [0xAB, 0xE1, 0xFF, 0x7E, 0xF0]),
+            (Model::SGB2, [0xAB, 0xE1, 0xFF, 0x7E, 0xF0]),
+            (Model::CGB, [0x26, 0xE1, 0x00, 0x7F, 0xF1]),
+            (Model::AGB, [0x26, 0xE1, 0x00, 0x7F, 0xF1]),
+        ];
+        for (model, registers) in expected {
+            let mut bus = bus(model);
+            bus.skip_boot();
+            let read = [0xFF04, 0xFF0F, 0xFF46, 0xFF02, 0xFF26].map(|address| bus.read_byte(address));
+            assert_eq!(read, registers, "{}", model.name());
+        }
+    }
+
+    #[test]
+    fn the_cgb_boot_rom_finishes_sooner_for_cgb_games() {
+        for model in [Model::CGB, Model::AGB] {
+            let mut bus = bus(model);
+            bus.set_cgb_mode(true);
+            bus.skip_boot();
+            assert_eq!(bus.read_byte(0xFF04), 0x1E, "{}", model.name());
+        }
+    }
+}
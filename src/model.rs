@@ -0,0 +1,68 @@
+// Game Boy hardware revisions. They differ in the state the boot ROM leaves behind and in a few quirks
+// that test ROMs check for, so a ROM has to be run on the model it was written for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Model {
+    // the original Game Boy with its first boot ROM
+    DMG0,
+    #[default]
+    DMG,
+    // Game Boy Pocket and Light
+    MGB,
+    SGB,
+    SGB2,
+    CGB,
+    // a Game Boy Advance running Game Boy games
+    AGB,
+}
+
+impl Model {
+    pub const ALL: [Model; 7] = [Model::DMG0, Model::DMG, Model::MGB, Model::SGB, Model::SGB2, Model::CGB, Model::AGB];
+
+    pub fn from_name(name: &str) -> Option<Model> {
+        Model::ALL.iter().copied().find(|model| model.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Model::DMG0 => "DMG0",
+            Model::DMG => "DMG",
+            Model::MGB => "MGB",
+            Model::SGB => "SGB",
+            Model::SGB2 => "SGB2",
+            Model::CGB => "CGB",
+            Model::AGB => "AGB",
+        }
+    }
+
+    // CGB hardware, which runs CGB games in color and colorizes DMG games
+    pub fn is_cgb(self) -> bool {
+        matches!(self, Model::CGB | Model::AGB)
+    }
+
+    pub fn is_sgb(self) -> bool {
+        matches!(self, Model::SGB | Model::SGB2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn models_are_found_by_name_in_any_case() {
+        for model in Model::ALL {
+            assert_eq!(Model::from_name(model.name()), Some(model));
+            assert_eq!(Model::from_name(&model.name().to_ascii_lowercase()), Some(model));
+        }
+        assert_eq!(Model::from_name("GBC"), None);
+        assert_eq!(Model::default(), Model::DMG);
+    }
+
+    #[test]
+    fn only_cgb_and_agb_are_cgb_hardware() {
+        let cgb: Vec<Model> = Model::ALL.into_iter().filter(|model| model.is_cgb()).collect();
+        assert_eq!(cgb, [Model::CGB, Model::AGB]);
+        let sgb: Vec<Model> = Model::ALL.into_iter().filter(|model| model.is_sgb()).collect();
+        assert_eq!(sgb, [Model::SGB, Model::SGB2]);
+    }
+}
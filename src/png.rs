@@ -0,0 +1,74 @@
+use std::io::{self, Write};
+
+// zlib stored blocks can't hold more than this
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+// an 8-bit grayscale PNG, without compression since the images are small
+pub fn write_grayscale<W: Write>(writer: &mut W, width: usize, height: usize, pixels: &[u8]) -> io::Result<()> {
+    assert_eq!(pixels.len(), width * height, "pixel data doesn't match the image size");
+
+    writer.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // bit depth, color type (grayscale), compression, filter and interlace method
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+    write_chunk(writer, b"IHDR", &header)?;
+
+    // every scanline starts with its filter type, 0 is none
+    let mut raw = Vec::with_capacity((width + 1) * height);
+    for row in pixels.chunks(width.max(1)).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(writer, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(writer, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let crc = crc32(crc32(0xFFFF_FFFF, kind), data) ^ 0xFFFF_FFFF;
+    writer.write_all(&crc.to_be_bytes())
+}
+
+// a zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        output.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        output.push(last as u8);
+        let length = block.len() as u16;
+        output.extend_from_slice(&length.to_le_bytes());
+        output.extend_from_slice(&(!length).to_le_bytes());
+        output.extend_from_slice(block);
+    }
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
+
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
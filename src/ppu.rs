@@ -0,0 +1,1075 @@
+mod pixelfifo;
+
+use self::pixelfifo::PixelFifo;
+use crate::compatpalette::CompatibilityPalette;
+use crate::model::Model;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+// interrupt requests returned from step, laid out like the IF register
+pub const VBLANK_INTERRUPT: u8 = 1 << 0;
+pub const STAT_INTERRUPT: u8 = 1 << 1;
+
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+// shortest possible mode 3, without scrolling, window or sprites
+const DRAWING_DOTS: u16 = 172;
+const VBLANK_START_LINE: u8 = 144;
+const LAST_LINE: u8 = 153;
+
+// size of one VRAM bank, CGB has two
+const VRAM_SIZE: usize = 0x2000;
+const OAM_SIZE: usize = 0xA0;
+const SPRITE_COUNT: usize = 40;
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+// LCDC (0xFF40) bits
+const LCDC_BG_ENABLE: u8 = 1 << 0;
+const LCDC_OBJ_ENABLE: u8 = 1 << 1;
+const LCDC_OBJ_SIZE: u8 = 1 << 2;
+const LCDC_BG_TILE_MAP: u8 = 1 << 3;
+const LCDC_TILE_DATA: u8 = 1 << 4;
+const LCDC_WINDOW_ENABLE: u8 = 1 << 5;
+const LCDC_WINDOW_TILE_MAP: u8 = 1 << 6;
+const LCDC_LCD_ENABLE: u8 = 1 << 7;
+
+// STAT (0xFF41) bits
+const STAT_MODE: u8 = 0x03;
+const STAT_COINCIDENCE: u8 = 1 << 2;
+const STAT_HBLANK_SOURCE: u8 = 1 << 3;
+const STAT_VBLANK_SOURCE: u8 = 1 << 4;
+const STAT_OAM_SOURCE: u8 = 1 << 5;
+const STAT_LYC_SOURCE: u8 = 1 << 6;
+
+// sprite attribute bits (byte 3 of an OAM entry)
+const SPRITE_BEHIND_BG: u8 = 1 << 7;
+const SPRITE_Y_FLIP: u8 = 1 << 6;
+const SPRITE_X_FLIP: u8 = 1 << 5;
+const SPRITE_PALETTE: u8 = 1 << 4;
+// CGB sprites pick one of eight palettes and a VRAM bank instead
+const SPRITE_CGB_PALETTE: u8 = 0x07;
+const SPRITE_BANK: u8 = 1 << 3;
+
+// CGB BG map attributes, kept in VRAM bank 1 at the same address as the tile number
+const BG_ATTR_PALETTE: u8 = 0x07;
+const BG_ATTR_BANK: u8 = 1 << 3;
+const BG_ATTR_X_FLIP: u8 = 1 << 5;
+const BG_ATTR_Y_FLIP: u8 = 1 << 6;
+const BG_ATTR_PRIORITY: u8 = 1 << 7;
+
+// 8 palettes of 4 RGB555 colors, two bytes each
+const PALETTE_RAM_SIZE: usize = 64;
+// BCPS/OCPS auto-increment bit
+const PALETTE_AUTO_INCREMENT: u8 = 1 << 7;
+
+// what the LCD shows while it is off, in RGB555
+const WHITE: u16 = 0x7FFF;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+// the scanline renderer draws a whole line at the end of mode 3, the pixel FIFO renderer
+// draws dot by dot like the hardware and picks up register writes made in the middle of a line
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Renderer {
+    Scanline,
+    PixelFifo,
+}
+
+#[derive(Clone, Copy)]
+struct Sprite {
+    y: i16,
+    x: i16,
+    tile: u8,
+    flags: u8,
+    // position in OAM, CGB sprite priority goes by it
+    index: u8,
+}
+
+// a background or window pixel before its palette is applied
+#[derive(Clone, Copy, Default)]
+struct BgPixel {
+    color: u8,
+    // CGB BG map attributes, always 0 on DMG
+    attributes: u8,
+}
+
+pub struct PPU {
+    model: Model,
+    renderer: Renderer,
+    fifo: PixelFifo,
+    vram: [u8; VRAM_SIZE * 2],
+    // VBK, the bank the CPU sees at 0x8000-0x9FFF in CGB mode
+    vram_bank: usize,
+    cgb_mode: bool,
+    oam: [u8; OAM_SIZE],
+    lcdc: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    // the window keeps its own line counter, it only advances on lines where the window was drawn
+    window_line: u8,
+    // set once LY has matched WY during the current frame
+    window_triggered: bool,
+    mode: Mode,
+    // dot within the current line, 0-455
+    dot: u16,
+    // length of mode 3 on the current line, depends on SCX, the window and sprites
+    drawing_dots: u16,
+    // all STAT interrupt sources ORed together, an interrupt is only requested on its rising edge
+    stat_line: bool,
+    // the first frame after the LCD is switched on is not sent to the screen
+    skip_frame: bool,
+    frame_ready: bool,
+    // BCPS/BCPD and OCPS/OCPD palette memory
+    bg_palette_ram: [u8; PALETTE_RAM_SIZE],
+    obj_palette_ram: [u8; PALETTE_RAM_SIZE],
+    bcps: u8,
+    ocps: u8,
+    // OPRI bit 0, 0 lets the OAM position decide sprite priority like CGB games expect
+    opri: u8,
+    // one 2-bit shade (0 = white, 3 = black) per pixel, the raw color number in CGB mode
+    frame_buffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // the same frame as RGB555 (red in the low bits)
+    color_frame_buffer: [u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // colors the DMG shades are shown in, grays unless a CGB colorizes the game
+    dmg_palette: CompatibilityPalette,
+    // a STAT write on pre-CGB models that raised an interrupt, handed out by the next step
+    stat_write_interrupt: bool,
+}
+
+impl PPU {
+    pub fn new() -> PPU {
+        PPU::with_renderer(Renderer::Scanline)
+    }
+
+    pub fn with_renderer(renderer: Renderer) -> PPU {
+        PPU::with_model(Model::default(), renderer)
+    }
+
+    pub fn with_model(model: Model, renderer: Renderer) -> PPU {
+        PPU {
+            model,
+            renderer,
+            fifo: PixelFifo::new(),
+            vram: [0; VRAM_SIZE * 2],
+            vram_bank: 0,
+            cgb_mode: false,
+            oam: [0; OAM_SIZE],
+            // power-on state, the boot ROM switches the LCD on
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            window_line: 0,
+            window_triggered: false,
+            mode: Mode::HBlank,
+            dot: 0,
+            drawing_dots: DRAWING_DOTS,
+            stat_line: false,
+            skip_frame: false,
+            frame_ready: false,
+            // the CGB boot ROM leaves all palettes white
+            bg_palette_ram: [0xFF; PALETTE_RAM_SIZE],
+            obj_palette_ram: [0xFF; PALETTE_RAM_SIZE],
+            bcps: 0,
+            ocps: 0,
+            opri: 0,
+            frame_buffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            color_frame_buffer: [WHITE; SCREEN_WIDTH * SCREEN_HEIGHT],
+            dmg_palette: CompatibilityPalette::GRAYSCALE,
+            stat_write_interrupt: false,
+        }
+    }
+
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.frame_buffer
+    }
+
+    pub fn color_frame_buffer(&self) -> &[u16] {
+        &self.color_frame_buffer
+    }
+
+    // BG palette 0 and OBJ palettes 0 and 1 from palette memory, where the CGB boot ROM leaves the colors
+    // it picked for a DMG game
+    pub fn palette_ram_colors(&self) -> CompatibilityPalette {
+        let palette = |palette_ram: &[u8; PALETTE_RAM_SIZE], palette: u8| -> [u16; 4] {
+            [0, 1, 2, 3].map(|color| palette_color(palette_ram, palette, color))
+        };
+        CompatibilityPalette {
+            bg: palette(&self.bg_palette_ram, 0),
+            obj0: palette(&self.obj_palette_ram, 0),
+            obj1: palette(&self.obj_palette_ram, 1),
+        }
+    }
+
+    // colors for DMG mode output, CGB mode uses the palettes the game writes
+    pub fn set_dmg_palette(&mut self, palette: CompatibilityPalette) {
+        self.dmg_palette = palette;
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    // true once per completed frame, cleared by reading it
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::replace(&mut self.frame_ready, false)
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+        self.vram_bank = 0;
+    }
+
+    pub fn read_vram(&self, address: u16) -> u8 {
+        self.vram[self.vram_bank * VRAM_SIZE + ((address as usize - 0x8000) & (VRAM_SIZE - 1))]
+    }
+
+    pub fn write_vram(&mut self, address: u16, value: u8) {
+        self.vram[self.vram_bank * VRAM_SIZE + ((address as usize - 0x8000) & (VRAM_SIZE - 1))] = value;
+    }
+
+    pub fn read_oam(&self, address: u16) -> u8 {
+        self.oam[address as usize - 0xFE00]
+    }
+
+    pub fn write_oam(&mut self, address: u16, value: u8) {
+        self.oam[address as usize - 0xFE00] = value;
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF40 => self.lcdc,
+            // bit 7 is unused and always reads back as 1
+            0xFF41 => self.stat | 0x80,
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF4F if self.cgb_mode => 0xFE | self.vram_bank as u8,
+            0xFF68 if self.cgb_mode => self.bcps | 0x40,
+            0xFF6A if self.cgb_mode => self.ocps | 0x40,
+            // palette memory can't be reached while the PPU is drawing
+            0xFF69 if self.cgb_mode && self.mode != Mode::Drawing => self.bg_palette_ram[(self.bcps & 0x3F) as usize],
+            0xFF6B if self.cgb_mode && self.mode != Mode::Drawing => self.obj_palette_ram[(self.ocps & 0x3F) as usize],
+            0xFF6C if self.cgb_mode => 0xFE | self.opri,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF40 => self.write_lcdc(value),
+            // the mode and coincidence bits are read only
+            0xFF41 => {
+                self.stat_write_bug();
+                self.stat = (self.stat & 0x07) | (value & 0x78);
+            }
+            0xFF42 => self.scy = value,
+            0xFF43 => self.scx = value,
+            // LY is read only
+            0xFF44 => {}
+            0xFF45 => self.lyc = value,
+            0xFF47 => self.bgp = value,
+            0xFF48 => self.obp0 = value,
+            0xFF49 => self.obp1 = value,
+            0xFF4A => self.wy = value,
+            0xFF4B => self.wx = value,
+            0xFF4F if self.cgb_mode => self.vram_bank = (value & 0x01) as usize,
+            0xFF68 if self.cgb_mode => self.bcps = value & 0xBF,
+            0xFF6A if self.cgb_mode => self.ocps = value & 0xBF,
+            0xFF69 if self.cgb_mode => {
+                let drawing = self.mode == Mode::Drawing;
+                write_palette_ram(&mut self.bg_palette_ram, &mut self.bcps, value, drawing);
+            }
+            0xFF6B if self.cgb_mode => {
+                let drawing = self.mode == Mode::Drawing;
+                write_palette_ram(&mut self.obj_palette_ram, &mut self.ocps, value, drawing);
+            }
+            0xFF6C if self.cgb_mode => self.opri = value & 0x01,
+            _ => {}
+        }
+    }
+
+    // the state the boot ROM leaves behind when it jumps to the cartridge, LCD and background on and partway
+    // into VBlank. DMG0's boot ROM is done earlier in the frame than the later ones, which all finish on line
+    // 153 with LY already reading 0. The dot within the line is approximate.
+    pub fn skip_boot(&mut self) {
+        self.lcdc = 0x91;
+        self.bgp = 0xFC;
+        let (ly, dot) = if self.model == Model::DMG0 { (145, 200) } else { (0, 400) };
+        self.ly = ly;
+        self.dot = dot;
+        self.set_mode(Mode::VBlank);
+        self.skip_frame = false;
+        self.update_stat_line();
+    }
+
+    fn write_lcdc(&mut self, value: u8) {
+        let was_enabled = self.lcdc & LCDC_LCD_ENABLE != 0;
+        let enabled = value & LCDC_LCD_ENABLE != 0;
+        self.lcdc = value;
+        if was_enabled && !enabled {
+            // switching the LCD off resets LY and leaves the PPU idle in mode 0 with a blank screen
+            self.ly = 0;
+            self.dot = 0;
+            self.set_mode(Mode::HBlank);
+            self.stat_line = false;
+            self.frame_buffer.fill(0);
+            self.color_frame_buffer.fill(WHITE);
+        } else if !was_enabled && enabled {
+            // line 0 after switching on skips the OAM scan and reports mode 0 instead
+            self.ly = 0;
+            self.dot = 0;
+            self.start_frame();
+            self.set_mode(Mode::HBlank);
+            self.skip_frame = true;
+            self.update_stat_line();
+        }
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.stat = (self.stat & !STAT_MODE) | mode as u8;
+    }
+
+    // advance the PPU by a number of dots (T-cycles), returns the requested interrupts
+    pub fn step(&mut self, cycles: u32) -> u8 {
+        let mut interrupts = if std::mem::take(&mut self.stat_write_interrupt) { STAT_INTERRUPT } else { 0 };
+        for _ in 0..cycles {
+            interrupts |= self.tick();
+        }
+        interrupts
+    }
+
+    // render all 144 visible lines of the current VRAM/OAM contents in one go, by running the PPU until it
+    // finishes a frame. Interrupts are dropped, with the LCD off there is nothing to render.
+    pub fn render_frame(&mut self) {
+        if self.lcdc & LCDC_LCD_ENABLE == 0 {
+            return;
+        }
+        while !self.take_frame_ready() {
+            self.tick();
+        }
+    }
+
+    fn tick(&mut self) -> u8 {
+        if self.lcdc & LCDC_LCD_ENABLE == 0 {
+            return 0;
+        }
+        let mut interrupts = 0;
+        self.dot += 1;
+
+        if self.mode != Mode::VBlank {
+            if self.dot == OAM_SCAN_DOTS && self.mode != Mode::Drawing {
+                self.start_drawing();
+            } else if self.mode == Mode::Drawing && self.drawing_tick() {
+                self.set_mode(Mode::HBlank);
+            }
+        }
+
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            if self.mode == Mode::VBlank && self.ly == 0 {
+                // end of line 153, LY already wrapped around
+                self.start_frame();
+                self.set_mode(Mode::OamScan);
+            } else {
+                self.ly += 1;
+                if self.ly == VBLANK_START_LINE {
+                    self.set_mode(Mode::VBlank);
+                    interrupts |= VBLANK_INTERRUPT;
+                    if !std::mem::replace(&mut self.skip_frame, false) {
+                        self.frame_ready = true;
+                    }
+                } else if self.ly < VBLANK_START_LINE {
+                    self.set_mode(Mode::OamScan);
+                }
+            }
+        } else if self.ly == LAST_LINE && self.dot == 4 {
+            // LY reads 153 only for a few dots before it already reads 0
+            self.ly = 0;
+        }
+
+        if self.update_stat_line() {
+            interrupts |= STAT_INTERRUPT;
+        }
+        interrupts
+    }
+
+    fn start_drawing(&mut self) {
+        if self.ly == self.wy {
+            self.window_triggered = true;
+        }
+        match self.renderer {
+            Renderer::Scanline => self.drawing_dots = self.drawing_length(),
+            Renderer::PixelFifo => self.start_fifo_line(),
+        }
+        self.set_mode(Mode::Drawing);
+    }
+
+    // one dot of mode 3, returns true once the line is finished
+    fn drawing_tick(&mut self) -> bool {
+        match self.renderer {
+            Renderer::Scanline => {
+                if self.dot == OAM_SCAN_DOTS + self.drawing_dots {
+                    self.render_scanline();
+                    true
+                } else {
+                    false
+                }
+            }
+            Renderer::PixelFifo => self.fifo_tick(),
+        }
+    }
+
+    // before the CGB, writing STAT enables every source for a cycle, so a write outside mode 3 (or while
+    // LY=LYC) raises an interrupt unless the line is already high
+    fn stat_write_bug(&mut self) {
+        if self.model.is_cgb() || self.lcdc & LCDC_LCD_ENABLE == 0 || self.stat_line {
+            return;
+        }
+        if self.mode != Mode::Drawing || self.stat & STAT_COINCIDENCE != 0 {
+            self.stat_write_interrupt = true;
+        }
+    }
+
+    // refresh the LY=LYC flag and the combined STAT interrupt line, returns true on a rising edge
+    fn update_stat_line(&mut self) -> bool {
+        let coincidence = self.ly == self.lyc;
+        if coincidence {
+            self.stat |= STAT_COINCIDENCE;
+        } else {
+            self.stat &= !STAT_COINCIDENCE;
+        }
+
+        let source_enabled = |bit: u8| self.stat & bit != 0;
+        let mode_source = match self.mode {
+            Mode::HBlank => source_enabled(STAT_HBLANK_SOURCE),
+            // entering VBlank also fires the mode 2 source, as line 144 starts like any other line
+            Mode::VBlank => {
+                source_enabled(STAT_VBLANK_SOURCE)
+                    || (self.ly == VBLANK_START_LINE && self.dot == 0 && source_enabled(STAT_OAM_SOURCE))
+            }
+            Mode::OamScan => source_enabled(STAT_OAM_SOURCE),
+            Mode::Drawing => false,
+        };
+        let line = mode_source || (coincidence && source_enabled(STAT_LYC_SOURCE));
+
+        // "STAT blocking" - while any source keeps the line high, other sources can't raise a new interrupt
+        let rising_edge = line && !self.stat_line;
+        self.stat_line = line;
+        rising_edge
+    }
+
+    // mode 3 gets longer by the discarded SCX pixels, the window restart and every sprite fetch
+    fn drawing_length(&self) -> u16 {
+        let mut length = DRAWING_DOTS + (self.scx % 8) as u16;
+        if self.lcdc & LCDC_WINDOW_ENABLE != 0 && self.window_triggered && self.wx <= 166 {
+            length += 6;
+        }
+        if self.lcdc & LCDC_OBJ_ENABLE != 0 {
+            let mut fetched_tiles = Vec::new();
+            for sprite in self.sprites_on_line(self.ly) {
+                length += 6;
+                // the first sprite in a background tile also waits for that tile's fetch to finish
+                let offset = (sprite.x + 8 + self.scx as i16).rem_euclid(256);
+                let tile = offset / 8;
+                if !fetched_tiles.contains(&tile) {
+                    fetched_tiles.push(tile);
+                    length += 5u16.saturating_sub((offset % 8) as u16);
+                }
+            }
+        }
+        length
+    }
+
+    fn start_frame(&mut self) {
+        self.window_line = 0;
+        self.window_triggered = false;
+    }
+
+    // draw line LY into the frame buffer
+    fn render_scanline(&mut self) {
+        let line = self.ly;
+        if line as usize >= SCREEN_HEIGHT {
+            return;
+        }
+        let row_start = line as usize * SCREEN_WIDTH;
+        if self.lcdc & LCDC_LCD_ENABLE == 0 {
+            self.frame_buffer[row_start..row_start + SCREEN_WIDTH].fill(0);
+            self.color_frame_buffer[row_start..row_start + SCREEN_WIDTH].fill(WHITE);
+            return;
+        }
+        // pixels before their palette are kept around for the sprite priority check
+        let mut bg_pixels = [BgPixel::default(); SCREEN_WIDTH];
+        let background = self.background_enabled();
+        if background {
+            self.render_background(line, &mut bg_pixels);
+            self.render_window(&mut bg_pixels);
+        }
+        for (x, &pixel) in bg_pixels.iter().enumerate() {
+            if background {
+                self.output_bg_pixel(row_start + x, pixel);
+            } else {
+                self.output_blank_pixel(row_start + x);
+            }
+        }
+
+        if self.lcdc & LCDC_OBJ_ENABLE != 0 {
+            self.render_sprites(line, &bg_pixels);
+        }
+    }
+
+    // LCDC bit 0 switches the background off on DMG, on CGB it only takes away its priority over sprites
+    fn background_enabled(&self) -> bool {
+        self.cgb_mode || self.lcdc & LCDC_BG_ENABLE != 0
+    }
+
+    fn render_background(&self, line: u8, bg_pixels: &mut [BgPixel; SCREEN_WIDTH]) {
+        let map_base = if self.lcdc & LCDC_BG_TILE_MAP != 0 { 0x1C00 } else { 0x1800 };
+        let y = line.wrapping_add(self.scy);
+        for (x, pixel) in bg_pixels.iter_mut().enumerate() {
+            let map_x = (x as u8).wrapping_add(self.scx);
+            *pixel = self.tile_map_pixel(map_base, map_x, y);
+        }
+    }
+
+    fn render_window(&mut self, bg_pixels: &mut [BgPixel; SCREEN_WIDTH]) {
+        if self.lcdc & LCDC_WINDOW_ENABLE == 0 || !self.window_triggered || self.wx > 166 {
+            return;
+        }
+        let map_base = if self.lcdc & LCDC_WINDOW_TILE_MAP != 0 { 0x1C00 } else { 0x1800 };
+        // WX is the window position plus 7
+        let start = self.wx as i16 - 7;
+        for x in start.max(0)..SCREEN_WIDTH as i16 {
+            let window_x = (x - start) as u8;
+            bg_pixels[x as usize] = self.tile_map_pixel(map_base, window_x, self.window_line);
+        }
+        self.window_line = self.window_line.wrapping_add(1);
+    }
+
+    // the pixel at (x, y) inside a 256x256 tile map
+    fn tile_map_pixel(&self, map_base: usize, x: u8, y: u8) -> BgPixel {
+        let map_index = map_base + (y as usize / 8) * 32 + (x as usize / 8);
+        let attributes = self.bg_attributes(map_index);
+        let tile_address = self.bg_tile_row_address(self.vram[map_index], attributes, y);
+        let column = if attributes & BG_ATTR_X_FLIP != 0 { 7 - x % 8 } else { x % 8 };
+        BgPixel {
+            color: tile_pixel(&self.vram, tile_address, column),
+            attributes,
+        }
+    }
+
+    fn bg_attributes(&self, map_index: usize) -> u8 {
+        if self.cgb_mode { self.vram[VRAM_SIZE + map_index] } else { 0 }
+    }
+
+    // address of the row of a background tile that is shown on map line y, in either VRAM bank
+    fn bg_tile_row_address(&self, tile: u8, attributes: u8, y: u8) -> usize {
+        let row = if attributes & BG_ATTR_Y_FLIP != 0 { 7 - y % 8 } else { y % 8 };
+        let bank = if attributes & BG_ATTR_BANK != 0 { VRAM_SIZE } else { 0 };
+        bank + self.bg_tile_address(tile) + row as usize * 2
+    }
+
+    // background and window tiles are addressed either from 0x8000 with an unsigned index
+    // or from 0x9000 with a signed one, depending on LCDC bit 4
+    fn bg_tile_address(&self, tile: u8) -> usize {
+        if self.lcdc & LCDC_TILE_DATA != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + (tile as i8 as i32) * 16) as usize
+        }
+    }
+
+    fn sprite_height(&self) -> i16 {
+        if self.lcdc & LCDC_OBJ_SIZE != 0 { 16 } else { 8 }
+    }
+
+    // OAM scan - the first 10 sprites in OAM order that overlap the line, sorted into drawing priority
+    fn sprites_on_line(&self, line: u8) -> Vec<Sprite> {
+        let height = self.sprite_height();
+        let mut sprites: Vec<Sprite> = self
+            .oam
+            .chunks_exact(4)
+            .take(SPRITE_COUNT)
+            .enumerate()
+            .map(|(index, entry)| Sprite {
+                y: entry[0] as i16 - 16,
+                x: entry[1] as i16 - 8,
+                tile: entry[2],
+                flags: entry[3],
+                index: index as u8,
+            })
+            .filter(|sprite| (sprite.y..sprite.y + height).contains(&(line as i16)))
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+        // on DMG the sprite with the smaller X wins, ties go to the earlier OAM entry (the sort is stable),
+        // CGB goes by OAM position alone unless OPRI asks for the DMG behavior
+        if !self.oam_priority() {
+            sprites.sort_by_key(|sprite| sprite.x);
+        }
+        sprites
+    }
+
+    fn oam_priority(&self) -> bool {
+        self.cgb_mode && self.opri & 0x01 == 0
+    }
+
+    // address of a sprite's tile row for a line, in either VRAM bank
+    fn sprite_row_address(&self, sprite: &Sprite, line: u8) -> usize {
+        let height = self.sprite_height();
+        let mut row = line as i16 - sprite.y;
+        if sprite.flags & SPRITE_Y_FLIP != 0 {
+            row = height - 1 - row;
+        }
+        let tile = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+        let bank = if self.cgb_mode && sprite.flags & SPRITE_BANK != 0 { VRAM_SIZE } else { 0 };
+        bank + tile as usize * 16 + (row as usize & 0x0F) * 2
+    }
+
+    // whether an opaque sprite pixel is drawn over the background pixel below it
+    fn sprite_over_background(&self, bg: BgPixel, flags: u8) -> bool {
+        if bg.color == 0 {
+            return true;
+        }
+        if self.cgb_mode {
+            self.lcdc & LCDC_BG_ENABLE == 0 || (bg.attributes & BG_ATTR_PRIORITY == 0 && flags & SPRITE_BEHIND_BG == 0)
+        } else {
+            flags & SPRITE_BEHIND_BG == 0
+        }
+    }
+
+    fn output_bg_pixel(&mut self, index: usize, pixel: BgPixel) {
+        if self.cgb_mode {
+            self.frame_buffer[index] = pixel.color;
+            self.color_frame_buffer[index] =
+                palette_color(&self.bg_palette_ram, pixel.attributes & BG_ATTR_PALETTE, pixel.color);
+        } else {
+            let shade = shade(self.bgp, pixel.color);
+            self.frame_buffer[index] = shade;
+            self.color_frame_buffer[index] = self.dmg_palette.bg[shade as usize];
+        }
+    }
+
+    // with the background off on DMG the LCD shows white whatever BGP says, sprites still see color 0 below
+    fn output_blank_pixel(&mut self, index: usize) {
+        self.frame_buffer[index] = 0;
+        self.color_frame_buffer[index] = self.dmg_palette.bg[0];
+    }
+
+    fn output_sprite_pixel(&mut self, index: usize, color: u8, flags: u8) {
+        if self.cgb_mode {
+            self.frame_buffer[index] = color;
+            self.color_frame_buffer[index] = palette_color(&self.obj_palette_ram, flags & SPRITE_CGB_PALETTE, color);
+        } else {
+            let (palette, colors) = if flags & SPRITE_PALETTE != 0 {
+                (self.obp1, self.dmg_palette.obj1)
+            } else {
+                (self.obp0, self.dmg_palette.obj0)
+            };
+            let shade = shade(palette, color);
+            self.frame_buffer[index] = shade;
+            self.color_frame_buffer[index] = colors[shade as usize];
+        }
+    }
+
+    fn render_sprites(&mut self, line: u8, bg_pixels: &[BgPixel; SCREEN_WIDTH]) {
+        let row_start = line as usize * SCREEN_WIDTH;
+        // tracks pixels already claimed by a higher priority sprite
+        let mut claimed = [false; SCREEN_WIDTH];
+
+        for sprite in self.sprites_on_line(line) {
+            let tile_address = self.sprite_row_address(&sprite, line);
+            for pixel in 0..8 {
+                let x = sprite.x + pixel;
+                if !(0..SCREEN_WIDTH as i16).contains(&x) || claimed[x as usize] {
+                    continue;
+                }
+                let column = if sprite.flags & SPRITE_X_FLIP != 0 { 7 - pixel } else { pixel };
+                let color = tile_pixel(&self.vram, tile_address, column as u8);
+                // color 0 is transparent and lets lower priority sprites show through
+                if color == 0 {
+                    continue;
+                }
+                claimed[x as usize] = true;
+                if self.sprite_over_background(bg_pixels[x as usize], sprite.flags) {
+                    self.output_sprite_pixel(row_start + x as usize, color, sprite.flags);
+                }
+            }
+        }
+    }
+}
+
+impl Default for PPU {
+    fn default() -> Self {
+        PPU::new()
+    }
+}
+
+// color number (0-3) of one pixel of a tile row, the row is two bytes of bit planes
+fn tile_pixel(vram: &[u8], row_address: usize, column: u8) -> u8 {
+    let low = vram[row_address];
+    let high = vram[row_address + 1];
+    let bit = 7 - column;
+    ((high >> bit) & 1) << 1 | ((low >> bit) & 1)
+}
+
+// map a color number through a BGP/OBP palette register to a shade
+fn shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0x03
+}
+
+// RGB555 color from CGB palette memory, stored little endian
+fn palette_color(palette_ram: &[u8; PALETTE_RAM_SIZE], palette: u8, color: u8) -> u16 {
+    let index = palette as usize * 8 + color as usize * 2;
+    u16::from_le_bytes([palette_ram[index], palette_ram[index + 1]]) & 0x7FFF
+}
+
+// BCPD/OCPD write, the index still advances when the write is blocked during mode 3
+fn write_palette_ram(palette_ram: &mut [u8; PALETTE_RAM_SIZE], specification: &mut u8, value: u8, drawing: bool) {
+    let index = *specification & 0x3F;
+    if !drawing {
+        palette_ram[index as usize] = value;
+    }
+    if *specification & PALETTE_AUTO_INCREMENT != 0 {
+        *specification = PALETTE_AUTO_INCREMENT | ((index + 1) & 0x3F);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LCD on, background and sprites enabled, tiles at 0x8000
+    const LCDC: u8 = LCDC_LCD_ENABLE | LCDC_TILE_DATA | LCDC_OBJ_ENABLE | LCDC_BG_ENABLE;
+
+    fn ppu(lcdc: u8) -> PPU {
+        let mut ppu = PPU::new();
+        for address in [0xFF47, 0xFF48, 0xFF49] {
+            ppu.write_register(address, 0xE4);
+        }
+        ppu.write_register(0xFF40, lcdc);
+        ppu
+    }
+
+    // a tile with every pixel in one color
+    fn solid_tile(ppu: &mut PPU, tile: u8, color: u8) {
+        for row in 0..8 {
+            let address = 0x8000 + tile as u16 * 16 + row * 2;
+            ppu.write_vram(address, if color & 0x01 != 0 { 0xFF } else { 0x00 });
+            ppu.write_vram(address + 1, if color & 0x02 != 0 { 0xFF } else { 0x00 });
+        }
+    }
+
+    // a sprite at a screen position
+    fn sprite(ppu: &mut PPU, index: u16, x: u8, y: u8, tile: u8, flags: u8) {
+        let address = 0xFE00 + index * 4;
+        for (offset, value) in [y + 16, x + 8, tile, flags].into_iter().enumerate() {
+            ppu.write_oam(address + offset as u16, value);
+        }
+    }
+
+    fn pixel(ppu: &PPU, x: usize, y: usize) -> u8 {
+        ppu.frame_buffer()[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn sprites_draw_over_the_background_unless_behind_it() {
+        let mut ppu = ppu(LCDC);
+        solid_tile(&mut ppu, 0, 1);
+        solid_tile(&mut ppu, 1, 3);
+        sprite(&mut ppu, 0, 0, 0, 1, 0);
+        sprite(&mut ppu, 1, 16, 0, 1, SPRITE_BEHIND_BG);
+        ppu.render_frame();
+        assert_eq!(pixel(&ppu, 0, 0), 3);
+        assert_eq!(pixel(&ppu, 16, 0), 1);
+        assert_eq!(pixel(&ppu, 8, 0), 1);
+
+        // behind a background of color 0 the sprite still shows
+        solid_tile(&mut ppu, 0, 0);
+        ppu.render_frame();
+        assert_eq!(pixel(&ppu, 16, 0), 3);
+    }
+
+    #[test]
+    fn the_sprite_with_the_smaller_x_wins_on_dmg() {
+        let mut ppu = ppu(LCDC);
+        solid_tile(&mut ppu, 1, 2);
+        solid_tile(&mut ppu, 2, 3);
+        sprite(&mut ppu, 0, 4, 0, 1, 0);
+        sprite(&mut ppu, 1, 0, 0, 2, 0);
+        ppu.render_frame();
+        assert_eq!(pixel(&ppu, 6, 0), 3);
+        assert_eq!(pixel(&ppu, 10, 0), 2);
+    }
+
+    #[test]
+    fn only_ten_sprites_are_drawn_per_line() {
+        let mut ppu = ppu(LCDC);
+        solid_tile(&mut ppu, 1, 3);
+        for index in 0..11 {
+            sprite(&mut ppu, index, index as u8 * 8, 0, 1, 0);
+        }
+        ppu.render_frame();
+        assert_eq!(pixel(&ppu, 72, 0), 3);
+        assert_eq!(pixel(&ppu, 80, 0), 0);
+    }
+
+    #[test]
+    fn a_disabled_background_is_white_whatever_bgp_says() {
+        let mut ppu = ppu(LCDC & !LCDC_BG_ENABLE);
+        ppu.write_register(0xFF47, 0xFF);
+        solid_tile(&mut ppu, 0, 3);
+        solid_tile(&mut ppu, 1, 3);
+        sprite(&mut ppu, 0, 0, 0, 1, SPRITE_BEHIND_BG);
+        ppu.render_frame();
+        assert_eq!(pixel(&ppu, 0, 0), 3);
+        assert_eq!(pixel(&ppu, 8, 0), 0);
+        assert_eq!(pixel(&ppu, 100, 100), 0);
+    }
+
+    // step dot by dot until LY reads the given line at the start of a line
+    fn run_to_line(ppu: &mut PPU, line: u8) {
+        while ppu.read_register(0xFF44) != line || ppu.dot != 0 {
+            ppu.step(1);
+        }
+    }
+
+    #[test]
+    fn lines_go_through_oam_scan_drawing_and_hblank() {
+        let mut ppu = ppu(LCDC);
+        run_to_line(&mut ppu, 1);
+        let mut dots = [0; 4];
+        for _ in 0..DOTS_PER_LINE {
+            dots[ppu.read_register(0xFF41) as usize & 0x03] += 1;
+            ppu.step(1);
+        }
+        assert_eq!(dots, [204, 0, 80, 172]);
+
+        // SCX % 8 pixels are thrown away at the start of mode 3
+        ppu.write_register(0xFF43, 3);
+        let mut drawing = 0;
+        for _ in 0..DOTS_PER_LINE {
+            drawing += (ppu.mode() == Mode::Drawing) as u16;
+            ppu.step(1);
+        }
+        assert_eq!(drawing, 175);
+    }
+
+    #[test]
+    fn vblank_starts_at_line_144_once_per_frame() {
+        let mut ppu = ppu(LCDC);
+        run_to_line(&mut ppu, 0);
+        let mut vblanks = Vec::new();
+        for _ in 0..DOTS_PER_LINE as u32 * 154 * 2 {
+            if ppu.step(1) & VBLANK_INTERRUPT != 0 {
+                vblanks.push(ppu.read_register(0xFF44));
+                assert_eq!(ppu.mode(), Mode::VBlank);
+            }
+        }
+        assert_eq!(vblanks, [144, 144]);
+    }
+
+    #[test]
+    fn lyc_match_raises_one_stat_interrupt_per_frame() {
+        // set up with the LCD off, a STAT write with it on raises an interrupt of its own on DMG
+        let mut ppu = ppu(0);
+        ppu.write_register(0xFF45, 5);
+        ppu.write_register(0xFF41, STAT_LYC_SOURCE);
+        ppu.write_register(0xFF40, LCDC);
+        run_to_line(&mut ppu, 1);
+        let mut matches = Vec::new();
+        for _ in 0..DOTS_PER_LINE as u32 * 154 {
+            if ppu.step(1) & STAT_INTERRUPT != 0 {
+                matches.push((ppu.read_register(0xFF44), ppu.dot));
+                assert_ne!(ppu.read_register(0xFF41) & STAT_COINCIDENCE, 0);
+            }
+        }
+        assert_eq!(matches, [(5, 0)]);
+        assert_eq!(ppu.read_register(0xFF41) & STAT_COINCIDENCE, 0);
+    }
+
+    #[test]
+    fn a_high_stat_line_blocks_other_sources() {
+        // HBlank of line 4 runs straight into LY=LYC on line 5, which holds the line high until line 6. Neither
+        // the match nor the HBlank of line 5 raise an interrupt of their own.
+        let mut ppu = ppu(0);
+        ppu.write_register(0xFF45, 5);
+        ppu.write_register(0xFF41, STAT_LYC_SOURCE | STAT_HBLANK_SOURCE);
+        ppu.write_register(0xFF40, LCDC);
+        run_to_line(&mut ppu, 1);
+        let mut interrupts = 0;
+        for _ in 0..DOTS_PER_LINE as u32 * 154 {
+            interrupts += (ppu.step(1) & STAT_INTERRUPT != 0) as u32;
+        }
+        assert_eq!(interrupts, 143);
+
+        // on a VBlank line nothing else holds the line, every HBlank and the match get through
+        ppu.write_register(0xFF45, 150);
+        let mut interrupts = 0;
+        for _ in 0..DOTS_PER_LINE as u32 * 154 {
+            interrupts += (ppu.step(1) & STAT_INTERRUPT != 0) as u32;
+        }
+        assert_eq!(interrupts, 145);
+    }
+
+    fn cgb_ppu() -> PPU {
+        let mut ppu = PPU::with_model(Model::CGB, Renderer::Scanline);
+        ppu.set_cgb_mode(true);
+        ppu.write_register(0xFF40, LCDC);
+        ppu
+    }
+
+    // one RGB555 color through BCPS/BCPD (0xFF68) or OCPS/OCPD (0xFF6A)
+    fn set_color(ppu: &mut PPU, specification: u16, palette: u8, color: u8, rgb: u16) {
+        ppu.write_register(specification, PALETTE_AUTO_INCREMENT | (palette * 8 + color * 2));
+        ppu.write_register(specification + 1, rgb as u8);
+        ppu.write_register(specification + 1, (rgb >> 8) as u8);
+    }
+
+    fn color(ppu: &PPU, x: usize, y: usize) -> u16 {
+        ppu.color_frame_buffer()[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn palette_specifications_auto_increment_and_wrap() {
+        let mut ppu = cgb_ppu();
+        ppu.write_register(0xFF40, 0);
+        ppu.write_register(0xFF68, PALETTE_AUTO_INCREMENT | 0x3E);
+        for value in [0x11, 0x22, 0x33] {
+            ppu.write_register(0xFF69, value);
+        }
+        assert_eq!(ppu.read_register(0xFF68), 0xC1);
+        ppu.write_register(0xFF68, 0x3F);
+        assert_eq!(ppu.read_register(0xFF69), 0x22);
+        // without auto-increment the index stays put
+        ppu.write_register(0xFF69, 0x44);
+        ppu.write_register(0xFF69, 0x55);
+        assert_eq!(ppu.read_register(0xFF68), 0x7F);
+        assert_eq!(ppu.read_register(0xFF69), 0x55);
+        ppu.write_register(0xFF68, 0x00);
+        assert_eq!(ppu.read_register(0xFF69), 0x33);
+        // the object palettes are separate
+        ppu.write_register(0xFF6A, 0x00);
+        ppu.write_register(0xFF6B, 0x66);
+        assert_eq!(ppu.read_register(0xFF69), 0x33);
+        assert_eq!(ppu.read_register(0xFF6B), 0x66);
+    }
+
+    #[test]
+    fn palette_memory_is_locked_while_drawing_but_the_index_still_advances() {
+        let mut ppu = cgb_ppu();
+        run_to_line(&mut ppu, 1);
+        while ppu.mode() != Mode::Drawing {
+            ppu.step(1);
+        }
+        ppu.write_register(0xFF68, PALETTE_AUTO_INCREMENT);
+        ppu.write_register(0xFF69, 0x12);
+        assert_eq!(ppu.read_register(0xFF69), 0xFF);
+        assert_eq!(ppu.read_register(0xFF68), 0xC1);
+        while ppu.mode() != Mode::HBlank {
+            ppu.step(1);
+        }
+        ppu.write_register(0xFF68, 0x00);
+        assert_eq!(ppu.read_register(0xFF69), 0xFF);
+    }
+
+    #[test]
+    fn bg_attributes_pick_the_palette_bank_and_flip() {
+        let mut ppu = cgb_ppu();
+        set_color(&mut ppu, 0xFF68, 0, 0, 0x0000);
+        set_color(&mut ppu, 0xFF68, 0, 1, 0x1111);
+        set_color(&mut ppu, 0xFF68, 2, 3, 0x2222);
+        // tile 0 is color 0 in bank 0 and color 3 in bank 1, tile 1 has its leftmost column in color 1
+        solid_tile(&mut ppu, 0, 0);
+        for row in 0..8 {
+            ppu.write_vram(0x8010 + row * 2, 0x80);
+        }
+        ppu.write_register(0xFF4F, 1);
+        solid_tile(&mut ppu, 0, 3);
+        // attributes for map entries 0-2: palette 2 from bank 1, nothing, X flip
+        ppu.write_vram(0x9800, 0x02 | BG_ATTR_BANK);
+        ppu.write_vram(0x9802, BG_ATTR_X_FLIP);
+        ppu.write_register(0xFF4F, 0);
+        ppu.write_vram(0x9801, 1);
+        ppu.write_vram(0x9802, 1);
+        ppu.render_frame();
+        assert_eq!(color(&ppu, 0, 0), 0x2222);
+        assert_eq!(color(&ppu, 8, 0), 0x1111);
+        assert_eq!(color(&ppu, 9, 0), 0x0000);
+        assert_eq!(color(&ppu, 16, 0), 0x0000);
+        assert_eq!(color(&ppu, 23, 0), 0x1111);
+    }
+
+    #[test]
+    fn sprite_attributes_pick_the_palette_and_bank() {
+        let mut ppu = cgb_ppu();
+        set_color(&mut ppu, 0xFF6A, 5, 2, 0x5555);
+        set_color(&mut ppu, 0xFF6A, 0, 1, 0x0101);
+        ppu.write_register(0xFF4F, 1);
+        solid_tile(&mut ppu, 1, 2);
+        ppu.write_register(0xFF4F, 0);
+        solid_tile(&mut ppu, 1, 1);
+        sprite(&mut ppu, 0, 0, 0, 1, SPRITE_BANK | 5);
+        sprite(&mut ppu, 1, 8, 0, 1, 0);
+        ppu.render_frame();
+        assert_eq!(color(&ppu, 0, 0), 0x5555);
+        assert_eq!(color(&ppu, 8, 0), 0x0101);
+    }
+
+    #[test]
+    fn cgb_sprites_overlap_by_oam_position_and_bg_priority_wins() {
+        let mut ppu = cgb_ppu();
+        set_color(&mut ppu, 0xFF68, 0, 1, 0x1111);
+        set_color(&mut ppu, 0xFF6A, 0, 3, 0x3333);
+        set_color(&mut ppu, 0xFF6A, 1, 3, 0x4444);
+        solid_tile(&mut ppu, 1, 3);
+        solid_tile(&mut ppu, 2, 1);
+        // the later sprite sits further left but the earlier one wins
+        sprite(&mut ppu, 0, 4, 0, 1, 0);
+        sprite(&mut ppu, 1, 0, 0, 1, 1);
+        ppu.render_frame();
+        assert_eq!(color(&ppu, 6, 0), 0x3333);
+        assert_eq!(color(&ppu, 2, 0), 0x4444);
+
+        // a BG tile with the priority attribute covers sprites wherever its color isn't 0
+        ppu.write_vram(0x9820, 2);
+        ppu.write_register(0xFF4F, 1);
+        ppu.write_vram(0x9820, BG_ATTR_PRIORITY);
+        ppu.write_register(0xFF4F, 0);
+        sprite(&mut ppu, 2, 0, 8, 1, 0);
+        ppu.render_frame();
+        assert_eq!(color(&ppu, 0, 8), 0x1111);
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::VecDeque;
+
+use super::*;
+
+// every fetcher step except the push takes two dots
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FetcherStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+#[derive(Clone, Copy)]
+struct SpritePixel {
+    color: u8,
+    flags: u8,
+    // OAM position of the sprite the pixel came from
+    index: u8,
+}
+
+struct SpriteFetch {
+    sprite: Sprite,
+    dots: u8,
+}
+
+// state of the background/window fetcher and both pixel FIFOs for the line being drawn
+pub(super) struct PixelFifo {
+    background: VecDeque<BgPixel>,
+    sprites: VecDeque<SpritePixel>,
+    step: FetcherStep,
+    // set on the first dot of a two dot fetcher step
+    step_started: bool,
+    // fetcher position in tiles, relative to SCX or the window's left edge
+    tile_x: u8,
+    tile: u8,
+    // CGB attributes of the fetched tile
+    attributes: u8,
+    data_low: u8,
+    data_high: u8,
+    // the first tile fetched on a line is thrown away
+    dummy_fetch: bool,
+    // pixels dropped from the start of the line to apply SCX % 8
+    discard: u8,
+    // next pixel to be sent to the LCD
+    x: u8,
+    window: bool,
+    // sprites from the OAM scan that haven't been fetched yet
+    pending_sprites: Vec<Sprite>,
+    sprite_fetch: Option<SpriteFetch>,
+}
+
+impl PixelFifo {
+    pub(super) fn new() -> PixelFifo {
+        PixelFifo {
+            background: VecDeque::with_capacity(16),
+            sprites: VecDeque::with_capacity(8),
+            step: FetcherStep::Tile,
+            step_started: false,
+            tile_x: 0,
+            tile: 0,
+            attributes: 0,
+            data_low: 0,
+            data_high: 0,
+            dummy_fetch: true,
+            discard: 0,
+            x: 0,
+            window: false,
+            pending_sprites: Vec::new(),
+            sprite_fetch: None,
+        }
+    }
+
+    fn restart_fetcher(&mut self) {
+        self.step = FetcherStep::Tile;
+        self.step_started = false;
+        self.tile_x = 0;
+    }
+}
+
+impl PPU {
+    pub(super) fn start_fifo_line(&mut self) {
+        let sprites = if self.lcdc & LCDC_OBJ_ENABLE != 0 { self.sprites_on_line(self.ly) } else { Vec::new() };
+        let fifo = &mut self.fifo;
+        fifo.background.clear();
+        fifo.sprites.clear();
+        fifo.restart_fetcher();
+        fifo.dummy_fetch = true;
+        fifo.discard = self.scx % 8;
+        fifo.x = 0;
+        fifo.window = false;
+        fifo.pending_sprites = sprites;
+        fifo.sprite_fetch = None;
+    }
+
+    // one dot of mode 3 with the pixel FIFO renderer, returns true once 160 pixels were sent to the LCD
+    pub(super) fn fifo_tick(&mut self) -> bool {
+        if self.fifo.sprite_fetch.is_none() {
+            self.check_sprite_fetch();
+        }
+        if self.fifo.sprite_fetch.is_some() {
+            // the background fetcher finishes the tile it is working on before the sprite is fetched,
+            // nothing is sent to the LCD in the meantime
+            if self.fifo.step == FetcherStep::Push && !self.fifo.background.is_empty() {
+                self.sprite_fetch_tick();
+            } else {
+                self.fetcher_tick();
+            }
+            return false;
+        }
+
+        self.fetcher_tick();
+        if self.check_window() {
+            return false;
+        }
+        self.shift_pixel();
+
+        if self.fifo.x as usize == SCREEN_WIDTH {
+            if self.fifo.window {
+                self.window_line = self.window_line.wrapping_add(1);
+            }
+            return true;
+        }
+        false
+    }
+
+    fn check_sprite_fetch(&mut self) {
+        // discarded SCX pixels are still being shifted out, sprites wait for the first visible pixel
+        if self.fifo.discard > 0 {
+            return;
+        }
+        let x = self.fifo.x as i16;
+        if let Some(index) = self.fifo.pending_sprites.iter().position(|sprite| sprite.x <= x) {
+            let sprite = self.fifo.pending_sprites.remove(index);
+            self.fifo.sprite_fetch = Some(SpriteFetch { sprite, dots: 0 });
+        }
+    }
+
+    // switch the fetcher over to the window once the LCD reaches WX - 7, returns true on the switch
+    fn check_window(&mut self) -> bool {
+        if self.fifo.window
+            || self.lcdc & LCDC_WINDOW_ENABLE == 0
+            || !self.window_triggered
+            || self.fifo.x as u16 + 7 < self.wx as u16
+        {
+            return false;
+        }
+        self.fifo.window = true;
+        self.fifo.discard = 0;
+        self.fifo.background.clear();
+        self.fifo.restart_fetcher();
+        true
+    }
+
+    fn fetcher_tick(&mut self) {
+        let fifo = &mut self.fifo;
+        if fifo.step == FetcherStep::Push {
+            // the push is retried every dot until the background FIFO has room
+            if fifo.background.is_empty() {
+                if fifo.dummy_fetch {
+                    fifo.dummy_fetch = false;
+                } else {
+                    let attributes = fifo.attributes;
+                    for column in 0..8 {
+                        let bit = if attributes & BG_ATTR_X_FLIP != 0 { column } else { 7 - column };
+                        let color = ((fifo.data_high >> bit) & 1) << 1 | ((fifo.data_low >> bit) & 1);
+                        fifo.background.push_back(BgPixel { color, attributes });
+                    }
+                    fifo.tile_x = fifo.tile_x.wrapping_add(1);
+                }
+                fifo.step = FetcherStep::Tile;
+            }
+            return;
+        }
+
+        fifo.step_started = !fifo.step_started;
+        if fifo.step_started {
+            return;
+        }
+        match fifo.step {
+            FetcherStep::Tile => {
+                let map_address = self.fetcher_map_address();
+                self.fifo.tile = self.vram[map_address];
+                self.fifo.attributes = self.bg_attributes(map_address);
+                self.fifo.step = FetcherStep::DataLow;
+            }
+            FetcherStep::DataLow => {
+                self.fifo.data_low = self.vram[self.fetcher_data_address()];
+                self.fifo.step = FetcherStep::DataHigh;
+            }
+            FetcherStep::DataHigh => {
+                self.fifo.data_high = self.vram[self.fetcher_data_address() + 1];
+                self.fifo.step = FetcherStep::Push;
+            }
+            FetcherStep::Push => {}
+        }
+    }
+
+    // registers are read at the time of each fetch, so mid-line SCX/SCY/LCDC writes show up on screen
+    fn fetcher_y(&self) -> u8 {
+        if self.fifo.window {
+            self.window_line
+        } else {
+            self.ly.wrapping_add(self.scy)
+        }
+    }
+
+    fn fetcher_map_address(&self) -> usize {
+        let (map_bit, column) = if self.fifo.window {
+            (LCDC_WINDOW_TILE_MAP, self.fifo.tile_x & 0x1F)
+        } else {
+            (LCDC_BG_TILE_MAP, ((self.scx / 8).wrapping_add(self.fifo.tile_x)) & 0x1F)
+        };
+        let map_base = if self.lcdc & map_bit != 0 { 0x1C00 } else { 0x1800 };
+        map_base + (self.fetcher_y() as usize / 8) * 32 + column as usize
+    }
+
+    fn fetcher_data_address(&self) -> usize {
+        self.bg_tile_row_address(self.fifo.tile, self.fifo.attributes, self.fetcher_y())
+    }
+
+    fn sprite_fetch_tick(&mut self) {
+        let fetch = match self.fifo.sprite_fetch.as_mut() {
+            Some(fetch) => fetch,
+            None => return,
+        };
+        fetch.dots += 1;
+        // tile number, low and high data take two dots each
+        if fetch.dots < 6 {
+            return;
+        }
+        let sprite = fetch.sprite;
+        self.fifo.sprite_fetch = None;
+        let tile_address = self.sprite_row_address(&sprite, self.ly);
+        let oam_priority = self.oam_priority();
+
+        // sprites hanging off the left edge lose the columns that were already passed
+        let skip = (self.fifo.x as i16 - sprite.x).max(0) as usize;
+        for (slot, pixel) in (skip..8).enumerate() {
+            let column = if sprite.flags & SPRITE_X_FLIP != 0 { 7 - pixel } else { pixel };
+            let pixel = SpritePixel {
+                color: tile_pixel(&self.vram, tile_address, column as u8),
+                flags: sprite.flags,
+                index: sprite.index,
+            };
+            // a sprite only fills slots that earlier sprites left transparent, unless CGB priority by OAM
+            // position lets it cover them
+            match self.fifo.sprites.get_mut(slot) {
+                Some(existing) if existing.color == 0 => *existing = pixel,
+                Some(existing) if oam_priority && pixel.color != 0 && pixel.index < existing.index => *existing = pixel,
+                Some(_) => {}
+                None => self.fifo.sprites.push_back(pixel),
+            }
+        }
+    }
+
+    // shift one pixel out of the FIFOs, mix it and send it to the LCD
+    fn shift_pixel(&mut self) {
+        let pixel = match self.fifo.background.pop_front() {
+            Some(pixel) => pixel,
+            None => return,
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+        let sprite = self.fifo.sprites.pop_front();
+
+        let background = self.background_enabled();
+        let bg = if background { pixel } else { BgPixel::default() };
+        // palettes are applied when the pixel leaves the FIFO, not when it's fetched
+        let index = self.ly as usize * SCREEN_WIDTH + self.fifo.x as usize;
+        match sprite {
+            Some(sprite)
+                if sprite.color != 0
+                    && self.lcdc & LCDC_OBJ_ENABLE != 0
+                    && self.sprite_over_background(bg, sprite.flags) =>
+            {
+                self.output_sprite_pixel(index, sprite.color, sprite.flags)
+            }
+            _ if background => self.output_bg_pixel(index, bg),
+            _ => self.output_blank_pixel(index),
+        }
+        self.fifo.x += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE: u8 = 10;
+
+    // background on with tiles at 0x8000, tile 0 is color 0 and tile 1 color 3. The map alternates them.
+    fn ppu(renderer: Renderer) -> PPU {
+        let mut ppu = PPU::with_renderer(renderer);
+        ppu.write_register(0xFF47, 0xE4);
+        for row in 0..8 {
+            ppu.write_vram(0x8010 + row * 2, 0xFF);
+            ppu.write_vram(0x8011 + row * 2, 0xFF);
+        }
+        for index in 0..0x400 {
+            ppu.write_vram(0x9800 + index, (index & 0x01) as u8);
+        }
+        ppu.write_register(0xFF40, LCDC_LCD_ENABLE | LCDC_TILE_DATA | LCDC_BG_ENABLE);
+        while ppu.ly != LINE || ppu.dot != 0 {
+            ppu.step(1);
+        }
+        ppu
+    }
+
+    // run the line halfway through mode 3, write a register and finish drawing it
+    fn write_mid_line(ppu: &mut PPU, address: u16, value: u8) -> Vec<u8> {
+        while ppu.dot < OAM_SCAN_DOTS + DRAWING_DOTS / 2 {
+            ppu.step(1);
+        }
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        ppu.write_register(address, value);
+        while ppu.mode() == Mode::Drawing {
+            ppu.step(1);
+        }
+        let start = LINE as usize * SCREEN_WIDTH;
+        ppu.frame_buffer()[start..start + SCREEN_WIDTH].to_vec()
+    }
+
+    #[test]
+    fn a_mid_line_bgp_write_only_splits_the_line_on_the_fifo_path() {
+        let line = write_mid_line(&mut ppu(Renderer::PixelFifo), 0xFF47, 0x00);
+        assert_eq!(line[9], 3);
+        assert_eq!(line[SCREEN_WIDTH - 7], 0);
+
+        let line = write_mid_line(&mut ppu(Renderer::Scanline), 0xFF47, 0x00);
+        assert!(line.iter().all(|&shade| shade == 0));
+    }
+
+    #[test]
+    fn a_mid_line_scx_write_only_moves_later_tiles_on_the_fifo_path() {
+        // SCX = 8 swaps the two tiles around
+        let line = write_mid_line(&mut ppu(Renderer::PixelFifo), 0xFF43, 8);
+        assert_eq!(&line[..16], [[0; 8], [3; 8]].concat());
+        assert_eq!(&line[SCREEN_WIDTH - 16..], [[3; 8], [0; 8]].concat());
+
+        let line = write_mid_line(&mut ppu(Renderer::Scanline), 0xFF43, 8);
+        assert_eq!(&line[..16], [[3; 8], [0; 8]].concat());
+        assert_eq!(&line[SCREEN_WIDTH - 16..], [[3; 8], [0; 8]].concat());
+    }
+}
@@ -0,0 +1,379 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::png;
+use crate::serial::SerialDevice;
+
+pub const PAPER_WIDTH: usize = 160;
+
+// 20 tiles of 16 bytes make up a band of 8 pixel rows
+const TILES_PER_BAND: usize = PAPER_WIDTH / 8;
+const BAND_SIZE: usize = TILES_PER_BAND * 16;
+// the printer holds up to 9 data packets of two bands each
+const BUFFER_SIZE: usize = 0x1680;
+// status requests answered with the busy bit after a print command, games wait for it to clear
+const BUSY_STATUS_REQUESTS: u8 = 4;
+// paper fed per margin unit, in pixel rows
+const MARGIN_ROWS: usize = 8;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+const STATUS_PACKET_ERROR: u8 = 0x10;
+
+// the printer answers every packet byte with 0 except for the last two
+const ALIVE: u8 = 0x81;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+// a printed sheet of paper, pixels are DMG shades 0 (white) to 3 (black)
+pub struct PrintedPage {
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl PrintedPage {
+    pub fn write_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let pixels: Vec<u8> = self.pixels.iter().map(|&shade| 255 - shade * 85).collect();
+        let mut writer = BufWriter::new(File::create(path)?);
+        png::write_grayscale(&mut writer, PAPER_WIDTH, self.height, &pixels)
+    }
+}
+
+struct PrinterState {
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    packet: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    busy_requests: u8,
+    // decompressed tile data waiting to be printed
+    buffer: Vec<u8>,
+    // paper printed since the last page was cut off
+    page: Vec<u8>,
+    pages: Vec<PrintedPage>,
+}
+
+// a Game Boy Printer on the link port. Printouts are joined into a page until a print command feeds paper
+// after the image, then the page is done. Clones share the same printer, so one can be kept to collect pages.
+#[derive(Clone)]
+pub struct Printer {
+    state: Rc<RefCell<PrinterState>>,
+}
+
+impl Printer {
+    pub fn new() -> Printer {
+        Printer {
+            state: Rc::new(RefCell::new(PrinterState {
+                state: PacketState::Magic1,
+                command: 0,
+                compressed: false,
+                length: 0,
+                packet: Vec::new(),
+                checksum: 0,
+                received_checksum: 0,
+                status: 0,
+                busy_requests: 0,
+                buffer: Vec::new(),
+                page: Vec::new(),
+                pages: Vec::new(),
+            })),
+        }
+    }
+
+    // pages completed since the last call
+    pub fn take_pages(&self) -> Vec<PrintedPage> {
+        std::mem::take(&mut self.state.borrow_mut().pages)
+    }
+
+    // cut off whatever has been printed on the current page, like tearing the paper off
+    pub fn finish_page(&self) {
+        self.state.borrow_mut().finish_page();
+    }
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Printer::new()
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.state.borrow_mut().receive(outgoing)
+    }
+}
+
+impl PrinterState {
+    // packets are 0x88 0x33, command, compression, length, data, checksum, then two bytes clocked to
+    // read back the alive marker and the status
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut reply = 0x00;
+        self.state = match self.state {
+            PacketState::Magic1 if byte == 0x88 => PacketState::Magic2,
+            PacketState::Magic1 => PacketState::Magic1,
+            PacketState::Magic2 if byte == 0x33 => PacketState::Command,
+            // 0x88 0x88 0x33 is still a packet, the second 0x88 can start it
+            PacketState::Magic2 if byte == 0x88 => PacketState::Magic2,
+            PacketState::Magic2 => PacketState::Magic1,
+            PacketState::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                PacketState::Compression
+            }
+            PacketState::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthLow
+            }
+            PacketState::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthHigh
+            }
+            PacketState::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.packet.clear();
+                if self.length == 0 { PacketState::ChecksumLow } else { PacketState::Data }
+            }
+            PacketState::Data => {
+                self.packet.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.packet.len() == self.length as usize { PacketState::ChecksumLow } else { PacketState::Data }
+            }
+            PacketState::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                PacketState::ChecksumHigh
+            }
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                PacketState::Alive
+            }
+            PacketState::Alive => {
+                reply = ALIVE;
+                PacketState::Status
+            }
+            PacketState::Status => {
+                self.execute();
+                reply = self.status;
+                PacketState::Magic1
+            }
+        };
+        reply
+    }
+
+    fn execute(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_requests = 0;
+            }
+            COMMAND_DATA => {
+                // an empty data packet marks the end of the image
+                if !self.packet.is_empty() {
+                    let data = if self.compressed { decompress(&self.packet) } else { self.packet.clone() };
+                    let room = BUFFER_SIZE - self.buffer.len();
+                    self.buffer.extend_from_slice(&data[..data.len().min(room)]);
+                    self.status |= STATUS_UNPROCESSED;
+                    if self.buffer.len() == BUFFER_SIZE {
+                        self.status |= STATUS_IMAGE_FULL;
+                    }
+                }
+            }
+            COMMAND_PRINT if self.packet.len() == 4 => {
+                // sheets, margins, palette and exposure, the sheet count and darkness don't change the image
+                let margins = self.packet[1];
+                self.print(margins >> 4, margins & 0x0F, self.packet[2]);
+                self.status = (self.status & !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL)) | STATUS_PRINTING;
+                self.busy_requests = BUSY_STATUS_REQUESTS;
+            }
+            COMMAND_STATUS => {
+                if self.busy_requests > 0 {
+                    self.busy_requests -= 1;
+                    if self.busy_requests == 0 {
+                        self.status &= !STATUS_PRINTING;
+                    }
+                }
+            }
+            _ => self.status |= STATUS_PACKET_ERROR,
+        }
+    }
+
+    fn print(&mut self, margin_before: u8, margin_after: u8, palette: u8) {
+        // a palette of 0 prints like the usual 0xE4
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        self.feed(margin_before);
+        for band in self.buffer.chunks_exact(BAND_SIZE) {
+            for row in 0..8 {
+                for tile in 0..TILES_PER_BAND {
+                    let low = band[tile * 16 + row * 2];
+                    let high = band[tile * 16 + row * 2 + 1];
+                    for bit in (0..8).rev() {
+                        let color = ((high >> bit) & 1) << 1 | ((low >> bit) & 1);
+                        self.page.push((palette >> (color * 2)) & 0x03);
+                    }
+                }
+            }
+        }
+        self.buffer.clear();
+        if margin_after > 0 {
+            self.feed(margin_after);
+            self.finish_page();
+        }
+    }
+
+    fn feed(&mut self, units: u8) {
+        let length = self.page.len() + units as usize * MARGIN_ROWS * PAPER_WIDTH;
+        self.page.resize(length, 0);
+    }
+
+    fn finish_page(&mut self) {
+        if self.page.is_empty() {
+            return;
+        }
+        let pixels = std::mem::take(&mut self.page);
+        self.pages.push(PrintedPage {
+            height: pixels.len() / PAPER_WIDTH,
+            pixels,
+        });
+    }
+}
+
+// runs with the top bit set repeat the next byte (low bits + 2) times, otherwise (byte + 1) literal bytes follow
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut bytes = data.iter();
+    while let Some(&control) = bytes.next() {
+        if control & 0x80 != 0 {
+            let count = (control & 0x7F) as usize + 2;
+            if let Some(&value) = bytes.next() {
+                output.extend(std::iter::repeat_n(value, count));
+            }
+        } else {
+            output.extend(bytes.by_ref().take(control as usize + 1));
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a whole packet with a valid checksum, followed by the two bytes clocked to read the answer
+    fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8> {
+        let mut body = vec![command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+        body.extend_from_slice(data);
+        let checksum = body.iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        let mut bytes = vec![0x88, 0x33];
+        bytes.extend(body);
+        bytes.extend(checksum.to_le_bytes());
+        bytes.extend([0, 0]);
+        bytes
+    }
+
+    fn send(printer: &mut Printer, bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().map(|&byte| printer.transfer(byte)).collect()
+    }
+
+    #[test]
+    fn replies_with_alive_and_status_at_the_end_of_a_packet() {
+        let mut printer = Printer::new();
+        let replies = send(&mut printer, &packet(COMMAND_STATUS, false, &[]));
+        let (body, answer) = replies.split_at(replies.len() - 2);
+        assert!(body.iter().all(|&reply| reply == 0));
+        assert_eq!(answer, [ALIVE, 0]);
+        assert_eq!(printer.state.borrow().state, PacketState::Magic1);
+    }
+
+    #[test]
+    fn finds_the_magic_bytes_after_garbage() {
+        let mut printer = Printer::new();
+        send(&mut printer, &[0x33, 0x12, 0x88, 0x00]);
+        assert_eq!(printer.state.borrow().state, PacketState::Magic1);
+        let mut bytes = vec![0x88];
+        bytes.extend(packet(COMMAND_STATUS, false, &[]));
+        let replies = send(&mut printer, &bytes);
+        assert_eq!(replies[replies.len() - 2..], [ALIVE, 0]);
+    }
+
+    #[test]
+    fn decompresses_data_packets() {
+        let mut printer = Printer::new();
+        // a run of 3 0xAA, then 2 literal bytes
+        let replies = send(&mut printer, &packet(COMMAND_DATA, true, &[0x81, 0xAA, 0x01, 0x12, 0x34]));
+        assert_eq!(replies[replies.len() - 1], STATUS_UNPROCESSED);
+        assert_eq!(printer.state.borrow().buffer, [0xAA, 0xAA, 0xAA, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn rejects_packets_with_a_bad_checksum() {
+        let mut printer = Printer::new();
+        let mut bytes = packet(COMMAND_DATA, false, &[0x12, 0x34]);
+        let checksum = bytes.len() - 4;
+        bytes[checksum] ^= 0x01;
+        let replies = send(&mut printer, &bytes);
+        assert_eq!(replies[replies.len() - 1], STATUS_CHECKSUM_ERROR);
+        assert!(printer.state.borrow().buffer.is_empty());
+
+        // the error clears with the next good packet
+        let replies = send(&mut printer, &packet(COMMAND_DATA, false, &[0x12, 0x34]));
+        assert_eq!(replies[replies.len() - 1], STATUS_UNPROCESSED);
+        assert_eq!(printer.state.borrow().buffer, [0x12, 0x34]);
+    }
+
+    #[test]
+    fn prints_a_page_and_stays_busy_for_a_few_status_requests() {
+        let mut printer = Printer::new();
+        send(&mut printer, &packet(COMMAND_INIT, false, &[]));
+        // two bands with every pixel in color 3
+        send(&mut printer, &packet(COMMAND_DATA, false, &[0xFF; BAND_SIZE * 2]));
+        send(&mut printer, &packet(COMMAND_DATA, false, &[]));
+        let replies = send(&mut printer, &packet(COMMAND_PRINT, false, &[1, 0x01, 0xE4, 0x40]));
+        assert_eq!(replies[replies.len() - 1], STATUS_PRINTING);
+
+        for request in 1..=BUSY_STATUS_REQUESTS {
+            let replies = send(&mut printer, &packet(COMMAND_STATUS, false, &[]));
+            let busy = if request < BUSY_STATUS_REQUESTS { STATUS_PRINTING } else { 0 };
+            assert_eq!(replies[replies.len() - 1], busy);
+        }
+
+        let pages = printer.take_pages();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].height, 16 + MARGIN_ROWS);
+        assert!(pages[0].pixels[..16 * PAPER_WIDTH].iter().all(|&shade| shade == 3));
+        assert!(pages[0].pixels[16 * PAPER_WIDTH..].iter().all(|&shade| shade == 0));
+    }
+}
@@ -1,33 +1,82 @@
-struct Registers {
-    a: u8,
-    b: u8,
-    c: u8,
-    d: u8,
-    e: u8,
-    h: u8,
-    l: u8,
+#[derive(Clone, Copy)]
+pub struct Registers {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub f: FlagsRegister,
+    pub h: u8,
+    pub l: u8,
 }
 
 impl Registers {
-  fn get_bc(&self) -> u16 {
+  pub fn new() -> Registers {
+    Registers {
+      a: 0,
+      b: 0,
+      c: 0,
+      d: 0,
+      e: 0,
+      f: FlagsRegister::from(0),
+      h: 0,
+      l: 0,
+    }
+  }
+
+  pub fn get_af(&self) -> u16 {
+    (self.a as u16) << 8 | u8::from(self.f) as u16
+  }
+
+  pub fn set_af(&mut self, value: u16) {
+    self.a = (value >> 8) as u8;
+    self.f = FlagsRegister::from(value as u8);
+  }
+
+  pub fn get_bc(&self) -> u16 {
     (self.b as u16) << 8 | self.c as u16
   }
 
-  fn set_bc(&mut self, value: u16) {
+  pub fn set_bc(&mut self, value: u16) {
     self.b = (value >> 8) as u8;
     self.c = value as u8;
   }
+
+  pub fn get_de(&self) -> u16 {
+    (self.d as u16) << 8 | self.e as u16
+  }
+
+  pub fn set_de(&mut self, value: u16) {
+    self.d = (value >> 8) as u8;
+    self.e = value as u8;
+  }
+
+  pub fn get_hl(&self) -> u16 {
+    (self.h as u16) << 8 | self.l as u16
+  }
+
+  pub fn set_hl(&mut self, value: u16) {
+    self.h = (value >> 8) as u8;
+    self.l = value as u8;
+  }
 }
 
-struct FlagsRegister {
+impl Default for Registers {
+  fn default() -> Self {
+    Registers::new()
+  }
+}
+
+#[derive(Clone, Copy)]
+pub struct FlagsRegister {
     //set if the result of operation is 0
-    zero: bool,
+    pub zero: bool,
     //set if the operation was a subtraction
-    subtract: bool,
+    pub subtract: bool,
     //set if there was was an oveflow from the lower nibble to the upper nibble
-    half_carry: bool,
-    //
-    carry: bool,
+    pub half_carry: bool,
+    //set if there was an overflow out of the whole byte (or word)
+    pub carry: bool,
 }
 
 const ZERO_BYTE_POSITION: u8 = 7;
@@ -37,10 +86,10 @@ const CARRY_BYTE_POSITION: u8 = 4;
 
 impl std::convert::From<FlagsRegister> for u8{
     fn from(flag: FlagsRegister) -> u8 {
-        if(flag.zero {1} else {0}) << ZERO_BYTE_POSITION |
-        if(flag.subtract {1} else {0}) << SUBTRACT_BYTE_POSITION |
-        if(flag.half_carry {1} else {0}) << HALF_CARRY_BYTE_POSITION |
-        if(flag.carry {1} else {0}) << CARRY_BYTE_POSITION
+        (if flag.zero {1} else {0}) << ZERO_BYTE_POSITION |
+        (if flag.subtract {1} else {0}) << SUBTRACT_BYTE_POSITION |
+        (if flag.half_carry {1} else {0}) << HALF_CARRY_BYTE_POSITION |
+        (if flag.carry {1} else {0}) << CARRY_BYTE_POSITION
     }
 }
 
@@ -58,4 +107,4 @@ impl std::convert::From<u8> for FlagsRegister {
             carry,
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,202 @@
+use std::f64::consts::PI;
+
+use crate::apu::NATIVE_SAMPLE_RATE;
+
+// taps of the band-limited step, also the latency of the output in samples
+const KERNEL_WIDTH: usize = 16;
+// sub-sample positions the kernel is precomputed for
+const KERNEL_PHASES: usize = 64;
+// passband as a fraction of the output Nyquist frequency
+const CUTOFF: f64 = 0.9;
+// dynamic rate control never stretches or squeezes the audio by more than 0.5%
+const MAX_RATE_DEVIATION: f64 = 0.005;
+
+// Turns the APU's stepped native-rate output into host rate audio by band-limited synthesis:
+// every change of the input level is added to the output as a windowed sinc step, so nothing
+// above the output Nyquist frequency is left to alias.
+pub struct Resampler {
+    output_rate: u32,
+    // 1.0 plays at the nominal rate, set by dynamic rate control
+    rate_adjustment: f64,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    // time of the next input sample, in output samples from the start of the delta buffers
+    time: f64,
+    last_input: [f32; 2],
+    // band-limited level changes per channel, integrated into samples on read
+    deltas: [Vec<f32>; 2],
+    levels: [f32; 2],
+}
+
+impl Resampler {
+    pub fn new(output_rate: u32) -> Resampler {
+        Resampler {
+            output_rate,
+            rate_adjustment: 1.0,
+            kernel: build_kernel(),
+            time: 0.0,
+            last_input: [0.0; 2],
+            deltas: [Vec::new(), Vec::new()],
+            levels: [0.0; 2],
+        }
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    pub fn set_output_rate(&mut self, output_rate: u32) {
+        self.output_rate = output_rate;
+    }
+
+    pub fn set_rate_adjustment(&mut self, rate_adjustment: f64) {
+        self.rate_adjustment = rate_adjustment.clamp(1.0 - MAX_RATE_DEVIATION, 1.0 + MAX_RATE_DEVIATION);
+    }
+
+    // dynamic rate control - produce slightly more audio when the host buffer runs low and slightly
+    // less when it fills up, so audio stays locked to video without pops or drift
+    pub fn update_rate_control(&mut self, queued_frames: usize, capacity_frames: usize) {
+        if capacity_frames == 0 {
+            return;
+        }
+        let fill = queued_frames.min(capacity_frames) as f64 / capacity_frames as f64;
+        self.set_rate_adjustment(1.0 + MAX_RATE_DEVIATION * (1.0 - 2.0 * fill));
+    }
+
+    // feed stereo samples at the APU's native rate
+    pub fn push(&mut self, samples: &[(f32, f32)]) {
+        let step = self.output_rate as f64 * self.rate_adjustment / NATIVE_SAMPLE_RATE as f64;
+        for &(left, right) in samples {
+            for (channel, level) in [left, right].into_iter().enumerate() {
+                let delta = level - self.last_input[channel];
+                if delta != 0.0 {
+                    self.add_delta(channel, delta);
+                    self.last_input[channel] = level;
+                }
+            }
+            self.time += step;
+        }
+    }
+
+    fn add_delta(&mut self, channel: usize, delta: f32) {
+        let start = self.time as usize;
+        let phase = ((self.time - start as f64) * KERNEL_PHASES as f64).round() as usize;
+        let buffer = &mut self.deltas[channel];
+        if buffer.len() < start + KERNEL_WIDTH {
+            buffer.resize(start + KERNEL_WIDTH, 0.0);
+        }
+        for (slot, tap) in buffer[start..start + KERNEL_WIDTH].iter_mut().zip(self.kernel[phase].iter()) {
+            *slot += delta * tap;
+        }
+    }
+
+    // number of output frames that are complete and can be read
+    pub fn available(&self) -> usize {
+        self.time as usize
+    }
+
+    // append the finished frames as interleaved stereo f32
+    pub fn read_f32(&mut self, output: &mut Vec<f32>) {
+        for (left, right) in self.read_frames() {
+            output.push(left);
+            output.push(right);
+        }
+    }
+
+    // append the finished frames as interleaved stereo i16
+    pub fn read_i16(&mut self, output: &mut Vec<i16>) {
+        for (left, right) in self.read_frames() {
+            output.push(to_i16(left));
+            output.push(to_i16(right));
+        }
+    }
+
+    fn read_frames(&mut self) -> Vec<(f32, f32)> {
+        let count = self.available();
+        let mut frames = Vec::with_capacity(count);
+        for index in 0..count {
+            let mut frame = [0.0; 2];
+            for (channel, value) in frame.iter_mut().enumerate() {
+                self.levels[channel] += self.deltas[channel].get(index).copied().unwrap_or(0.0);
+                *value = self.levels[channel];
+            }
+            frames.push((frame[0], frame[1]));
+        }
+        for buffer in self.deltas.iter_mut() {
+            buffer.drain(..count.min(buffer.len()));
+        }
+        self.time -= count as f64;
+        frames
+    }
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+// Blackman-windowed sinc impulses for each sub-sample phase, every phase sums to 1 so the
+// integrated steps reach exactly the input level
+fn build_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    let half_width = KERNEL_WIDTH as f64 / 2.0;
+    (0..=KERNEL_PHASES)
+        .map(|phase| {
+            let offset = phase as f64 / KERNEL_PHASES as f64;
+            let mut taps = [0.0f64; KERNEL_WIDTH];
+            for (tap, value) in taps.iter_mut().enumerate() {
+                let x = tap as f64 + 1.0 - half_width - offset;
+                let sinc = if x == 0.0 { 1.0 } else { (PI * CUTOFF * x).sin() / (PI * CUTOFF * x) };
+                let window = 0.42 + 0.5 * (PI * x / half_width).cos() + 0.08 * (2.0 * PI * x / half_width).cos();
+                *value = if x.abs() < half_width { sinc * window } else { 0.0 };
+            }
+            let sum: f64 = taps.iter().sum();
+            let mut normalized = [0.0f32; KERNEL_WIDTH];
+            for (target, value) in normalized.iter_mut().zip(taps.iter()) {
+                *target = (value / sum) as f32;
+            }
+            normalized
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_kernel_phase_has_unit_dc_gain() {
+        for taps in build_kernel() {
+            let sum: f32 = taps.iter().sum();
+            assert!((sum - 1.0).abs() < 1e-5, "phase sums to {}", sum);
+        }
+    }
+
+    #[test]
+    fn a_constant_input_settles_at_its_level() {
+        let mut resampler = Resampler::new(48000);
+        resampler.push(&vec![(0.5, -0.25); NATIVE_SAMPLE_RATE as usize / 100]);
+        let mut output = Vec::new();
+        resampler.read_f32(&mut output);
+        // 10 ms at 48 kHz, give or take the fraction still in progress
+        assert!((479..=480).contains(&(output.len() / 2)), "{} frames", output.len() / 2);
+        // past the kernel the step is complete
+        for frame in output[KERNEL_WIDTH * 2..].chunks(2) {
+            assert!((frame[0] - 0.5).abs() < 1e-4, "left is {}", frame[0]);
+            assert!((frame[1] + 0.25).abs() < 1e-4, "right is {}", frame[1]);
+        }
+    }
+
+    #[test]
+    fn rate_adjustment_keeps_the_level() {
+        let mut resampler = Resampler::new(44100);
+        resampler.set_rate_adjustment(1.1);
+        // odd sample counts between changes put the steps at every sub-sample phase
+        let input: Vec<(f32, f32)> = (0..NATIVE_SAMPLE_RATE as usize / 50)
+            .map(|index| if (index / 37) % 2 == 0 { (0.8, 0.8) } else { (0.2, 0.2) })
+            .chain(std::iter::repeat_n((0.6, 0.6), NATIVE_SAMPLE_RATE as usize / 100))
+            .collect();
+        resampler.push(&input);
+        let mut output = Vec::new();
+        resampler.read_i16(&mut output);
+        let last = *output.last().unwrap();
+        assert!((last as f32 / i16::MAX as f32 - 0.6).abs() < 1e-3, "level is {}", last);
+    }
+}
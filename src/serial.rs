@@ -0,0 +1,366 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const SERIAL_INTERRUPT: u8 = 1 << 3;
+
+const SC_TRANSFER: u8 = 0x80;
+const SC_FAST_CLOCK: u8 = 0x02;
+const SC_INTERNAL_CLOCK: u8 = 0x01;
+
+// the internal clock shifts one bit per falling edge of this bit of the DIV counter: 8192 Hz,
+// or 262144 Hz with the CGB fast clock
+const NORMAL_CLOCK_BIT: u16 = 8;
+const FAST_CLOCK_BIT: u16 = 3;
+
+// whatever is plugged into the other end of the link cable
+pub trait SerialDevice {
+    // this Game Boy starts clocking out a byte with its internal clock, returns the byte the device
+    // shifts back in over the same 8 clocks
+    fn transfer(&mut self, outgoing: u8) -> u8;
+
+    // polled while this Game Boy waits for an external clock with outgoing in SB, returns the byte
+    // received once the device has clocked a whole byte
+    fn external_transfer(&mut self, _outgoing: u8) -> Option<u8> {
+        None
+    }
+
+    // called once per T-cycle before anything else, for devices that keep time
+    fn tick(&mut self) {}
+}
+
+// records every byte the Game Boy sends and answers with 0xFF like an open port, test ROMs print
+// their results this way. Clones share the same buffer, so a clone can be kept to read it back.
+#[derive(Clone, Default)]
+pub struct SerialCapture {
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl SerialCapture {
+    pub fn new() -> SerialCapture {
+        SerialCapture::default()
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.output.borrow().clone()
+    }
+
+    // the captured bytes as text, anything that isn't valid UTF-8 is replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.output.borrow()).into_owned()
+    }
+
+    pub fn clear(&self) {
+        self.output.borrow_mut().clear();
+    }
+
+    // the bytes captured since the last call, emptying the buffer so long runs don't keep copying it
+    pub fn take_bytes(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.output.borrow_mut())
+    }
+}
+
+impl SerialDevice for SerialCapture {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.output.borrow_mut().push(outgoing);
+        0xFF
+    }
+}
+
+// SB/SC at 0xFF01/0xFF02
+pub struct SerialPort {
+    sb: u8,
+    sc: u8,
+    // the fast clock bit of SC only exists on CGB
+    cgb_mode: bool,
+    // bits left in the running transfer
+    bits: u8,
+    // byte being shifted in from the other side, MSB first
+    incoming: u8,
+    device: Option<Box<dyn SerialDevice>>,
+    // internally clocked transfers that started (with the byte sent) or finished since last asked,
+    // for a link cable coordinating two ports
+    started: Option<u8>,
+    finished: bool,
+    // an externally clocked transfer was completed from outside, the interrupt goes out on the next tick
+    received: bool,
+}
+
+impl SerialPort {
+    pub fn new() -> SerialPort {
+        SerialPort {
+            sb: 0,
+            sc: 0,
+            cgb_mode: false,
+            bits: 0,
+            incoming: 0xFF,
+            device: None,
+            started: None,
+            finished: false,
+            received: false,
+        }
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+    }
+
+    // plug a device into the link port, returning the one that was there
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) -> Option<Box<dyn SerialDevice>> {
+        self.device.replace(device)
+    }
+
+    pub fn disconnect(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.device.take()
+    }
+
+    pub fn device(&self) -> Option<&dyn SerialDevice> {
+        self.device.as_deref()
+    }
+
+    pub fn device_mut(&mut self) -> Option<&mut (dyn SerialDevice + 'static)> {
+        self.device.as_deref_mut()
+    }
+
+    // waiting for the other side to clock a transfer
+    pub fn is_listening(&self) -> bool {
+        self.sc & (SC_TRANSFER | SC_INTERNAL_CLOCK) == SC_TRANSFER
+    }
+
+    pub fn take_transfer_started(&mut self) -> Option<u8> {
+        self.started.take()
+    }
+
+    pub fn take_transfer_finished(&mut self) -> bool {
+        std::mem::replace(&mut self.finished, false)
+    }
+
+    // replace what the running internally clocked transfer shifts in, for the bits not clocked yet
+    pub fn set_incoming(&mut self, byte: u8) {
+        if self.bits > 0 {
+            self.incoming = byte << (8 - self.bits);
+        }
+    }
+
+    // finish an externally clocked transfer driven from outside with the byte that was clocked in
+    pub fn receive(&mut self, byte: u8) {
+        if self.is_listening() {
+            self.sb = byte;
+            self.sc &= !SC_TRANSFER;
+            self.received = true;
+        }
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF01 => self.sb,
+            0xFF02 => {
+                let unused = if self.cgb_mode { 0x7C } else { 0x7E };
+                self.sc | unused
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF01 => self.sb = value,
+            0xFF02 => {
+                let mut mask = SC_TRANSFER | SC_INTERNAL_CLOCK;
+                if self.cgb_mode {
+                    mask |= SC_FAST_CLOCK;
+                }
+                self.sc = value & mask;
+                self.bits = 0;
+                if self.sc & (SC_TRANSFER | SC_INTERNAL_CLOCK) == SC_TRANSFER | SC_INTERNAL_CLOCK {
+                    self.bits = 8;
+                    self.started = Some(self.sb);
+                    // with nothing connected the input line floats high
+                    self.incoming = match self.device.as_mut() {
+                        Some(device) => device.transfer(self.sb),
+                        None => 0xFF,
+                    };
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // one T-cycle, returns the requested interrupt
+    pub fn tick(&mut self) -> u8 {
+        if let Some(device) = self.device.as_mut() {
+            device.tick();
+        }
+        if std::mem::replace(&mut self.received, false) {
+            return SERIAL_INTERRUPT;
+        }
+        if !self.is_listening() {
+            return 0;
+        }
+        let received = self.device.as_mut().and_then(|device| device.external_transfer(self.sb));
+        match received {
+            Some(byte) => {
+                self.sb = byte;
+                self.complete()
+            }
+            None => 0,
+        }
+    }
+
+    // the internal clock, given the DIV counter before and after it changed, returns the requested interrupt
+    pub fn clock(&mut self, old_counter: u16, counter: u16) -> u8 {
+        if self.sc & (SC_TRANSFER | SC_INTERNAL_CLOCK) != SC_TRANSFER | SC_INTERNAL_CLOCK {
+            return 0;
+        }
+        let bit = if self.sc & SC_FAST_CLOCK != 0 { FAST_CLOCK_BIT } else { NORMAL_CLOCK_BIT };
+        if old_counter & (1 << bit) == 0 || counter & (1 << bit) != 0 {
+            return 0;
+        }
+        // SB shifts out MSB first while the other side's bits come in at the bottom
+        self.sb = (self.sb << 1) | (self.incoming >> 7);
+        self.incoming <<= 1;
+        self.bits -= 1;
+        if self.bits == 0 {
+            self.finished = true;
+            self.complete()
+        } else {
+            0
+        }
+    }
+
+    fn complete(&mut self) -> u8 {
+        self.sc &= !SC_TRANSFER;
+        self.bits = 0;
+        SERIAL_INTERRUPT
+    }
+}
+
+impl Default for SerialPort {
+    fn default() -> Self {
+        SerialPort::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // answers every transfer with the same byte
+    struct Echo(u8);
+
+    impl SerialDevice for Echo {
+        fn transfer(&mut self, _outgoing: u8) -> u8 {
+            self.0
+        }
+    }
+
+    // runs the DIV counter from 0 until the port raises its interrupt, returns the T-cycles that took
+    fn cycles_to_interrupt(port: &mut SerialPort, limit: u32) -> Option<u32> {
+        let mut counter = 0u16;
+        for cycle in 1..=limit {
+            let next = counter.wrapping_add(1);
+            let interrupt = port.tick() | port.clock(counter, next);
+            counter = next;
+            if interrupt & SERIAL_INTERRUPT != 0 {
+                return Some(cycle);
+            }
+        }
+        None
+    }
+
+    fn start(port: &mut SerialPort, sb: u8, sc: u8) {
+        port.write_register(0xFF01, sb);
+        port.write_register(0xFF02, sc);
+    }
+
+    #[test]
+    fn an_internally_clocked_byte_takes_8_clocks_at_8192_hz() {
+        let mut port = SerialPort::new();
+        start(&mut port, 0x42, 0x81);
+        assert_eq!(port.take_transfer_started(), Some(0x42));
+        assert_eq!(cycles_to_interrupt(&mut port, 10000), Some(8 * 512));
+        // nothing connected shifts in ones
+        assert_eq!(port.read_register(0xFF01), 0xFF);
+        assert_eq!(port.read_register(0xFF02), 0x7F);
+        assert!(port.take_transfer_finished());
+    }
+
+    #[test]
+    fn the_fast_clock_only_exists_on_cgb() {
+        let mut port = SerialPort::new();
+        start(&mut port, 0x00, 0x83);
+        assert_eq!(port.read_register(0xFF02), 0xFF);
+        assert_eq!(cycles_to_interrupt(&mut port, 10000), Some(8 * 512));
+        let mut port = SerialPort::new();
+        port.set_cgb_mode(true);
+        start(&mut port, 0x00, 0x83);
+        assert_eq!(port.read_register(0xFF02), 0xFF);
+        assert_eq!(cycles_to_interrupt(&mut port, 10000), Some(8 * 16));
+        assert_eq!(port.read_register(0xFF02), 0x7F);
+    }
+
+    #[test]
+    fn the_received_byte_is_shifted_in_msb_first() {
+        let mut port = SerialPort::new();
+        port.connect(Box::new(Echo(0x5A)));
+        start(&mut port, 0x81, 0x81);
+        // after 4 clocks the top half of SB has gone out and the top half of 0x5A has come in
+        for counter in 0..4 * 512 {
+            port.clock(counter, counter + 1);
+        }
+        assert_eq!(port.read_register(0xFF01), 0x15);
+        assert!(cycles_to_interrupt(&mut port, 10000).is_some());
+        assert_eq!(port.read_register(0xFF01), 0x5A);
+    }
+
+    #[test]
+    fn an_externally_clocked_transfer_waits_for_the_other_side() {
+        let mut port = SerialPort::new();
+        start(&mut port, 0x42, 0x80);
+        assert!(port.is_listening());
+        assert_eq!(port.take_transfer_started(), None);
+        assert_eq!(cycles_to_interrupt(&mut port, 20000), None);
+        port.receive(0x24);
+        assert_eq!(port.read_register(0xFF01), 0x24);
+        assert_eq!(port.tick(), SERIAL_INTERRUPT);
+        assert!(!port.is_listening());
+    }
+
+    #[test]
+    fn clearing_the_transfer_bit_stops_a_transfer() {
+        let mut port = SerialPort::new();
+        start(&mut port, 0x42, 0x81);
+        port.write_register(0xFF02, 0x01);
+        assert_eq!(cycles_to_interrupt(&mut port, 10000), None);
+    }
+
+    #[test]
+    fn serial_capture_records_what_is_sent_and_answers_0xff() {
+        let capture = SerialCapture::new();
+        let mut port = SerialPort::new();
+        port.connect(Box::new(capture.clone()));
+        for &byte in b"ok\xff" {
+            start(&mut port, byte, 0x81);
+            assert!(cycles_to_interrupt(&mut port, 10000).is_some());
+            assert_eq!(port.read_register(0xFF01), 0xFF);
+        }
+        // the clone kept outside sees what the connected one captured
+        assert_eq!(capture.bytes(), b"ok\xff");
+        assert_eq!(capture.text(), "ok\u{FFFD}");
+        assert_eq!(capture.take_bytes(), b"ok\xff");
+        assert!(capture.bytes().is_empty());
+        start(&mut port, b'!', 0x81);
+        assert_eq!(capture.text(), "!");
+        capture.clear();
+        assert_eq!(capture.text(), "");
+    }
+
+    #[test]
+    fn serial_capture_ignores_externally_clocked_transfers() {
+        let capture = SerialCapture::new();
+        let mut port = SerialPort::new();
+        port.connect(Box::new(capture.clone()));
+        start(&mut port, 0x42, 0x80);
+        assert_eq!(cycles_to_interrupt(&mut port, 10000), None);
+        assert!(capture.bytes().is_empty());
+    }
+}
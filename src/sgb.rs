@@ -0,0 +1,546 @@
+use crate::compatpalette::CompatibilityPalette;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// the SNES picture, the Game Boy screen sits in the middle of the border
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+// packets are 16 bytes sent LSB first through JOYP, followed by a 0 stop bit
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+
+// attributes pick one of the four palettes per 8x8 cell of the screen
+const COLUMNS: usize = SCREEN_WIDTH / 8;
+const ROWS: usize = SCREEN_HEIGHT / 8;
+const ATTRIBUTE_FILE_SIZE: usize = COLUMNS * ROWS / 4;
+const ATTRIBUTE_FILE_COUNT: usize = 45;
+
+// PAL_TRN fills 512 palettes of 4 colors that PAL_SET picks from
+const SYSTEM_PALETTE_COUNT: usize = 512;
+
+// VRAM transfers move 4KB, read off the screen of the next frame as 256 tiles in 2bpp format
+const TRANSFER_SIZE: usize = 0x1000;
+const TRANSFER_TILES: usize = TRANSFER_SIZE / 16;
+
+// the border is a 32x28 map of SNES 4bpp tiles using palettes 4-7
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_TILES: usize = 256;
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_PALETTES_OFFSET: usize = 0x800;
+const BORDER_X_FLIP: u16 = 1 << 14;
+const BORDER_Y_FLIP: u16 = 1 << 15;
+
+// what MASK_EN shows in place of the game
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Mask {
+    None,
+    // keep showing the last picture
+    Freeze,
+    Black,
+    Color0,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Transfer {
+    Palettes,
+    Attributes,
+    // the lower or upper 128 border tiles
+    BorderTiles(bool),
+    BorderMap,
+}
+
+// the Super Game Boy side: command packets from the game, colorization and the border around the screen
+pub struct Sgb {
+    // the SGB BIOS only listens to games flagged for SGB in their header
+    commands_enabled: bool,
+    // P14 and P15 as last written to JOYP
+    joypad_lines: u8,
+    // next bit of the packet being received, none between packets
+    receive_bit: Option<usize>,
+    packet: [u8; PACKET_SIZE],
+    // packets of the command so far, the first one says how many there are
+    command: Vec<u8>,
+    // color 0 is shared, the one of palette 0 is the backdrop
+    palettes: [[u16; 4]; 4],
+    system_palettes: [u16; SYSTEM_PALETTE_COUNT * 4],
+    attributes: [u8; COLUMNS * ROWS],
+    attribute_files: [u8; ATTRIBUTE_FILE_SIZE * ATTRIBUTE_FILE_COUNT],
+    mask: Mask,
+    // a VRAM transfer waiting for the end of the frame
+    transfer: Option<Transfer>,
+    border_tiles: [u8; BORDER_TILES * BORDER_TILE_SIZE],
+    border_map: [u16; BORDER_MAP_WIDTH * SGB_HEIGHT / 8],
+    border_palettes: [[u16; 16]; 4],
+    // MLT_REQ, the player whose ID JOYP reads when no lines are selected
+    players: u8,
+    player: u8,
+    // the colorized Game Boy screen and the whole picture with the border, RGB555
+    screen: [u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+    frame_buffer: [u16; SGB_WIDTH * SGB_HEIGHT],
+}
+
+impl Sgb {
+    pub fn new(commands_enabled: bool) -> Sgb {
+        Sgb {
+            commands_enabled,
+            joypad_lines: 0x30,
+            receive_bit: None,
+            packet: [0; PACKET_SIZE],
+            command: Vec::new(),
+            palettes: [CompatibilityPalette::GRAYSCALE.bg; 4],
+            system_palettes: [0; SYSTEM_PALETTE_COUNT * 4],
+            attributes: [0; COLUMNS * ROWS],
+            attribute_files: [0; ATTRIBUTE_FILE_SIZE * ATTRIBUTE_FILE_COUNT],
+            mask: Mask::None,
+            transfer: None,
+            border_tiles: [0; BORDER_TILES * BORDER_TILE_SIZE],
+            border_map: [0; BORDER_MAP_WIDTH * SGB_HEIGHT / 8],
+            border_palettes: [[0; 16]; 4],
+            players: 1,
+            player: 0,
+            screen: [CompatibilityPalette::GRAYSCALE.bg[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_buffer: [CompatibilityPalette::GRAYSCALE.bg[0]; SGB_WIDTH * SGB_HEIGHT],
+        }
+    }
+
+    // 256x224 RGB555, the colorized screen inside the border
+    pub fn frame_buffer(&self) -> &[u16] {
+        &self.frame_buffer
+    }
+
+    // both lines low starts a packet. Every bit is then one line pulled low, P15 for a 1 and P14 for a 0,
+    // and released again.
+    pub fn write_joypad(&mut self, value: u8) {
+        let lines = value & 0x30;
+        let previous = std::mem::replace(&mut self.joypad_lines, lines);
+        match lines {
+            0x00 => {
+                self.receive_bit = Some(0);
+                self.packet = [0; PACKET_SIZE];
+            }
+            0x10 | 0x20 if previous == 0x30 => {
+                if let Some(bit) = self.receive_bit {
+                    self.receive(bit, lines == 0x10);
+                }
+            }
+            // releasing P15 outside of a packet moves on to the next controller
+            0x30 if previous == 0x10 && self.receive_bit.is_none() => self.player = (self.player + 1) % self.players,
+            _ => {}
+        }
+    }
+
+    // the low bits of JOYP with neither line selected, 0x0F for the first controller down to 0x0C
+    pub fn joypad_id(&self) -> u8 {
+        0x0F - self.player
+    }
+
+    fn receive(&mut self, bit: usize, one: bool) {
+        if bit == PACKET_BITS {
+            self.receive_bit = None;
+            // a packet without its stop bit is dropped
+            if !one {
+                self.receive_packet();
+            }
+            return;
+        }
+        if one {
+            self.packet[bit / 8] |= 1 << (bit % 8);
+        }
+        self.receive_bit = Some(bit + 1);
+    }
+
+    fn receive_packet(&mut self) {
+        if !self.commands_enabled || (self.command.is_empty() && self.packet[0] & 0x07 == 0) {
+            return;
+        }
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0x07) as usize;
+        if self.command.len() == packets * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.run_command(&command);
+        }
+    }
+
+    fn run_command(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            0x00 => self.set_palette_pair(0, 1, data),
+            0x01 => self.set_palette_pair(2, 3, data),
+            0x02 => self.set_palette_pair(0, 3, data),
+            0x03 => self.set_palette_pair(1, 2, data),
+            0x04 => self.attribute_blocks(data),
+            0x05 => self.attribute_lines(data),
+            0x06 => self.attribute_divide(data),
+            0x07 => self.attribute_characters(data),
+            0x0A => self.set_system_palettes(data),
+            0x0B => self.transfer = Some(Transfer::Palettes),
+            0x11 => {
+                self.players = match data[1] & 0x03 {
+                    0x01 => 2,
+                    0x03 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            0x13 => self.transfer = Some(Transfer::BorderTiles(data[1] & 0x01 != 0)),
+            0x14 => self.transfer = Some(Transfer::BorderMap),
+            0x15 => self.transfer = Some(Transfer::Attributes),
+            0x16 => self.set_attribute_file(data[1]),
+            0x17 => {
+                self.mask = match data[1] & 0x03 {
+                    0x00 => Mask::None,
+                    0x01 => Mask::Freeze,
+                    0x02 => Mask::Black,
+                    _ => Mask::Color0,
+                }
+            }
+            // sound, SNES code uploads and the like don't change the picture
+            _ => {}
+        }
+    }
+
+    // PAL01, PAL23, PAL03 and PAL12: the shared color 0 and colors 1-3 of two palettes
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color0 = color(data, 1);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color0;
+        }
+        for index in 0..3 {
+            self.palettes[first][index + 1] = color(data, 3 + index * 2);
+            self.palettes[second][index + 1] = color(data, 9 + index * 2);
+        }
+    }
+
+    // ATTR_BLK, rectangles with a palette each for the inside, the surrounding line and the outside
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let count = (data[1] & 0x1F) as usize;
+        for set in data[2..].chunks_exact(6).take(count) {
+            let control = set[0] & 0x07;
+            let area = |enabled: u8, shift: u8| (control & enabled != 0).then_some((set[1] >> shift) & 0x03);
+            let inside = area(0x01, 0);
+            let outside = area(0x04, 4);
+            // changing only the inside or only the outside takes the line along
+            let line = match control {
+                0x01 => inside,
+                0x04 => outside,
+                _ => area(0x02, 2),
+            };
+            let (left, top, right, bottom) =
+                ((set[2] & 0x1F) as usize, (set[3] & 0x1F) as usize, (set[4] & 0x1F) as usize, (set[5] & 0x1F) as usize);
+            for y in 0..ROWS {
+                for x in 0..COLUMNS {
+                    let palette = if x > left && x < right && y > top && y < bottom {
+                        inside
+                    } else if x < left || x > right || y < top || y > bottom {
+                        outside
+                    } else {
+                        line
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * COLUMNS + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    // ATTR_LIN, whole rows or columns
+    fn attribute_lines(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let index = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                if index < ROWS {
+                    self.attributes[index * COLUMNS..(index + 1) * COLUMNS].fill(palette);
+                }
+            } else if index < COLUMNS {
+                for y in 0..ROWS {
+                    self.attributes[y * COLUMNS + index] = palette;
+                }
+            }
+        }
+    }
+
+    // ATTR_DIV, the screen split in two at a row or column, with a palette for the dividing line itself
+    fn attribute_divide(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let split = (data[2] & 0x1F) as usize;
+        for y in 0..ROWS {
+            for x in 0..COLUMNS {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * COLUMNS + x] = match position.cmp(&split) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    // ATTR_CHR, a run of cells from a starting cell, left to right or top to bottom, four per byte
+    fn attribute_characters(&mut self, data: &[u8]) {
+        let (mut x, mut y) = ((data[1] & 0x1F) as usize, (data[2] & 0x1F) as usize);
+        let count = u16::from_le_bytes([data[3], data[4]]) as usize;
+        let vertical = data[5] & 0x01 != 0;
+        for index in 0..count.min((data.len() - 6) * 4) {
+            if x >= COLUMNS || y >= ROWS {
+                break;
+            }
+            self.attributes[y * COLUMNS + x] = (data[6 + index / 4] >> (6 - (index % 4) * 2)) & 0x03;
+            if vertical {
+                y += 1;
+                if y == ROWS {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == COLUMNS {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    // PAL_SET, four palettes out of the ones sent with PAL_TRN, optionally with an attribute file
+    fn set_system_palettes(&mut self, data: &[u8]) {
+        for (palette, number) in self.palettes.iter_mut().zip(data[1..9].chunks_exact(2)) {
+            let index = (u16::from_le_bytes([number[0], number[1]]) as usize % SYSTEM_PALETTE_COUNT) * 4;
+            palette.copy_from_slice(&self.system_palettes[index..index + 4]);
+        }
+        if data[9] & 0x80 != 0 {
+            self.set_attribute_file(data[9]);
+        }
+    }
+
+    // ATTR_SET and the attribute part of PAL_SET, bit 6 also lifts the mask
+    fn set_attribute_file(&mut self, value: u8) {
+        let file = (value & 0x3F) as usize;
+        if file < ATTRIBUTE_FILE_COUNT {
+            let start = file * ATTRIBUTE_FILE_SIZE;
+            let bytes = &self.attribute_files[start..start + ATTRIBUTE_FILE_SIZE];
+            for (cell, attribute) in self.attributes.iter_mut().enumerate() {
+                *attribute = (bytes[cell / 4] >> (6 - (cell % 4) * 2)) & 0x03;
+            }
+        }
+        if value & 0x40 != 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    // called with the shades of every finished frame, which is also what VRAM transfers read
+    pub fn finish_frame(&mut self, shades: &[u8]) {
+        if let Some(transfer) = self.transfer.take() {
+            self.finish_transfer(transfer, &transfer_data(shades));
+        }
+        match self.mask {
+            Mask::None => {
+                for (index, (pixel, &shade)) in self.screen.iter_mut().zip(shades).enumerate() {
+                    let cell = (index / SCREEN_WIDTH / 8) * COLUMNS + (index % SCREEN_WIDTH) / 8;
+                    let palette = if shade == 0 { 0 } else { self.attributes[cell] as usize };
+                    *pixel = self.palettes[palette][shade as usize];
+                }
+            }
+            Mask::Freeze => {}
+            Mask::Black => self.screen.fill(0x0000),
+            Mask::Color0 => self.screen.fill(self.palettes[0][0]),
+        }
+        self.render();
+    }
+
+    fn finish_transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        match transfer {
+            Transfer::Palettes => {
+                for (index, entry) in self.system_palettes.iter_mut().enumerate() {
+                    *entry = color(data, index * 2);
+                }
+            }
+            Transfer::Attributes => self.attribute_files.copy_from_slice(&data[..ATTRIBUTE_FILE_SIZE * ATTRIBUTE_FILE_COUNT]),
+            Transfer::BorderTiles(upper) => {
+                let start = if upper { TRANSFER_SIZE } else { 0 };
+                self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(data);
+            }
+            Transfer::BorderMap => {
+                for (index, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+                }
+                for (index, entry) in self.border_palettes.as_flattened_mut().iter_mut().enumerate() {
+                    *entry = color(data, BORDER_PALETTES_OFFSET + index * 2);
+                }
+            }
+        }
+    }
+
+    // the border is in front of the game, which shows through its color 0
+    fn render(&mut self) {
+        let backdrop = self.palettes[0][0];
+        for y in 0..SGB_HEIGHT {
+            for x in 0..SGB_WIDTH {
+                let on_screen = (SCREEN_X..SCREEN_X + SCREEN_WIDTH).contains(&x)
+                    && (SCREEN_Y..SCREEN_Y + SCREEN_HEIGHT).contains(&y);
+                self.frame_buffer[y * SGB_WIDTH + x] = match self.border_pixel(x, y) {
+                    Some(color) => color,
+                    None if on_screen => self.screen[(y - SCREEN_Y) * SCREEN_WIDTH + x - SCREEN_X],
+                    None => backdrop,
+                };
+            }
+        }
+    }
+
+    fn border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = self.border_map[(y / 8) * BORDER_MAP_WIDTH + x / 8];
+        let tile = &self.border_tiles[(entry & 0xFF) as usize * BORDER_TILE_SIZE..][..BORDER_TILE_SIZE];
+        let row = if entry & BORDER_Y_FLIP != 0 { 7 - y % 8 } else { y % 8 };
+        let bit = if entry & BORDER_X_FLIP != 0 { x % 8 } else { 7 - x % 8 };
+        // planes 0 and 1 interleaved per row, then planes 2 and 3
+        let planes = [tile[row * 2], tile[row * 2 + 1], tile[16 + row * 2], tile[16 + row * 2 + 1]];
+        let color = planes.iter().enumerate().fold(0, |color, (plane, &byte)| color | ((byte >> bit) & 1) << plane);
+        // the map numbers the palettes 4-7
+        (color != 0).then(|| self.border_palettes[((entry >> 10) & 0x03) as usize][color as usize])
+    }
+}
+
+fn color(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]]) & 0x7FFF
+}
+
+// the 4KB a VRAM transfer sends: the screen read as 20 tiles per row, each back in 2bpp tile format
+fn transfer_data(shades: &[u8]) -> Vec<u8> {
+    let mut data = vec![0; TRANSFER_SIZE];
+    for tile in 0..TRANSFER_TILES {
+        let (tile_x, tile_y) = (tile % COLUMNS, tile / COLUMNS);
+        for row in 0..8 {
+            let start = (tile_y * 8 + row) * SCREEN_WIDTH + tile_x * 8;
+            for (column, &shade) in shades[start..start + 8].iter().enumerate() {
+                data[tile * 16 + row * 2] |= (shade & 0x01) << (7 - column);
+                data[tile * 16 + row * 2 + 1] |= ((shade >> 1) & 0x01) << (7 - column);
+            }
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // clock a packet in through JOYP the way the SGB BIOS reads it, stop bit included
+    fn send_packet(sgb: &mut Sgb, packet: &[u8]) {
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        let mut bytes = [0; PACKET_SIZE];
+        bytes[..packet.len()].copy_from_slice(packet);
+        for bit in 0..PACKET_BITS {
+            let one = bytes[bit / 8] & (1 << (bit % 8)) != 0;
+            sgb.write_joypad(if one { 0x10 } else { 0x20 });
+            sgb.write_joypad(0x30);
+        }
+        sgb.write_joypad(0x20);
+        sgb.write_joypad(0x30);
+    }
+
+    // the screen shades a VRAM transfer of the given bytes is read from
+    fn transfer_shades(data: &[u8]) -> Vec<u8> {
+        let mut shades = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        for (tile, bytes) in data.chunks_exact(16).enumerate() {
+            let (tile_x, tile_y) = (tile % COLUMNS, tile / COLUMNS);
+            for row in 0..8 {
+                for column in 0..8 {
+                    let low = (bytes[row * 2] >> (7 - column)) & 0x01;
+                    let high = (bytes[row * 2 + 1] >> (7 - column)) & 0x01;
+                    shades[(tile_y * 8 + row) * SCREEN_WIDTH + tile_x * 8 + column] = high << 1 | low;
+                }
+            }
+        }
+        shades
+    }
+
+    #[test]
+    fn pal01_sets_the_shared_color_and_two_palettes() {
+        let mut sgb = Sgb::new(true);
+        let colors: [u16; 7] = [0x7FFF, 0x0001, 0x0002, 0x0003, 0x0011, 0x0012, 0x0013];
+        let mut packet = vec![0x01];
+        packet.extend(colors.iter().flat_map(|color| color.to_le_bytes()));
+        send_packet(&mut sgb, &packet);
+
+        assert_eq!(sgb.palettes[0], [0x7FFF, 0x0001, 0x0002, 0x0003]);
+        assert_eq!(sgb.palettes[1], [0x7FFF, 0x0011, 0x0012, 0x0013]);
+        assert_eq!(sgb.palettes[2][0], 0x7FFF);
+        assert_eq!(sgb.palettes[3][1..], CompatibilityPalette::GRAYSCALE.bg[1..]);
+
+        let mut shades = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        shades[0] = 2;
+        sgb.finish_frame(&shades);
+        let screen = SCREEN_Y * SGB_WIDTH + SCREEN_X;
+        assert_eq!(sgb.frame_buffer()[screen], 0x0002);
+        assert_eq!(sgb.frame_buffer()[screen + 1], 0x7FFF);
+    }
+
+    #[test]
+    fn ignores_packets_without_sgb_support() {
+        let mut sgb = Sgb::new(false);
+        send_packet(&mut sgb, &[0x01, 0x1F, 0x00]);
+        assert_eq!(sgb.palettes[0], CompatibilityPalette::GRAYSCALE.bg);
+    }
+
+    #[test]
+    fn attr_blk_colors_inside_line_and_outside() {
+        let mut sgb = Sgb::new(true);
+        // palettes 1 inside, 2 on the line and 3 outside of cells (2, 3) to (6, 8)
+        send_packet(&mut sgb, &[0x21, 0x01, 0x07, 0x39, 2, 3, 6, 8]);
+        let attribute = |x: usize, y: usize| sgb.attributes[y * COLUMNS + x];
+        assert_eq!(attribute(4, 5), 1);
+        assert_eq!(attribute(2, 3), 2);
+        assert_eq!(attribute(6, 5), 2);
+        assert_eq!(attribute(4, 8), 2);
+        assert_eq!(attribute(1, 5), 3);
+        assert_eq!(attribute(7, 5), 3);
+        assert_eq!(attribute(4, 9), 3);
+    }
+
+    #[test]
+    fn attr_blk_takes_the_line_along_with_only_the_inside() {
+        let mut sgb = Sgb::new(true);
+        send_packet(&mut sgb, &[0x21, 0x01, 0x01, 0x01, 2, 3, 6, 8]);
+        let attribute = |x: usize, y: usize| sgb.attributes[y * COLUMNS + x];
+        assert_eq!(attribute(4, 5), 1);
+        assert_eq!(attribute(2, 3), 1);
+        assert_eq!(attribute(7, 5), 0);
+    }
+
+    #[test]
+    fn chr_trn_and_pct_trn_draw_the_border() {
+        let mut sgb = Sgb::new(true);
+
+        // tile 1 has a single pixel of color 5 at the top left, planes 0 and 2
+        let mut tiles = vec![0; TRANSFER_SIZE];
+        tiles[BORDER_TILE_SIZE] = 0x80;
+        tiles[BORDER_TILE_SIZE + 16] = 0x80;
+        send_packet(&mut sgb, &[0x99, 0x00]);
+        sgb.finish_frame(&transfer_shades(&tiles));
+
+        // cell (0, 0) uses it with palette 5 flipped horizontally, cell (1, 0) flipped vertically
+        let mut map = vec![0; TRANSFER_SIZE];
+        map[0..2].copy_from_slice(&(1 | 5 << 10 | BORDER_X_FLIP).to_le_bytes());
+        map[2..4].copy_from_slice(&(1 | 5 << 10 | BORDER_Y_FLIP).to_le_bytes());
+        let color = BORDER_PALETTES_OFFSET + (16 + 5) * 2;
+        map[color..color + 2].copy_from_slice(&0x1234u16.to_le_bytes());
+        send_packet(&mut sgb, &[0xA1]);
+        sgb.finish_frame(&transfer_shades(&map));
+
+        sgb.finish_frame(&vec![0; SCREEN_WIDTH * SCREEN_HEIGHT]);
+        let frame = sgb.frame_buffer();
+        let backdrop = CompatibilityPalette::GRAYSCALE.bg[0];
+        assert_eq!(frame[7], 0x1234);
+        assert_eq!(frame[0], backdrop);
+        assert_eq!(frame[7 * SGB_WIDTH + 8], 0x1234);
+        assert_eq!(frame[8], backdrop);
+        assert_eq!(frame[SCREEN_Y * SGB_WIDTH + SCREEN_X], backdrop);
+    }
+}
@@ -0,0 +1,282 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
+
+use crate::serial::SerialDevice;
+
+// how many T-cycles a side may run ahead of the time the other side has promised, this is also how
+// long a transfer takes to reach the other side in emulated time
+pub const LOOKAHEAD: u64 = 8192;
+// T-cycles between time updates sent to the other side
+const SYNC_INTERVAL: u64 = 2048;
+// the other side is taken to be gone when it stays silent this long, its emulator has hung or been stopped
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MESSAGE_SIZE: usize = 10;
+const SYNC: u8 = 0;
+const TRANSFER: u8 = 1;
+const REPLY: u8 = 2;
+
+trait Stream: Read + Write {}
+
+impl<S: Read + Write> Stream for S {}
+
+// a link cable to an emulator in another process over a TCP or Unix domain socket.
+//
+// Both sides count T-cycles from the moment they were connected and tell each other how far they got.
+// A side never runs more than LOOKAHEAD cycles past the time the other side has promised, and a
+// transfer started at cycle t is seen by the other side at exactly t + LOOKAHEAD + 1, which it can't
+// have passed yet. The answer is the other side's SB if it is listening on the external clock at that
+// point and 0xFF otherwise. When both sides start clocking before seeing the other's transfer, the
+// cycles the transfers are stamped with decide: the earlier one reads the other side's SB and the later
+// one reads 0xFF, with the listening end of the connection winning a tie. None of this depends on how
+// fast either process runs, so exchanges are the same on every run.
+pub struct SocketLink {
+    stream: Option<Box<dyn Stream>>,
+    // cycles since connecting
+    time: u64,
+    // the other side won't start a transfer before this cycle
+    peer_time: u64,
+    last_sync: u64,
+    // a transfer from the other side and the cycle it was started at
+    pending: Option<(u64, u8)>,
+    // this side accepted the connection, it wins transfers started in the same cycle
+    listener: bool,
+}
+
+impl SocketLink {
+    pub fn connect_tcp<A: ToSocketAddrs>(address: A) -> io::Result<SocketLink> {
+        SocketLink::from_tcp(TcpStream::connect(address)?, false)
+    }
+
+    // wait for the other side to connect
+    pub fn listen_tcp<A: ToSocketAddrs>(address: A) -> io::Result<SocketLink> {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        SocketLink::from_tcp(stream, true)
+    }
+
+    fn from_tcp(stream: TcpStream, listener: bool) -> io::Result<SocketLink> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        Ok(SocketLink::new(stream, listener))
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<SocketLink> {
+        SocketLink::from_unix(UnixStream::connect(path)?, false)
+    }
+
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<Path>>(path: P) -> io::Result<SocketLink> {
+        let (stream, _) = UnixListener::bind(path)?.accept()?;
+        SocketLink::from_unix(stream, true)
+    }
+
+    #[cfg(unix)]
+    fn from_unix(stream: UnixStream, listener: bool) -> io::Result<SocketLink> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        Ok(SocketLink::new(stream, listener))
+    }
+
+    fn new<S: Read + Write + 'static>(stream: S, listener: bool) -> SocketLink {
+        SocketLink {
+            stream: Some(Box::new(stream)),
+            time: 0,
+            peer_time: 0,
+            last_sync: 0,
+            pending: None,
+            listener,
+        }
+    }
+
+    // false once the connection failed, from then on the port behaves as if nothing was plugged in
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn send(&mut self, kind: u8, time: u64, value: u8) {
+        let mut message = [0; MESSAGE_SIZE];
+        message[0] = kind;
+        message[1..9].copy_from_slice(&time.to_le_bytes());
+        message[9] = value;
+        if let Some(stream) = self.stream.as_mut() {
+            if stream.write_all(&message).and_then(|_| stream.flush()).is_err() {
+                self.stream = None;
+            }
+        }
+    }
+
+    fn send_sync(&mut self) {
+        if self.last_sync != self.time {
+            self.last_sync = self.time;
+            self.send(SYNC, self.time, 0);
+        }
+    }
+
+    // block for the next message, returns a reply to our own transfer when that's what came in. Running
+    // into the read timeout counts as a disconnect like any other read error.
+    fn receive(&mut self) -> Option<u8> {
+        let mut message = [0; MESSAGE_SIZE];
+        let stream = self.stream.as_mut()?;
+        if stream.read_exact(&mut message).is_err() {
+            self.stream = None;
+            return None;
+        }
+        let time = u64::from_le_bytes(message[1..9].try_into().unwrap());
+        match message[0] {
+            SYNC => self.peer_time = self.peer_time.max(time),
+            TRANSFER => {
+                // nothing else happens on the other side in the cycle it started a transfer
+                self.peer_time = self.peer_time.max(time + 1);
+                self.pending = Some((time, message[9]));
+            }
+            REPLY => return Some(message[9]),
+            _ => self.stream = None,
+        }
+        None
+    }
+
+    // a transfer from the other side crossed one of ours started at the given cycle
+    fn peer_started_first(&self, peer_started: u64, started: u64) -> bool {
+        peer_started < started || (peer_started == started && !self.listener)
+    }
+
+    // the cycle a transfer from the other side is seen at
+    fn arrival(started: u64) -> u64 {
+        started + LOOKAHEAD + 1
+    }
+}
+
+impl SerialDevice for SocketLink {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        let started = self.time;
+        self.send(TRANSFER, started, outgoing);
+        while self.stream.is_some() {
+            // a transfer from the other side that hasn't arrived yet, or comes in while this one waits for
+            // its answer, crossed this one. The other side reaches the same verdict from the same stamps.
+            if let Some((peer_started, _)) = self.pending.take() {
+                let reply = if self.peer_started_first(peer_started, started) { outgoing } else { 0xFF };
+                self.send(REPLY, 0, reply);
+            }
+            if let Some(reply) = self.receive() {
+                return reply;
+            }
+        }
+        0xFF
+    }
+
+    fn external_transfer(&mut self, outgoing: u8) -> Option<u8> {
+        match self.pending {
+            Some((started, byte)) if SocketLink::arrival(started) <= self.time => {
+                self.pending = None;
+                self.send(REPLY, 0, outgoing);
+                Some(byte)
+            }
+            _ => None,
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.stream.is_none() {
+            return;
+        }
+        // nobody was listening when the transfer arrived in the cycle that just ended
+        if let Some((started, _)) = self.pending {
+            if SocketLink::arrival(started) <= self.time {
+                self.pending = None;
+                self.send(REPLY, 0, 0xFF);
+            }
+        }
+        if self.time >= self.peer_time + LOOKAHEAD {
+            self.send_sync();
+            while self.stream.is_some() && self.time >= self.peer_time + LOOKAHEAD {
+                self.receive();
+            }
+        }
+        self.time += 1;
+        if self.time - self.last_sync >= SYNC_INTERVAL {
+            self.send_sync();
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // the listening end of the connection stays on the test thread, the connecting end runs the closure on
+    // a thread of its own since a link isn't Send
+    fn pair<T, F>(connecting: F) -> (SocketLink, thread::JoinHandle<T>)
+    where
+        T: Send + 'static,
+        F: FnOnce(SocketLink) -> T + Send + 'static,
+    {
+        let (listening, stream) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || connecting(SocketLink::from_unix(stream, false).unwrap()));
+        (SocketLink::from_unix(listening, true).unwrap(), handle)
+    }
+
+    // ticks like the serial port does while waiting for an external clock, returns the byte and the cycle
+    fn listen(link: &mut SocketLink, outgoing: u8) -> (u8, u64) {
+        loop {
+            link.tick();
+            if let Some(byte) = link.external_transfer(outgoing) {
+                return (byte, link.time);
+            }
+        }
+    }
+
+    #[test]
+    fn a_transfer_reaches_a_listening_side_after_the_lookahead() {
+        let (mut first, listening) = pair(|mut second| listen(&mut second, 0x22));
+        assert_eq!(first.transfer(0x11), 0x22);
+        assert_eq!(listening.join().unwrap(), (0x11, LOOKAHEAD + 1));
+    }
+
+    #[test]
+    fn the_listening_end_wins_crossing_transfers_started_in_the_same_cycle() {
+        let (mut first, connecting) = pair(|mut second| second.transfer(0x22));
+        assert_eq!(first.transfer(0x11), 0x22);
+        assert_eq!(connecting.join().unwrap(), 0xFF);
+    }
+
+    #[test]
+    fn the_earlier_of_two_crossing_transfers_wins() {
+        let (mut first, connecting) = pair(|mut second| {
+            second.tick();
+            second.transfer(0x22)
+        });
+        first.tick();
+        first.tick();
+        assert_eq!(first.transfer(0x11), 0xFF);
+        assert_eq!(connecting.join().unwrap(), 0x11);
+    }
+
+    #[test]
+    fn a_transfer_with_nobody_listening_reads_0xff() {
+        let (mut first, ticking) = pair(|mut second| {
+            for _ in 0..2 * LOOKAHEAD {
+                second.tick();
+            }
+            second.is_connected()
+        });
+        assert_eq!(first.transfer(0x11), 0xFF);
+        drop(first);
+        // the other side keeps ticking until it notices the connection went away
+        assert!(!ticking.join().unwrap());
+    }
+
+    #[test]
+    fn a_silent_peer_counts_as_a_disconnect() {
+        let (listening, _silent) = UnixStream::pair().unwrap();
+        listening.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let mut link = SocketLink::new(listening, true);
+        assert_eq!(link.transfer(0x11), 0xFF);
+        assert!(!link.is_connected());
+    }
+}
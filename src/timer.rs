@@ -0,0 +1,114 @@
+pub const TIMER_INTERRUPT: u8 = 1 << 2;
+
+// T-cycles between TIMA overflowing and TMA being loaded with the interrupt requested
+const OVERFLOW_DELAY: u8 = 4;
+
+// DIV is the upper byte of a 16-bit counter running at the T-cycle rate, TIMA counts
+// falling edges of one of its bits selected by TAC
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    overflow_delay: u8,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow_delay: 0,
+        }
+    }
+
+    pub fn div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    // the full internal counter, other hardware is clocked off its bits too
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF04 => self.div(),
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => self.tac | 0xF8,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            // any write clears the whole counter, which can itself produce a TIMA tick
+            0xFF04 => self.set_counter(0),
+            0xFF05 => {
+                // writing TIMA during the overflow delay cancels the reload
+                self.tima = value;
+                self.overflow_delay = 0;
+            }
+            0xFF06 => self.tma = value,
+            0xFF07 => {
+                let old_signal = self.timer_signal();
+                self.tac = value & 0x07;
+                if old_signal && !self.timer_signal() {
+                    self.increment_tima();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // one T-cycle, returns the requested interrupt
+    pub fn tick(&mut self) -> u8 {
+        let mut interrupts = 0;
+        if self.overflow_delay > 0 {
+            self.overflow_delay -= 1;
+            if self.overflow_delay == 0 {
+                self.tima = self.tma;
+                interrupts |= TIMER_INTERRUPT;
+            }
+        }
+        self.set_counter(self.counter.wrapping_add(1));
+        interrupts
+    }
+
+    // also puts the counter where a boot ROM would have left it
+    pub fn set_counter(&mut self, counter: u16) {
+        let old_signal = self.timer_signal();
+        self.counter = counter;
+        if old_signal && !self.timer_signal() {
+            self.increment_tima();
+        }
+    }
+
+    // the counter bit TIMA watches, gated by the enable bit of TAC
+    fn timer_signal(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        self.tac & 0x04 != 0 && (self.counter >> bit) & 1 != 0
+    }
+
+    fn increment_tima(&mut self) {
+        let (value, overflow) = self.tima.overflowing_add(1);
+        self.tima = value;
+        if overflow {
+            self.overflow_delay = OVERFLOW_DELAY;
+        }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
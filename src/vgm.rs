@@ -0,0 +1,182 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::apu::RegisterWrite;
+
+// VGM timestamps count samples at a fixed 44.1 kHz
+const VGM_SAMPLE_RATE: u64 = 44100;
+const CPU_CLOCK: u64 = 4_194_304;
+const VERSION: u32 = 0x0000_0161;
+const HEADER_SIZE: u32 = 0x100;
+const DATA_OFFSET_FIELD: u32 = 0x34;
+const DMG_CLOCK_FIELD: usize = 0x80;
+
+const WRITE_DMG: u8 = 0xB3;
+const WAIT: u8 = 0x61;
+const WAIT_NTSC_FRAME: u8 = 0x62;
+const WAIT_PAL_FRAME: u8 = 0x63;
+const WAIT_SHORT: u8 = 0x70;
+const END_OF_DATA: u8 = 0x66;
+
+// writes APU register logs as a VGM 1.61 file for the Game Boy DMG chip
+pub struct VgmRecorder {
+    writer: BufWriter<File>,
+    // position of the last command, in VGM samples
+    sample: u64,
+    data_size: u32,
+    finished: bool,
+}
+
+impl VgmRecorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<VgmRecorder> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        // the sizes are patched in once the recording is finished
+        write_header(&mut writer, 0, 0)?;
+        Ok(VgmRecorder {
+            writer,
+            sample: 0,
+            data_size: 0,
+            finished: false,
+        })
+    }
+
+    // add logged writes and advance the clock to end_cycle, both in T-cycles since the log started
+    pub fn push(&mut self, writes: &[RegisterWrite], end_cycle: u64) -> io::Result<()> {
+        for write in writes {
+            self.wait_until(write.cycle)?;
+            let register = match write.address {
+                0xFF10..=0xFF3F => (write.address - 0xFF10) as u8,
+                _ => continue,
+            };
+            self.emit(&[WRITE_DMG, register, write.value])?;
+        }
+        self.wait_until(end_cycle)
+    }
+
+    // timestamps are rounded on the absolute position so the rounding never accumulates
+    fn wait_until(&mut self, cycle: u64) -> io::Result<()> {
+        let target = cycle * VGM_SAMPLE_RATE / CPU_CLOCK;
+        while self.sample < target {
+            let remaining = target - self.sample;
+            let samples = match remaining {
+                735 => {
+                    self.emit(&[WAIT_NTSC_FRAME])?;
+                    735
+                }
+                882 => {
+                    self.emit(&[WAIT_PAL_FRAME])?;
+                    882
+                }
+                1..=16 => {
+                    self.emit(&[WAIT_SHORT + remaining as u8 - 1])?;
+                    remaining
+                }
+                _ => {
+                    let samples = remaining.min(0xFFFF);
+                    self.emit(&[WAIT, samples as u8, (samples >> 8) as u8])?;
+                    samples
+                }
+            };
+            self.sample += samples;
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.data_size += bytes.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finalize()
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.emit(&[END_OF_DATA])?;
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.writer, self.data_size, self.sample as u32)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+impl Drop for VgmRecorder {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}
+
+fn write_header<W: Write>(writer: &mut W, data_size: u32, total_samples: u32) -> io::Result<()> {
+    let mut header = [0u8; HEADER_SIZE as usize];
+    let mut field = |offset: usize, value: u32| header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    // offsets in the header are relative to the field holding them
+    field(0x04, HEADER_SIZE + data_size - 0x04);
+    field(0x08, VERSION);
+    field(0x18, total_samples);
+    field(DATA_OFFSET_FIELD as usize, HEADER_SIZE - DATA_OFFSET_FIELD);
+    field(DMG_CLOCK_FIELD, CPU_CLOCK as u32);
+    header[0..4].copy_from_slice(b"Vgm ");
+    writer.write_all(&header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    // the first T-cycle at or after a VGM sample
+    fn cycle_of(sample: u64) -> u64 {
+        (sample * CPU_CLOCK).div_ceil(VGM_SAMPLE_RATE)
+    }
+
+    #[test]
+    fn the_header_points_at_the_data_and_names_the_dmg_clock() {
+        let mut header = Vec::new();
+        write_header(&mut header, 0x20, 44100).unwrap();
+        assert_eq!(header.len(), HEADER_SIZE as usize);
+        assert_eq!(&header[0..4], b"Vgm ");
+        assert_eq!(u32_at(&header, 0x04), 0x11C);
+        assert_eq!(u32_at(&header, 0x08), 0x161);
+        assert_eq!(u32_at(&header, 0x18), 44100);
+        assert_eq!(0x34 + u32_at(&header, 0x34), 0x100);
+        assert_eq!(u32_at(&header, 0x80), 4_194_304);
+    }
+
+    #[test]
+    fn writes_and_waits_are_encoded() {
+        let path = std::env::temp_dir().join(format!("vgm-test-{}.vgm", std::process::id()));
+        let mut recorder = VgmRecorder::create(&path).unwrap();
+        let writes = [
+            RegisterWrite { cycle: 0, address: 0xFF26, value: 0x80 },
+            RegisterWrite { cycle: cycle_of(735), address: 0xFF12, value: 0xF0 },
+            RegisterWrite { cycle: cycle_of(745), address: 0xFF30, value: 0x12 },
+            // not an APU register, dropped
+            RegisterWrite { cycle: cycle_of(745), address: 0xFF40, value: 0x91 },
+        ];
+        recorder.push(&writes, cycle_of(2745)).unwrap();
+        recorder.finish().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let expected = [
+            0xB3, 0x16, 0x80, // NR52
+            0x62, // one NTSC frame
+            0xB3, 0x02, 0xF0, // NR12
+            0x79, // 10 samples
+            0xB3, 0x20, 0x12, // wave RAM
+            0x61, 0xD0, 0x07, // 2000 samples
+            0x66,
+        ];
+        assert_eq!(&bytes[HEADER_SIZE as usize..], &expected);
+        assert_eq!(u32_at(&bytes, 0x04), bytes.len() as u32 - 4);
+        assert_eq!(u32_at(&bytes, 0x18), 2745);
+    }
+}
@@ -0,0 +1,133 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::resampler::Resampler;
+
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const HEADER_SIZE: u32 = 44;
+
+// writes the mixed APU output to a 16-bit stereo PCM WAV file
+pub struct WavRecorder {
+    writer: BufWriter<File>,
+    resampler: Resampler,
+    buffer: Vec<i16>,
+    data_size: u32,
+    finished: bool,
+}
+
+impl WavRecorder {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<WavRecorder> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        // the sizes are patched in once the recording is finished
+        write_header(&mut writer, sample_rate, 0)?;
+        Ok(WavRecorder {
+            writer,
+            resampler: Resampler::new(sample_rate),
+            buffer: Vec::new(),
+            data_size: 0,
+            finished: false,
+        })
+    }
+
+    // add samples at the APU's native rate
+    pub fn push(&mut self, samples: &[(f32, f32)]) -> io::Result<()> {
+        self.resampler.push(samples);
+        self.buffer.clear();
+        self.resampler.read_i16(&mut self.buffer);
+        for sample in self.buffer.iter() {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size += self.buffer.len() as u32 * 2;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finalize()
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let sample_rate = self.resampler.output_rate();
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.writer, sample_rate, self.data_size)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+impl Drop for WavRecorder {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}
+
+fn write_header<W: Write>(writer: &mut W, sample_rate: u32, data_size: u32) -> io::Result<()> {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    // format 1 is uncompressed PCM
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&CHANNELS.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apu::NATIVE_SAMPLE_RATE;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn the_header_describes_16_bit_stereo_pcm() {
+        let mut header = Vec::new();
+        write_header(&mut header, 44100, 400).unwrap();
+        assert_eq!(header.len(), HEADER_SIZE as usize);
+        assert_eq!(&header[0..4], b"RIFF");
+        assert_eq!(u32_at(&header, 4), 436);
+        assert_eq!(&header[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&header, 16), 16);
+        assert_eq!(u16_at(&header, 20), 1);
+        assert_eq!(u16_at(&header, 22), 2);
+        assert_eq!(u32_at(&header, 24), 44100);
+        assert_eq!(u32_at(&header, 28), 44100 * 4);
+        assert_eq!(u16_at(&header, 32), 4);
+        assert_eq!(u16_at(&header, 34), 16);
+        assert_eq!(&header[36..40], b"data");
+        assert_eq!(u32_at(&header, 40), 400);
+    }
+
+    #[test]
+    fn finishing_patches_in_the_sizes() {
+        let path = std::env::temp_dir().join(format!("wav-test-{}.wav", std::process::id()));
+        let mut recorder = WavRecorder::create(&path, 48000).unwrap();
+        recorder.push(&vec![(0.5, -0.5); NATIVE_SAMPLE_RATE as usize / 10]).unwrap();
+        recorder.finish().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let data_size = bytes.len() as u32 - HEADER_SIZE;
+        // 100 ms at 48 kHz, four bytes a frame
+        assert!((4796..=4800).contains(&(data_size / 4)), "{} bytes of samples", data_size);
+        assert_eq!(u32_at(&bytes, 4), bytes.len() as u32 - 8);
+        assert_eq!(u32_at(&bytes, 40), data_size);
+    }
+}